@@ -0,0 +1,51 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compares `get` in a loop with `get_batch` on a table that doesn't fit in
+//! cache. Each iteration looks up enough keys that their buckets don't stay
+//! cached between iterations.
+
+#![feature(test)]
+
+extern crate hashmap2;
+extern crate test;
+
+use hashmap2::HashMap;
+use test::{black_box, Bencher};
+
+const N: u64 = 1 << 22;
+const LOOKUPS: u64 = 1 << 16;
+
+fn setup() -> (HashMap<u64, u64>, Vec<u64>) {
+    let map = (0..N).map(|i| (i, i)).collect();
+    let keys = (0..LOOKUPS).map(|i| i.wrapping_mul(2654435761) % (2 * N)).collect();
+    (map, keys)
+}
+
+#[bench]
+fn get_loop(b: &mut Bencher) {
+    let (map, keys) = setup();
+    b.iter(|| {
+        for k in &keys {
+            black_box(map.get(k));
+        }
+    });
+}
+
+#[bench]
+fn get_batch(b: &mut Bencher) {
+    let (map, keys) = setup();
+    let refs: Vec<&u64> = keys.iter().collect();
+    let mut out = vec![None; refs.len()];
+    b.iter(|| {
+        map.get_batch(&refs, &mut out);
+        black_box(&out);
+    });
+}
@@ -0,0 +1,119 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compares the table layouts for small and large keys and values.
+//!
+//! Lookups run against a table of 2^18 entries, which doesn't fit in cache
+//! for any of the measured sizes, from keys spread over the whole table.
+
+#![feature(test)]
+
+extern crate hashmap2;
+extern crate test;
+
+use std::hash::Hash;
+
+use hashmap2::{HashMap, HybridLayout, InterleavedLayout, Layout, RandomState, SplitLayout};
+use test::{black_box, Bencher};
+
+const N: u64 = 1 << 18;
+const LOOKUPS: u64 = 1024;
+const INSERTS: u64 = 1 << 12;
+
+fn small_key(i: u64) -> u64 { i }
+fn large_key(i: u64) -> [u64; 4] { [i, !i, 0, 0] }
+
+fn build<K, V, L>(n: u64, key: fn(u64) -> K, value: &V) -> HashMap<K, V, RandomState, L>
+    where K: Hash + Eq, V: Clone, L: Layout
+{
+    let mut map = HashMap::with_layout(n as usize, RandomState::new());
+    for i in 0..n {
+        map.insert(key(i), value.clone());
+    }
+    map
+}
+
+/// Keys spread over the table, so that consecutive lookups don't share
+/// cache lines.
+fn scattered(start: u64) -> Vec<u64> {
+    (0..LOOKUPS).map(|i| start + i.wrapping_mul(2654435761) % N).collect()
+}
+
+fn lookup_hit<K, V, L>(b: &mut Bencher, key: fn(u64) -> K, value: V)
+    where K: Hash + Eq, V: Clone, L: Layout
+{
+    let map = build::<K, V, L>(N, key, &value);
+    let keys: Vec<K> = scattered(0).into_iter().map(key).collect();
+    b.iter(|| {
+        for k in &keys {
+            black_box(map.get(k));
+        }
+    });
+}
+
+fn lookup_miss<K, V, L>(b: &mut Bencher, key: fn(u64) -> K, value: V)
+    where K: Hash + Eq, V: Clone, L: Layout
+{
+    let map = build::<K, V, L>(N, key, &value);
+    let keys: Vec<K> = scattered(N).into_iter().map(key).collect();
+    b.iter(|| {
+        for k in &keys {
+            black_box(map.get(k));
+        }
+    });
+}
+
+fn insert<K, V, L>(b: &mut Bencher, key: fn(u64) -> K, value: V)
+    where K: Hash + Eq, V: Clone, L: Layout
+{
+    b.iter(|| black_box(build::<K, V, L>(INSERTS, key, &value)));
+}
+
+macro_rules! bench {
+    ($name:ident, $op:ident, $key:ident, $value:expr, $layout:ty) => {
+        #[bench]
+        fn $name(b: &mut Bencher) {
+            $op::<_, _, $layout>(b, $key, $value)
+        }
+    }
+}
+
+// 8-byte keys and values.
+bench!(split_small_hit, lookup_hit, small_key, 0u64, SplitLayout);
+bench!(interleaved_small_hit, lookup_hit, small_key, 0u64, InterleavedLayout);
+bench!(hybrid_small_hit, lookup_hit, small_key, 0u64, HybridLayout);
+bench!(split_small_miss, lookup_miss, small_key, 0u64, SplitLayout);
+bench!(interleaved_small_miss, lookup_miss, small_key, 0u64, InterleavedLayout);
+bench!(hybrid_small_miss, lookup_miss, small_key, 0u64, HybridLayout);
+bench!(split_small_insert, insert, small_key, 0u64, SplitLayout);
+bench!(interleaved_small_insert, insert, small_key, 0u64, InterleavedLayout);
+bench!(hybrid_small_insert, insert, small_key, 0u64, HybridLayout);
+
+// 8-byte keys and 64-byte values.
+bench!(split_large_value_hit, lookup_hit, small_key, [0u64; 8], SplitLayout);
+bench!(interleaved_large_value_hit, lookup_hit, small_key, [0u64; 8], InterleavedLayout);
+bench!(hybrid_large_value_hit, lookup_hit, small_key, [0u64; 8], HybridLayout);
+bench!(split_large_value_miss, lookup_miss, small_key, [0u64; 8], SplitLayout);
+bench!(interleaved_large_value_miss, lookup_miss, small_key, [0u64; 8], InterleavedLayout);
+bench!(hybrid_large_value_miss, lookup_miss, small_key, [0u64; 8], HybridLayout);
+bench!(split_large_value_insert, insert, small_key, [0u64; 8], SplitLayout);
+bench!(interleaved_large_value_insert, insert, small_key, [0u64; 8], InterleavedLayout);
+bench!(hybrid_large_value_insert, insert, small_key, [0u64; 8], HybridLayout);
+
+// 32-byte keys and 8-byte values.
+bench!(split_large_key_hit, lookup_hit, large_key, 0u64, SplitLayout);
+bench!(interleaved_large_key_hit, lookup_hit, large_key, 0u64, InterleavedLayout);
+bench!(hybrid_large_key_hit, lookup_hit, large_key, 0u64, HybridLayout);
+bench!(split_large_key_miss, lookup_miss, large_key, 0u64, SplitLayout);
+bench!(interleaved_large_key_miss, lookup_miss, large_key, 0u64, InterleavedLayout);
+bench!(hybrid_large_key_miss, lookup_miss, large_key, 0u64, HybridLayout);
+bench!(split_large_key_insert, insert, large_key, 0u64, SplitLayout);
+bench!(interleaved_large_key_insert, insert, large_key, 0u64, InterleavedLayout);
+bench!(hybrid_large_key_insert, insert, large_key, 0u64, HybridLayout);
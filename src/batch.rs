@@ -0,0 +1,219 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lookups of many keys at once, which overlap their cache misses.
+//!
+//! Each batch is hashed first, then the ideal bucket of every key is
+//! prefetched, and only then are the probes run. By the time a probe reads
+//! its bucket, the load has usually arrived, instead of every lookup
+//! waiting on its own miss in turn.
+
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::mem;
+use std::vec::Vec;
+use std::collections::hash_state::HashState;
+
+use table::{FullBucketImm, Layout, SafeHash};
+use {Entry, HashMap, search_entry_hashed, search_hashed};
+
+/// The number of keys prefetched ahead of their probes. Enough to cover a
+/// miss to memory, few enough that the first bucket is still in cache when
+/// its probe runs.
+const BATCH: usize = 16;
+
+impl<K, V, S, L: Layout> HashMap<K, V, S, L>
+    where K: Eq + Hash, S: HashState
+{
+    /// Looks up every key in `keys`, writing the value of `keys[i]` to
+    /// `out[i]`. Gives the same results as calling `get` for each key, but
+    /// overlaps the lookups' cache misses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `keys` and `out` have different lengths.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let mut map = HashMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(3, "c");
+    ///
+    /// let mut out = [None; 3];
+    /// map.get_batch(&[&1, &2, &3], &mut out);
+    /// assert_eq!(out, [Some(&"a"), None, Some(&"c")]);
+    /// ```
+    pub fn get_batch<'a, Q: ?Sized>(&'a self, keys: &[&Q], out: &mut [Option<&'a V>])
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        assert_eq!(keys.len(), out.len());
+        self.search_batch(keys, |i, bucket| out[i] = bucket.map(|b| b.into_refs().1));
+    }
+
+    /// Checks every key in `keys`, writing whether the map contains
+    /// `keys[i]` to `out[i]`. Gives the same results as calling
+    /// `contains_key` for each key, but overlaps the lookups' cache misses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `keys` and `out` have different lengths.
+    pub fn contains_batch<Q: ?Sized>(&self, keys: &[&Q], out: &mut [bool])
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        assert_eq!(keys.len(), out.len());
+        self.search_batch(keys, |i, bucket| out[i] = bucket.is_some());
+    }
+
+    /// Calls `f` with the entry of each key in turn. Gives the same results
+    /// as calling `entry` for each key, but prefetches the buckets of the
+    /// following keys first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let mut counts = HashMap::new();
+    /// counts.entry_batch("a b a c a".split(' '), |e| *e.or_insert(0) += 1);
+    ///
+    /// assert_eq!(counts["a"], 3);
+    /// assert_eq!(counts["b"], 1);
+    /// assert_eq!(counts["c"], 1);
+    /// ```
+    pub fn entry_batch<I, F>(&mut self, keys: I, mut f: F)
+        where I: IntoIterator<Item=K>, F: FnMut(Entry<K, V, L>)
+    {
+        self.debug_check_invariants();
+
+        let mut keys = keys.into_iter();
+        let mut batch: Vec<K> = Vec::with_capacity(BATCH);
+        let mut hashes: [SafeHash; BATCH] = unsafe { mem::uninitialized() };
+        loop {
+            batch.extend(keys.by_ref().take(BATCH));
+            if batch.is_empty() {
+                break;
+            }
+
+            // Each entry adds at most one element, so growing now keeps the
+            // prefetched buckets where they are. Reserving can also reseed
+            // the hash state, so hash afterwards.
+            self.reserve(batch.len());
+            for (hash, k) in hashes.iter_mut().zip(&batch) {
+                *hash = self.make_hash(k);
+            }
+            for hash in &hashes[..batch.len()] {
+                self.table.prefetch(*hash);
+            }
+            for (hash, k) in hashes.iter().zip(batch.drain(..)) {
+                f(search_entry_hashed(&mut self.table, *hash, k));
+            }
+        }
+
+        self.debug_check_invariants();
+    }
+
+    /// Searches for each key, a batch at a time, passing its index in
+    /// `keys` and its bucket, if found, to `found`.
+    fn search_batch<'a, Q: ?Sized, F>(&'a self, keys: &[&Q], mut found: F)
+        where K: Borrow<Q>, Q: Hash + Eq,
+              F: FnMut(usize, Option<FullBucketImm<'a, K, V, L>>)
+    {
+        // Only the first `batch.len()` hashes are written and read.
+        let mut hashes: [SafeHash; BATCH] = unsafe { mem::uninitialized() };
+        for (n, batch) in keys.chunks(BATCH).enumerate() {
+            for (hash, &q) in hashes.iter_mut().zip(batch) {
+                *hash = self.make_hash(q);
+            }
+            for hash in &hashes[..batch.len()] {
+                self.table.prefetch(*hash);
+            }
+            for (i, (&q, &hash)) in batch.iter().zip(&hashes).enumerate() {
+                let bucket = search_hashed(&self.table, hash, |k| q.eq(k.borrow()));
+                found(n * BATCH + i, bucket.into_option());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use HashMap;
+
+    #[test]
+    fn test_get_batch() {
+        let map: HashMap<u32, u32> = (0..1000).map(|i| (i * 2, i)).collect();
+        let keys: Vec<u32> = (0..100).map(|i| i * 7).collect();
+        let refs: Vec<&u32> = keys.iter().collect();
+
+        let mut values = vec![None; refs.len()];
+        map.get_batch(&refs, &mut values);
+        let mut present = vec![false; refs.len()];
+        map.contains_batch(&refs, &mut present);
+
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(values[i], map.get(k));
+            assert_eq!(present[i], map.contains_key(k));
+        }
+
+        let empty: HashMap<u32, u32> = HashMap::new();
+        empty.get_batch(&refs, &mut values);
+        assert!(values.iter().all(|v| v.is_none()));
+    }
+
+    #[test]
+    fn test_get_batch_borrowed() {
+        let mut map = HashMap::new();
+        map.insert("one".to_string(), 1);
+        map.insert("two".to_string(), 2);
+
+        let mut out = [None; 3];
+        map.get_batch(&["two", "three", "one"], &mut out);
+        assert_eq!(out, [Some(&2), None, Some(&1)]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_batch_length_mismatch() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        map.contains_batch(&[&1, &2], &mut [false]);
+    }
+
+    #[test]
+    fn test_entry_batch_counts() {
+        let mut counts = HashMap::new();
+        counts.entry_batch((0..1000).map(|i| i % 37), |e| *e.or_insert(0) += 1);
+
+        assert_eq!(counts.len(), 37);
+        for (&k, &n) in &counts {
+            assert_eq!(n, if k < 1000 % 37 { 28 } else { 27 });
+        }
+    }
+
+    #[test]
+    fn test_entry_batch_reseed() {
+        use DisplacementLimit;
+        use test_map::MultiplyState;
+
+        // The first seed puts every key in one bucket, so the map reseeds
+        // while reserving room for a batch.
+        let mut counts = HashMap::with_hash_state(MultiplyState(1 << 32));
+        counts.set_displacement_limit(Some(DisplacementLimit::reseed(4)));
+        for _ in 0..2 {
+            counts.entry_batch(0..40u64, |e| *e.or_insert(0) += 1);
+        }
+        assert_eq!(counts.hash_state().0, 0x9e3779b97f4a7c15);
+        assert_eq!(counts.check_invariants(), Ok(()));
+        assert_eq!(counts.len(), 40);
+        assert!(counts.values().all(|&n| n == 2));
+    }
+}
@@ -0,0 +1,453 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A bidirectional map.
+
+use std::borrow::Borrow;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::iter::{FromIterator, IntoIterator};
+use std::slice;
+use std::collections::hash_state::HashState;
+
+use hashed::HashedTable;
+use table::{make_hash, SafeHash};
+use RandomState;
+
+/// A bidirectional map, where both the left and the right values are
+/// unique.
+///
+/// Each pair is stored once, in a vector. Two hash indexes map the left and
+/// the right values to the position of their pair, so neither side is
+/// cloned.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::BiHashMap;
+/// use hashmap2::bimap::Overwritten;
+///
+/// let mut ids = BiHashMap::new();
+/// ids.insert("alice", 1);
+/// ids.insert("bob", 2);
+///
+/// assert_eq!(ids.get_by_left("alice"), Some(&1));
+/// assert_eq!(ids.get_by_right(&2), Some(&"bob"));
+///
+/// // Both sides of the new pair collide with existing pairs.
+/// assert_eq!(ids.insert("alice", 2), Overwritten::Both(("alice", 1), ("bob", 2)));
+/// assert_eq!(ids.len(), 1);
+/// ```
+pub struct BiHashMap<L, R, S = RandomState> {
+    hash_state: S,
+    pairs: Vec<Pair<L, R>>,
+
+    // Map the hash of each side to the position of its pair.
+    left_index: HashedTable<usize, ()>,
+    right_index: HashedTable<usize, ()>,
+}
+
+struct Pair<L, R> {
+    left: L,
+    right: R,
+    left_hash: SafeHash,
+    right_hash: SafeHash,
+}
+
+/// The pairs removed from a `BiHashMap` by an insertion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Overwritten<L, R> {
+    /// No pair was removed.
+    Neither,
+
+    /// The pair with the same left value was removed.
+    Left(L, R),
+
+    /// The pair with the same right value was removed.
+    Right(L, R),
+
+    /// The same pair was already present, and was replaced.
+    Pair(L, R),
+
+    /// The pair with the same left value and the pair with the same right
+    /// value were both removed, in that order.
+    Both((L, R), (L, R)),
+}
+
+impl<L, R> Overwritten<L, R> {
+    /// Returns true if any pair was removed.
+    pub fn did_overwrite(&self) -> bool {
+        match *self {
+            Overwritten::Neither => false,
+            _ => true,
+        }
+    }
+}
+
+impl<L, R> BiHashMap<L, R, RandomState>
+    where L: Hash + Eq, R: Hash + Eq
+{
+    /// Creates an empty `BiHashMap`.
+    #[inline]
+    pub fn new() -> BiHashMap<L, R, RandomState> {
+        Default::default()
+    }
+}
+
+impl<L, R, S> BiHashMap<L, R, S>
+    where L: Hash + Eq, R: Hash + Eq, S: HashState
+{
+    /// Creates an empty `BiHashMap` which will use the given hash state to
+    /// hash both sides.
+    pub fn with_hash_state(hash_state: S) -> BiHashMap<L, R, S> {
+        BiHashMap {
+            hash_state: hash_state,
+            pairs: Vec::new(),
+            left_index: HashedTable::new(),
+            right_index: HashedTable::new(),
+        }
+    }
+
+    /// Returns the number of pairs in the map.
+    pub fn len(&self) -> usize { self.pairs.len() }
+
+    /// Returns true if the map contains no pairs.
+    pub fn is_empty(&self) -> bool { self.pairs.is_empty() }
+
+    fn find_left<Q: ?Sized>(&self, l: &Q) -> Option<usize>
+        where L: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_left_hashed(make_hash(&self.hash_state, l), l)
+    }
+
+    fn find_right<Q: ?Sized>(&self, r: &Q) -> Option<usize>
+        where R: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_right_hashed(make_hash(&self.hash_state, r), r)
+    }
+
+    fn find_left_hashed<Q: ?Sized>(&self, hash: SafeHash, l: &Q) -> Option<usize>
+        where L: Borrow<Q>, Q: Eq
+    {
+        let pairs = &self.pairs;
+        self.left_index.find(hash, |&pos| l.eq(pairs[pos].left.borrow()))
+                       .map(|(&pos, _)| pos)
+    }
+
+    fn find_right_hashed<Q: ?Sized>(&self, hash: SafeHash, r: &Q) -> Option<usize>
+        where R: Borrow<Q>, Q: Eq
+    {
+        let pairs = &self.pairs;
+        self.right_index.find(hash, |&pos| r.eq(pairs[pos].right.borrow()))
+                        .map(|(&pos, _)| pos)
+    }
+
+    /// Returns the right value paired with a left value.
+    ///
+    /// The value may be any borrowed form of the left type, but
+    /// `Hash` and `Eq` on the borrowed form *must* match those for
+    /// the left type.
+    pub fn get_by_left<Q: ?Sized>(&self, l: &Q) -> Option<&R>
+        where L: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_left(l).map(|pos| &self.pairs[pos].right)
+    }
+
+    /// Returns the left value paired with a right value.
+    pub fn get_by_right<Q: ?Sized>(&self, r: &Q) -> Option<&L>
+        where R: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_right(r).map(|pos| &self.pairs[pos].left)
+    }
+
+    /// Returns true if the map contains a pair with the left value.
+    pub fn contains_left<Q: ?Sized>(&self, l: &Q) -> bool
+        where L: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_left(l).is_some()
+    }
+
+    /// Returns true if the map contains a pair with the right value.
+    pub fn contains_right<Q: ?Sized>(&self, r: &Q) -> bool
+        where R: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_right(r).is_some()
+    }
+
+    /// Removes the pair with the left value, and returns it.
+    pub fn remove_by_left<Q: ?Sized>(&mut self, l: &Q) -> Option<(L, R)>
+        where L: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_left(l).map(|pos| self.remove_at(pos))
+    }
+
+    /// Removes the pair with the right value, and returns it.
+    pub fn remove_by_right<Q: ?Sized>(&mut self, r: &Q) -> Option<(L, R)>
+        where R: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_right(r).map(|pos| self.remove_at(pos))
+    }
+
+    /// Inserts a pair, removing any pairs that share its left or its right
+    /// value. Reports the removed pairs.
+    pub fn insert(&mut self, l: L, r: R) -> Overwritten<L, R> {
+        let left_hash = make_hash(&self.hash_state, &l);
+        let right_hash = make_hash(&self.hash_state, &r);
+        let found = (self.find_left_hashed(left_hash, &l), self.find_right_hashed(right_hash, &r));
+        let overwritten = match found {
+            (None, None) => Overwritten::Neither,
+            (Some(a), None) => {
+                let (l, r) = self.remove_at(a);
+                Overwritten::Left(l, r)
+            }
+            (None, Some(b)) => {
+                let (l, r) = self.remove_at(b);
+                Overwritten::Right(l, r)
+            }
+            (Some(a), Some(b)) if a == b => {
+                let (l, r) = self.remove_at(a);
+                Overwritten::Pair(l, r)
+            }
+            (Some(a), Some(b)) => {
+                // Removing a pair moves the last one, so remove the later
+                // position first.
+                if a > b {
+                    let left = self.remove_at(a);
+                    let right = self.remove_at(b);
+                    Overwritten::Both(left, right)
+                } else {
+                    let right = self.remove_at(b);
+                    let left = self.remove_at(a);
+                    Overwritten::Both(left, right)
+                }
+            }
+        };
+        self.push(l, r, left_hash, right_hash);
+        overwritten
+    }
+
+    /// Inserts a pair, unless its left or its right value is already
+    /// present. In that case, gives the pair back.
+    pub fn insert_no_overwrite(&mut self, l: L, r: R) -> Result<(), (L, R)> {
+        let left_hash = make_hash(&self.hash_state, &l);
+        let right_hash = make_hash(&self.hash_state, &r);
+        if self.find_left_hashed(left_hash, &l).is_some() ||
+           self.find_right_hashed(right_hash, &r).is_some() {
+            return Err((l, r));
+        }
+        self.push(l, r, left_hash, right_hash);
+        Ok(())
+    }
+
+    /// Clears the map, removing all pairs.
+    pub fn clear(&mut self) {
+        self.pairs.clear();
+        self.left_index.clear();
+        self.right_index.clear();
+    }
+
+    /// An iterator visiting all pairs in arbitrary order.
+    pub fn iter(&self) -> Iter<L, R> {
+        Iter { inner: self.pairs.iter() }
+    }
+
+    /// Appends a pair whose values have the given hashes.
+    fn push(&mut self, l: L, r: R, left_hash: SafeHash, right_hash: SafeHash) {
+        let pos = self.pairs.len();
+        self.left_index.insert(left_hash, pos, ());
+        self.right_index.insert(right_hash, pos, ());
+        self.pairs.push(Pair {
+            left: l,
+            right: r,
+            left_hash: left_hash,
+            right_hash: right_hash,
+        });
+    }
+
+    /// Removes the pair at `pos`. The last pair takes its place.
+    fn remove_at(&mut self, pos: usize) -> (L, R) {
+        {
+            let pair = &self.pairs[pos];
+            self.left_index.remove(pair.left_hash, |&p| p == pos);
+            self.right_index.remove(pair.right_hash, |&p| p == pos);
+        }
+
+        let last = self.pairs.len() - 1;
+        if pos != last {
+            let pair = &self.pairs[last];
+            if let Some((p, _)) = self.left_index.find_mut(pair.left_hash, |&p| p == last) {
+                *p = pos;
+            }
+            if let Some((p, _)) = self.right_index.find_mut(pair.right_hash, |&p| p == last) {
+                *p = pos;
+            }
+        }
+
+        let pair = self.pairs.swap_remove(pos);
+        (pair.left, pair.right)
+    }
+}
+
+impl<L, R, S> PartialEq for BiHashMap<L, R, S>
+    where L: Hash + Eq, R: Hash + Eq, S: HashState
+{
+    fn eq(&self, other: &BiHashMap<L, R, S>) -> bool {
+        self.len() == other.len() &&
+            self.iter().all(|(l, r)| other.get_by_left(l) == Some(r))
+    }
+}
+
+impl<L, R, S> Eq for BiHashMap<L, R, S>
+    where L: Hash + Eq, R: Hash + Eq, S: HashState
+{}
+
+impl<L, R, S> Debug for BiHashMap<L, R, S>
+    where L: Hash + Eq + Debug, R: Hash + Eq + Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<L, R, S> Default for BiHashMap<L, R, S>
+    where L: Hash + Eq, R: Hash + Eq, S: HashState + Default
+{
+    fn default() -> BiHashMap<L, R, S> {
+        BiHashMap::with_hash_state(Default::default())
+    }
+}
+
+impl<L, R, S> FromIterator<(L, R)> for BiHashMap<L, R, S>
+    where L: Hash + Eq, R: Hash + Eq, S: HashState + Default
+{
+    fn from_iter<T: IntoIterator<Item=(L, R)>>(iterable: T) -> BiHashMap<L, R, S> {
+        let mut map = BiHashMap::with_hash_state(Default::default());
+        map.extend(iterable);
+        map
+    }
+}
+
+impl<L, R, S> Extend<(L, R)> for BiHashMap<L, R, S>
+    where L: Hash + Eq, R: Hash + Eq, S: HashState
+{
+    /// Inserts each pair in turn, overwriting earlier pairs.
+    fn extend<T: IntoIterator<Item=(L, R)>>(&mut self, iter: T) {
+        for (l, r) in iter {
+            self.insert(l, r);
+        }
+    }
+}
+
+impl<'a, L, R, S> IntoIterator for &'a BiHashMap<L, R, S>
+    where L: Hash + Eq, R: Hash + Eq, S: HashState
+{
+    type Item = (&'a L, &'a R);
+    type IntoIter = Iter<'a, L, R>;
+
+    fn into_iter(self) -> Iter<'a, L, R> {
+        self.iter()
+    }
+}
+
+/// BiHashMap iterator.
+pub struct Iter<'a, L: 'a, R: 'a> {
+    inner: slice::Iter<'a, Pair<L, R>>,
+}
+
+// FIXME(#19839) Remove in favor of `#[derive(Clone)]`
+impl<'a, L, R> Clone for Iter<'a, L, R> {
+    fn clone(&self) -> Iter<'a, L, R> {
+        Iter {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<'a, L, R> Iterator for Iter<'a, L, R> {
+    type Item = (&'a L, &'a R);
+
+    #[inline] fn next(&mut self) -> Option<(&'a L, &'a R)> {
+        self.inner.next().map(|pair| (&pair.left, &pair.right))
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+impl<'a, L, R> ExactSizeIterator for Iter<'a, L, R> {
+    #[inline] fn len(&self) -> usize { self.inner.len() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BiHashMap;
+    use super::Overwritten::*;
+
+    #[test]
+    fn test_insert_overwritten() {
+        let mut map = BiHashMap::new();
+        assert_eq!(map.insert(1, 'a'), Neither);
+        assert_eq!(map.insert(2, 'b'), Neither);
+        assert_eq!(map.insert(3, 'c'), Neither);
+
+        assert_eq!(map.insert(1, 'x'), Left(1, 'a'));
+        assert_eq!(map.insert(9, 'b'), Right(2, 'b'));
+        assert_eq!(map.insert(3, 'c'), Pair(3, 'c'));
+        assert_eq!(map.insert(1, 'c'), Both((1, 'x'), (3, 'c')));
+        assert_eq!(map.insert(9, 'c'), Both((9, 'b'), (1, 'c')));
+
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get_by_left(&9), Some(&'c'));
+        assert_eq!(map.get_by_right(&'c'), Some(&9));
+        assert!(!map.contains_left(&1));
+        assert!(!map.contains_right(&'a'));
+    }
+
+    #[test]
+    fn test_insert_hashes_once() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use test_map::CountingState;
+
+        let hashes = Rc::new(Cell::new(0));
+        let mut map = BiHashMap::with_hash_state(CountingState(hashes.clone()));
+        map.insert(1, 'a');
+        map.insert(1, 'b');
+        assert_eq!(map.insert_no_overwrite(2, 'b'), Err((2, 'b')));
+        assert_eq!(hashes.get(), 6);
+    }
+
+    #[test]
+    fn test_insert_no_overwrite() {
+        let mut map = BiHashMap::new();
+        assert_eq!(map.insert_no_overwrite("a", 1), Ok(()));
+        assert_eq!(map.insert_no_overwrite("a", 2), Err(("a", 2)));
+        assert_eq!(map.insert_no_overwrite("b", 1), Err(("b", 1)));
+        assert_eq!(map.insert_no_overwrite("b", 2), Ok(()));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut map: BiHashMap<i32, String> = (0..1000).map(|i| (i, i.to_string())).collect();
+        for i in 0..500 {
+            if i % 2 == 0 {
+                assert_eq!(map.remove_by_left(&i), Some((i, i.to_string())));
+            } else {
+                assert_eq!(map.remove_by_right(&i.to_string()[..]), Some((i, i.to_string())));
+            }
+        }
+        assert_eq!(map.len(), 500);
+        for i in 0..1000 {
+            let expected = if i < 500 { None } else { Some(i.to_string()) };
+            assert_eq!(map.get_by_left(&i), expected.as_ref());
+            assert_eq!(map.get_by_right(&i.to_string()[..]).cloned(),
+                       expected.map(|_| i));
+        }
+    }
+}
@@ -0,0 +1,700 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A hash map bounded by a weight budget, with pluggable eviction.
+
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::collections::hash_state::HashState;
+
+use table::{self, Bucket, SafeHash};
+use table::BucketState::{Empty, Full};
+use {HashMap, RandomState, insert_hashed, pop_internal, search_hashed};
+
+/// The rule a `BoundedHashMap` follows to pick the entry to evict.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts an arbitrary entry, picked from a random bucket.
+    Random,
+
+    /// Evicts the entry that was inserted first. Replacing the value of an
+    /// existing key doesn't move it to the back of the queue.
+    Fifo,
+
+    /// Evicts the entry with the fewest lookups, breaking ties by age.
+    /// Finding it takes a scan over the whole table.
+    Lfu,
+
+    /// Evicts the first entry that hasn't been looked up since the clock
+    /// hand last passed over it. The hand sweeps the buckets in order.
+    Clock,
+}
+
+/// Computes the weight an entry takes out of a `BoundedHashMap`'s budget.
+pub trait Weigher<K, V> {
+    /// Returns the weight of the entry.
+    fn weight(&self, key: &K, value: &V) -> usize;
+}
+
+/// A weigher that gives every entry a weight of one, bounding the map by its
+/// number of entries.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnitWeigher;
+
+impl<K, V> Weigher<K, V> for UnitWeigher {
+    fn weight(&self, _: &K, _: &V) -> usize { 1 }
+}
+
+impl<K, V, F> Weigher<K, V> for F where F: Fn(&K, &V) -> usize {
+    fn weight(&self, key: &K, value: &V) -> usize { self(key, value) }
+}
+
+/// Counters of lookups and evictions in a `BoundedHashMap`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of lookups that found their key.
+    pub hits: u64,
+
+    /// The number of lookups that didn't find their key.
+    pub misses: u64,
+
+    /// The number of entries evicted to stay within the budget.
+    pub evictions: u64,
+}
+
+/// A hash map whose entries must fit in a total weight budget. Inserting
+/// evicts entries, chosen by an `EvictionPolicy`, until the new entry fits.
+///
+/// The weight of each entry is computed by a `Weigher` when it's inserted.
+/// With the default `UnitWeigher`, the budget is simply the number of
+/// entries.
+///
+/// Only `get` and `get_mut` count as uses of an entry; `peek` doesn't.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::{BoundedHashMap, EvictionPolicy};
+///
+/// // Each entry weighs as much as the length of its value.
+/// let weigher = |_: &u32, v: &String| v.len();
+/// let mut map = BoundedHashMap::with_weigher(10, EvictionPolicy::Fifo, weigher);
+///
+/// map.insert(1, "abcd".to_string());
+/// map.insert(2, "efgh".to_string());
+/// assert_eq!(map.weight(), 8);
+///
+/// let mut evicted = vec![];
+/// map.insert_with_evicted(3, "ijkl".to_string(), |k, _| evicted.push(k));
+/// assert_eq!(evicted, [1]);
+/// assert_eq!(map.weight(), 8);
+/// assert_eq!(map.stats().evictions, 1);
+/// ```
+pub struct BoundedHashMap<K, V, W = UnitWeigher, S = RandomState> {
+    map: HashMap<K, Slot<V>, S>,
+    weigher: W,
+    policy: EvictionPolicy,
+
+    weight: usize,
+    max_weight: usize,
+
+    // Insertion order, for `Fifo`. Entries that were removed are skipped
+    // lazily.
+    queue: VecDeque<(SafeHash, u64)>,
+    next_seq: u64,
+
+    // The bucket index of the clock hand, for `Clock`.
+    hand: usize,
+
+    // Seeds the choice of a bucket, for `Random`.
+    rng_counter: u64,
+
+    stats: CacheStats,
+}
+
+/// A value with its bookkeeping.
+struct Slot<V> {
+    value: V,
+    weight: usize,
+
+    // The insertion order of the key.
+    seq: u64,
+
+    // The number of lookups, for `Lfu`.
+    hits: u64,
+
+    // Whether the entry was used since the clock hand passed, for `Clock`.
+    referenced: bool,
+}
+
+impl<K: Hash + Eq, V> BoundedHashMap<K, V, UnitWeigher, RandomState> {
+    /// Creates an empty map that holds up to `max_len` entries.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::{BoundedHashMap, EvictionPolicy};
+    /// let mut map: BoundedHashMap<&str, isize> = BoundedHashMap::new(100, EvictionPolicy::Clock);
+    /// ```
+    pub fn new(max_len: usize, policy: EvictionPolicy) -> BoundedHashMap<K, V, UnitWeigher, RandomState> {
+        BoundedHashMap::with_weigher(max_len, policy, UnitWeigher)
+    }
+}
+
+impl<K: Hash + Eq, V, W: Weigher<K, V>> BoundedHashMap<K, V, W, RandomState> {
+    /// Creates an empty map whose entries, as weighed by `weigher`, add up
+    /// to at most `max_weight`.
+    pub fn with_weigher(max_weight: usize, policy: EvictionPolicy, weigher: W)
+                        -> BoundedHashMap<K, V, W, RandomState> {
+        BoundedHashMap::with_weigher_and_hash_state(max_weight, policy, weigher, Default::default())
+    }
+}
+
+impl<K, V, W, S> BoundedHashMap<K, V, W, S>
+    where K: Eq + Hash, W: Weigher<K, V>, S: HashState
+{
+    /// Creates an empty map whose entries, as weighed by `weigher`, add up
+    /// to at most `max_weight`, using `hash_state` to hash the keys.
+    pub fn with_weigher_and_hash_state(max_weight: usize,
+                                       policy: EvictionPolicy,
+                                       weigher: W,
+                                       hash_state: S)
+                                       -> BoundedHashMap<K, V, W, S> {
+        BoundedHashMap {
+            map: HashMap::with_hash_state(hash_state),
+            weigher: weigher,
+            policy: policy,
+            weight: 0,
+            max_weight: max_weight,
+            queue: VecDeque::new(),
+            next_seq: 0,
+            hand: 0,
+            rng_counter: 0,
+            stats: Default::default(),
+        }
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize { self.map.len() }
+
+    /// Returns true if the map contains no entries.
+    pub fn is_empty(&self) -> bool { self.map.is_empty() }
+
+    /// Returns the total weight of the entries in the map.
+    pub fn weight(&self) -> usize { self.weight }
+
+    /// Returns the weight budget.
+    pub fn max_weight(&self) -> usize { self.max_weight }
+
+    /// Returns the eviction policy.
+    pub fn policy(&self) -> EvictionPolicy { self.policy }
+
+    /// Returns the hit, miss and eviction counters.
+    pub fn stats(&self) -> CacheStats { self.stats }
+
+    /// Resets the hit, miss and eviction counters to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats = Default::default();
+    }
+
+    /// Changes the weight budget, evicting entries until they fit in it.
+    /// Evicted entries are dropped.
+    pub fn set_max_weight(&mut self, max_weight: usize) {
+        self.max_weight = max_weight;
+        while self.weight > max_weight {
+            self.evict_one();
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key, and counts
+    /// a use of the entry.
+    ///
+    /// The key may be any borrowed form of the map's key type, but
+    /// `Hash` and `Eq` on the borrowed form *must* match those for
+    /// the key type.
+    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get_mut(k).map(|v| &*v)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key,
+    /// and counts a use of the entry.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        match self.map.get_mut(k) {
+            Some(slot) => {
+                self.stats.hits += 1;
+                slot.hits = slot.hits.saturating_add(1);
+                slot.referenced = true;
+                Some(&mut slot.value)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key, without
+    /// counting a use of the entry or updating the stats.
+    pub fn peek<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.get(k).map(|slot| &slot.value)
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.contains_key(k)
+    }
+
+    /// Inserts a key-value pair, evicting entries until it fits. Evicted
+    /// entries are dropped.
+    ///
+    /// If the map had a value for this key, it's replaced and returned. A
+    /// replaced entry keeps its place in the insertion order and its use
+    /// count.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.insert_with_evicted(k, v, |_, _| {})
+    }
+
+    /// Inserts a key-value pair, evicting entries until it fits, and passes
+    /// each evicted entry to `on_evict`.
+    ///
+    /// An entry that weighs more than the whole budget is evicted right
+    /// away, along with the old value of its key, which is still returned.
+    pub fn insert_with_evicted<F>(&mut self, k: K, v: V, mut on_evict: F) -> Option<V>
+        where F: FnMut(K, V)
+    {
+        let weight = self.weigher.weight(&k, &v);
+
+        // The map has no displacement limit, so it never reseeds and the
+        // hash stays valid through the removal, the evictions and the
+        // insertion.
+        let hash = table::make_hash(&self.map.hash_state, &k);
+        let old = search_hashed(&mut self.map.table, hash, |q| *q == k)
+            .into_option()
+            .map(|bucket| pop_internal(bucket).1);
+        if let Some(ref old) = old {
+            self.weight -= old.weight;
+        }
+
+        // The replaced key is out of the map while entries are evicted, so
+        // its place in the queue must be kept for it.
+        let queued = match old {
+            Some(ref old) if self.policy == EvictionPolicy::Fifo => Some((hash, old.seq)),
+            _ => None,
+        };
+
+        if weight > self.max_weight {
+            self.stats.evictions += 1;
+            on_evict(k, v);
+            return old.map(|slot| slot.value);
+        }
+
+        while self.weight + weight > self.max_weight {
+            let (k, v) = self.evict_one_keeping(queued).expect("an empty map has no weight");
+            on_evict(k, v);
+        }
+
+        let (seq, hits) = match old {
+            Some(ref old) => (old.seq, old.hits),
+            None => {
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                if self.policy == EvictionPolicy::Fifo {
+                    self.queue.push_back((hash, seq));
+                }
+                (seq, 0)
+            }
+        };
+        self.weight += weight;
+        self.map.reserve(1);
+        insert_hashed(&mut self.map.table, hash, k, Slot {
+            value: v,
+            weight: weight,
+            seq: seq,
+            hits: hits,
+            referenced: false,
+        });
+        self.map.debug_check_invariants();
+
+        old.map(|slot| slot.value)
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key
+    /// was previously in the map.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.remove(k).map(|slot| {
+            self.weight -= slot.weight;
+            slot.value
+        })
+    }
+
+    /// Evicts one entry chosen by the eviction policy, and returns it.
+    pub fn evict_one(&mut self) -> Option<(K, V)> {
+        self.evict_one_keeping(None)
+    }
+
+    /// Evicts one entry, keeping the queue entry `queued` of a key that's
+    /// out of the map for now.
+    fn evict_one_keeping(&mut self, queued: Option<(SafeHash, u64)>) -> Option<(K, V)> {
+        if self.map.is_empty() {
+            return None;
+        }
+
+        let (k, slot) = match self.policy {
+            EvictionPolicy::Random => self.evict_random(),
+            EvictionPolicy::Fifo => self.evict_fifo(queued),
+            EvictionPolicy::Lfu => self.evict_lfu(),
+            EvictionPolicy::Clock => self.evict_clock(),
+        };
+        self.map.debug_check_invariants();
+
+        self.weight -= slot.weight;
+        self.stats.evictions += 1;
+        Some((k, slot.value))
+    }
+
+    /// Clears the map, removing all entries. The stats are kept.
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.queue.clear();
+        self.weight = 0;
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order. Doesn't
+    /// count as a use of any entry.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { inner: self.map.table.iter() }
+    }
+
+    fn evict_at(&mut self, index: usize) -> (K, Slot<V>) {
+        pop_internal(Bucket::at_index(&mut self.map.table, index).peek().expect_full())
+    }
+
+    fn evict_random(&mut self) -> (K, Slot<V>) {
+        self.rng_counter = self.rng_counter.wrapping_add(1);
+        let random = table::make_hash(&self.map.hash_state, &self.rng_counter).inspect();
+
+        // Take the first full bucket at or after a random one.
+        let mut probe = Bucket::at_index(&mut self.map.table, random as usize);
+        loop {
+            probe = match probe.peek() {
+                Full(full) => return pop_internal(full),
+                Empty(empty) => empty.next(),
+            };
+        }
+    }
+
+    fn evict_fifo(&mut self, queued: Option<(SafeHash, u64)>) -> (K, Slot<V>) {
+        if self.queue.len() > 2 * self.map.len() + 32 {
+            self.rebuild_queue(queued);
+        }
+
+        let mut passed = None;
+        while let Some((hash, seq)) = self.queue.pop_front() {
+            if Some((hash, seq)) == queued {
+                passed = queued;
+                continue;
+            }
+            if let Some(index) = self.find_index(hash, seq) {
+                // The queue is in insertion order, so the kept entry goes
+                // back to its front.
+                if let Some(entry) = passed {
+                    self.queue.push_front(entry);
+                }
+                return self.evict_at(index);
+            }
+        }
+        unreachable!("every entry is queued")
+    }
+
+    fn evict_lfu(&mut self) -> (K, Slot<V>) {
+        let cap = self.map.table.capacity();
+        let mut best: Option<(u64, u64, usize)> = None;
+
+        let mut probe = Bucket::first(&self.map.table);
+        for _ in 0..cap {
+            if let Full(full) = probe.peek() {
+                let slot = full.read().1;
+                let candidate = (slot.hits, slot.seq, full.index());
+                if best.map_or(true, |b| candidate < b) {
+                    best = Some(candidate);
+                }
+            }
+            probe.next();
+        }
+
+        let (_, _, index) = best.expect("the map isn't empty");
+        self.evict_at(index)
+    }
+
+    fn evict_clock(&mut self) -> (K, Slot<V>) {
+        let mask = self.map.table.capacity() - 1;
+
+        // Terminates within two sweeps, since the first clears every
+        // reference bit.
+        loop {
+            let index = self.hand & mask;
+            let victim = match Bucket::at_index(&mut self.map.table, index).peek() {
+                Full(mut full) => {
+                    let slot = full.read_mut().1;
+                    if slot.referenced {
+                        slot.referenced = false;
+                        false
+                    } else {
+                        true
+                    }
+                }
+                Empty(_) => false,
+            };
+            if victim {
+                // Leave the hand in place. The entry shifted back into this
+                // bucket hasn't been looked at yet.
+                return self.evict_at(index);
+            }
+            self.hand = self.hand.wrapping_add(1);
+        }
+    }
+
+    /// Finds the bucket index of the entry with the given hash and insertion
+    /// order.
+    fn find_index(&self, hash: SafeHash, seq: u64) -> Option<usize> {
+        let cap = self.map.table.capacity();
+        if cap == 0 {
+            return None;
+        }
+
+        let mut probe = Bucket::new(&self.map.table, hash);
+        for displacement in 0..cap {
+            let full = match probe.peek() {
+                Empty(..) => return None,
+                Full(full) => full
+            };
+            if full.distance() < displacement {
+                return None;
+            }
+            if full.hash() == hash && full.read().1.seq == seq {
+                return Some(full.index());
+            }
+            probe.next();
+        }
+        None
+    }
+
+    /// Drops the queue entries of removed keys, except `queued`.
+    fn rebuild_queue(&mut self, queued: Option<(SafeHash, u64)>) {
+        let cap = self.map.table.capacity();
+        let mut live: Vec<_> = queued.into_iter().map(|(hash, seq)| (seq, hash)).collect();
+
+        let mut probe = Bucket::first(&self.map.table);
+        for _ in 0..cap {
+            if let Full(full) = probe.peek() {
+                live.push((full.read().1.seq, full.hash()));
+            }
+            probe.next();
+        }
+        live.sort_by(|a, b| a.0.cmp(&b.0));
+
+        self.queue = live.into_iter().map(|(seq, hash)| (hash, seq)).collect();
+    }
+}
+
+impl<K, V, W, S> Debug for BoundedHashMap<K, V, W, S>
+    where K: Eq + Hash + Debug, V: Debug, W: Weigher<K, V>, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K, V, W, S> IntoIterator for &'a BoundedHashMap<K, V, W, S>
+    where K: Eq + Hash, W: Weigher<K, V>, S: HashState
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// BoundedHashMap iterator.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: table::Iter<'a, K, Slot<V>>
+}
+
+// FIXME(#19839) Remove in favor of `#[derive(Clone)]`
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    #[inline] fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.inner.next().map(|(k, slot)| (k, &slot.value))
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
+    #[inline] fn len(&self) -> usize { self.inner.len() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BoundedHashMap, CacheStats};
+    use super::EvictionPolicy::*;
+
+    #[test]
+    fn test_fifo() {
+        let mut map = BoundedHashMap::new(3, Fifo);
+        for i in 0..3 {
+            map.insert(i, i);
+        }
+        // Neither lookups nor replacing a value change the order.
+        map.get(&0);
+        assert_eq!(map.insert(0, 10), Some(0));
+
+        let mut evicted = vec![];
+        map.insert_with_evicted(3, 3, |k, v| evicted.push((k, v)));
+        map.insert_with_evicted(4, 4, |k, v| evicted.push((k, v)));
+        assert_eq!(evicted, [(0, 10), (1, 1)]);
+
+        // Removed keys are skipped.
+        map.remove(&2);
+        map.insert(5, 5);
+        assert_eq!(map.evict_one(), Some((3, 3)));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_fifo_replace_while_evicting() {
+        let weigher = |_: &i32, v: &usize| *v;
+        let mut map = BoundedHashMap::with_weigher(3, Fifo, weigher);
+        for i in 0..3 {
+            map.insert(i, 1);
+        }
+
+        // Key 0 is first in the queue, and stays there while 1 and 2 are
+        // evicted to make room for its new value.
+        let mut evicted = vec![];
+        assert_eq!(map.insert_with_evicted(0, 3, |k, v| evicted.push((k, v))), Some(1));
+        assert_eq!(evicted, [(1, 1), (2, 1)]);
+
+        evicted.clear();
+        map.insert_with_evicted(5, 1, |k, v| evicted.push((k, v)));
+        assert_eq!(evicted, [(0, 3)]);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_fifo_rebuild_queue() {
+        let mut map = BoundedHashMap::new(10, Fifo);
+        for i in 0..1000 {
+            map.insert(i, i);
+            if i % 10 != 9 {
+                map.remove(&i);
+            }
+        }
+        assert_eq!(map.len(), 10);
+        assert_eq!(map.evict_one(), Some((909, 909)));
+    }
+
+    #[test]
+    fn test_lfu() {
+        let mut map = BoundedHashMap::new(3, Lfu);
+        for i in 0..3 {
+            map.insert(i, i);
+        }
+        map.get(&0);
+        map.get(&0);
+        map.get(&2);
+        assert_eq!(map.evict_one(), Some((1, 1)));
+        map.insert(3, 3);
+        // Ties go to the oldest entry.
+        map.get(&3);
+        assert_eq!(map.evict_one(), Some((2, 2)));
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut map = BoundedHashMap::new(100, Clock);
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        for i in 0..100 {
+            if i != 42 {
+                map.get(&i);
+            }
+        }
+        assert_eq!(map.evict_one(), Some((42, 42)));
+        assert_eq!(map.len(), 99);
+        for _ in 0..99 {
+            assert!(map.evict_one().is_some());
+        }
+        assert_eq!(map.evict_one(), None);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut map = BoundedHashMap::new(50, Random);
+        for i in 0..1000 {
+            map.insert(i, i);
+            assert!(map.len() <= 50);
+        }
+        assert_eq!(map.len(), 50);
+        assert!(map.contains_key(&999));
+        assert_eq!(map.stats().evictions, 950);
+    }
+
+    #[test]
+    fn test_weight_and_stats() {
+        let weigher = |_: &i32, v: &Vec<u8>| v.len();
+        let mut map = BoundedHashMap::with_weigher(10, Fifo, weigher);
+
+        map.insert(1, vec![0; 6]);
+        map.insert(2, vec![0; 3]);
+        assert_eq!(map.weight(), 9);
+
+        // Too heavy to fit, even alone.
+        let mut evicted = vec![];
+        assert_eq!(map.insert_with_evicted(2, vec![0; 11], |k, _| evicted.push(k)),
+                   Some(vec![0; 3]));
+        assert_eq!(evicted, [2]);
+        assert_eq!(map.weight(), 6);
+
+        map.insert(3, vec![0; 5]);
+        assert!(!map.contains_key(&1));
+        assert_eq!(map.weight(), 5);
+
+        assert!(map.get(&3).is_some());
+        assert!(map.get(&1).is_none());
+        assert_eq!(map.stats(), CacheStats { hits: 1, misses: 1, evictions: 2 });
+
+        map.set_max_weight(4);
+        assert!(map.is_empty());
+        assert_eq!(map.weight(), 0);
+    }
+}
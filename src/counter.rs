@@ -0,0 +1,409 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A multiset, counting how many times each key occurs.
+
+use std::borrow::Borrow;
+use std::cmp::{self, Ordering};
+use std::collections::BinaryHeap;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::iter::{FromIterator, IntoIterator};
+use std::ops::{Add, BitAnd, BitOr, Sub};
+use std::collections::hash_state::HashState;
+
+use {HashMap, Iter, RandomState, pop_internal, retain_in_place};
+
+/// A multiset, built on a `HashMap` from each key to its count.
+///
+/// Keys whose count drops to zero are removed, so every stored count is
+/// positive. Counts are updated through the entry API, hashing the key
+/// once.
+///
+/// The operators follow multiset semantics: `+` adds counts, `-` subtracts
+/// them (stopping at zero), `|` takes the maximum of each count and `&`
+/// takes the minimum.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::Counter;
+///
+/// let words = "the cat and the dog and the bird".split(' ');
+/// let counts: Counter<&str> = words.collect();
+///
+/// assert_eq!(counts.get("the"), 3);
+/// assert_eq!(counts.get("fish"), 0);
+/// assert_eq!(counts.total(), 8);
+/// assert_eq!(counts.most_common(2), [(&"the", 3), (&"and", 2)]);
+/// ```
+#[derive(Clone)]
+pub struct Counter<K, S = RandomState> {
+    map: HashMap<K, usize, S>,
+
+    // The sum of all counts.
+    total: usize,
+}
+
+impl<K: Hash + Eq> Counter<K, RandomState> {
+    /// Creates an empty `Counter`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::Counter;
+    /// let mut counter: Counter<&str> = Counter::new();
+    /// ```
+    #[inline]
+    pub fn new() -> Counter<K, RandomState> {
+        Default::default()
+    }
+}
+
+impl<K, S> Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Creates an empty `Counter` which will use the given hash state to
+    /// hash keys.
+    #[inline]
+    pub fn with_hash_state(hash_state: S) -> Counter<K, S> {
+        Counter {
+            map: HashMap::with_hash_state(hash_state),
+            total: 0,
+        }
+    }
+
+    /// Returns the number of distinct keys.
+    pub fn len(&self) -> usize { self.map.len() }
+
+    /// Returns true if no key has a positive count.
+    pub fn is_empty(&self) -> bool { self.map.is_empty() }
+
+    /// Returns the sum of all counts.
+    pub fn total(&self) -> usize { self.total }
+
+    /// Returns the count of a key, which is zero if it's absent.
+    ///
+    /// The key may be any borrowed form of the counter's key type, but
+    /// `Hash` and `Eq` on the borrowed form *must* match those for
+    /// the key type.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> usize
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.get(k).cloned().unwrap_or(0)
+    }
+
+    /// Counts one more occurrence of the key, and returns its new count.
+    pub fn add(&mut self, k: K) -> usize {
+        self.add_n(k, 1)
+    }
+
+    /// Counts `n` more occurrences of the key, and returns its new count.
+    pub fn add_n(&mut self, k: K, n: usize) -> usize {
+        if n == 0 {
+            return self.get(&k);
+        }
+        self.total += n;
+        let count = self.map.entry(k).or_insert(0);
+        *count += n;
+        *count
+    }
+
+    /// Removes one occurrence of the key, and returns its new count.
+    pub fn sub<Q: ?Sized>(&mut self, k: &Q) -> usize
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.sub_n(k, 1)
+    }
+
+    /// Removes up to `n` occurrences of the key, and returns its new count.
+    /// The key is removed once its count reaches zero.
+    ///
+    /// The key is only looked up, so the map never grows.
+    pub fn sub_n<Q: ?Sized>(&mut self, k: &Q, n: usize) -> usize
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        if self.map.is_empty() {
+            return 0;
+        }
+        let remaining = {
+            let mut bucket = match self.map.search_mut(k) {
+                Some(bucket) => bucket,
+                None => return 0,
+            };
+            let count = *bucket.read().1;
+            if count <= n {
+                self.total -= count;
+                pop_internal(bucket);
+                0
+            } else {
+                self.total -= n;
+                *bucket.read_mut().1 = count - n;
+                count - n
+            }
+        };
+        self.map.debug_check_invariants();
+        remaining
+    }
+
+    /// Removes a key, and returns its count.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> usize
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let count = self.map.remove(k).unwrap_or(0);
+        self.total -= count;
+        count
+    }
+
+    /// Returns the `n` keys with the highest counts, from the highest down.
+    /// The order of keys with equal counts is unspecified.
+    ///
+    /// This keeps only `n` candidates at a time, rather than sorting every
+    /// key.
+    pub fn most_common(&self, n: usize) -> Vec<(&K, usize)> {
+        if n == 0 {
+            return Vec::new();
+        }
+
+        // A min-heap of the best candidates seen so far.
+        let mut heap = BinaryHeap::with_capacity(n);
+        for (k, &count) in &self.map {
+            if heap.len() < n {
+                heap.push(Candidate(count, k));
+            } else if count > heap.peek().unwrap().0 {
+                heap.pop();
+                heap.push(Candidate(count, k));
+            }
+        }
+
+        // Ascending order of the reversed comparison is descending count.
+        heap.into_sorted_vec().into_iter().map(|Candidate(count, k)| (k, count)).collect()
+    }
+
+    /// Clears the counter, removing all keys.
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.total = 0;
+    }
+
+    /// An iterator visiting all keys and their counts in arbitrary order.
+    pub fn iter(&self) -> Iter<K, usize> {
+        self.map.iter()
+    }
+}
+
+/// A key and its count, ordered by decreasing count.
+struct Candidate<'a, K: 'a>(usize, &'a K);
+
+impl<'a, K> PartialEq for Candidate<'a, K> {
+    fn eq(&self, other: &Candidate<'a, K>) -> bool { self.0 == other.0 }
+}
+
+impl<'a, K> Eq for Candidate<'a, K> {}
+
+impl<'a, K> PartialOrd for Candidate<'a, K> {
+    fn partial_cmp(&self, other: &Candidate<'a, K>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, K> Ord for Candidate<'a, K> {
+    fn cmp(&self, other: &Candidate<'a, K>) -> Ordering {
+        other.0.cmp(&self.0)
+    }
+}
+
+impl<K, S> PartialEq for Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{
+    fn eq(&self, other: &Counter<K, S>) -> bool {
+        self.total == other.total && self.map == other.map
+    }
+}
+
+impl<K, S> Eq for Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{}
+
+impl<K, S> Debug for Counter<K, S>
+    where K: Eq + Hash + Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+impl<K, S> Default for Counter<K, S>
+    where K: Eq + Hash, S: HashState + Default
+{
+    fn default() -> Counter<K, S> {
+        Counter::with_hash_state(Default::default())
+    }
+}
+
+impl<K, S> FromIterator<K> for Counter<K, S>
+    where K: Eq + Hash, S: HashState + Default
+{
+    fn from_iter<T: IntoIterator<Item=K>>(iterable: T) -> Counter<K, S> {
+        let mut counter = Counter::with_hash_state(Default::default());
+        counter.extend(iterable);
+        counter
+    }
+}
+
+impl<K, S> Extend<K> for Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{
+    fn extend<T: IntoIterator<Item=K>>(&mut self, iter: T) {
+        for k in iter {
+            self.add(k);
+        }
+    }
+}
+
+impl<'a, K, S> IntoIterator for &'a Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Item = (&'a K, &'a usize);
+    type IntoIter = Iter<'a, K, usize>;
+
+    fn into_iter(self) -> Iter<'a, K, usize> {
+        self.iter()
+    }
+}
+
+impl<K, S> Add for Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Output = Counter<K, S>;
+
+    /// Adds the counts of both counters.
+    fn add(mut self, rhs: Counter<K, S>) -> Counter<K, S> {
+        for (k, n) in rhs.map {
+            self.add_n(k, n);
+        }
+        self
+    }
+}
+
+impl<K, S> Sub for Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Output = Counter<K, S>;
+
+    /// Subtracts the counts of `rhs`, removing keys whose count reaches zero.
+    fn sub(mut self, rhs: Counter<K, S>) -> Counter<K, S> {
+        for (k, n) in rhs.map {
+            self.sub_n(&k, n);
+        }
+        self
+    }
+}
+
+impl<K, S> BitOr for Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Output = Counter<K, S>;
+
+    /// Takes the maximum of the counts of each key.
+    fn bitor(mut self, rhs: Counter<K, S>) -> Counter<K, S> {
+        for (k, n) in rhs.map {
+            let count = self.map.entry(k).or_insert(0);
+            if n > *count {
+                self.total += n - *count;
+                *count = n;
+            }
+        }
+        self
+    }
+}
+
+impl<K, S> BitAnd for Counter<K, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Output = Counter<K, S>;
+
+    /// Takes the minimum of the counts of each key, keeping only the keys
+    /// present in both counters.
+    fn bitand(mut self, rhs: Counter<K, S>) -> Counter<K, S> {
+        let mut total = 0;
+        retain_in_place(&mut self.map.table, |k, count| {
+            *count = cmp::min(*count, rhs.get(k));
+            total += *count;
+            *count > 0
+        });
+        self.map.debug_check_invariants();
+        self.total = total;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Counter;
+
+    fn counter(s: &str) -> Counter<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn test_add_sub() {
+        let mut c = counter("abracadabra");
+        assert_eq!(c.get(&'a'), 5);
+        assert_eq!(c.total(), 11);
+        assert_eq!(c.len(), 5);
+
+        assert_eq!(c.add_n('z', 3), 3);
+        assert_eq!(c.sub(&'z'), 2);
+        assert_eq!(c.sub_n(&'z', 10), 0);
+        assert_eq!(c.sub(&'z'), 0);
+        assert_eq!(c.add('z'), 1);
+        assert_eq!(c.sub(&'z'), 0);
+        assert_eq!(c.add_n('y', 0), 0);
+        assert_eq!(c.len(), 5);
+        assert_eq!(c.total(), 11);
+
+        assert_eq!(c.remove(&'b'), 2);
+        assert_eq!(c.total(), 9);
+        c.clear();
+        assert!(c.is_empty());
+        assert_eq!(c.total(), 0);
+
+        // Decrementing an absent key doesn't allocate.
+        let mut empty: Counter<char> = Counter::new();
+        assert_eq!(empty.sub(&'a'), 0);
+        assert_eq!(empty.map.capacity(), 0);
+    }
+
+    #[test]
+    fn test_most_common() {
+        let c: Counter<usize> = (0..100).flat_map(|i| (0..i).map(move |_| i % 20)).collect();
+        let top = c.most_common(3);
+        assert_eq!(top, [(&19, 19 + 39 + 59 + 79 + 99),
+                         (&18, 18 + 38 + 58 + 78 + 98),
+                         (&17, 17 + 37 + 57 + 77 + 97)]);
+        assert_eq!(c.most_common(0), []);
+        assert_eq!(c.most_common(100).len(), 20);
+    }
+
+    #[test]
+    fn test_operators() {
+        let a = counter("aaabbc");
+        let b = counter("abbbd");
+
+        assert_eq!(a.clone() + b.clone(), counter("aaaabbbbbcd"));
+        assert_eq!(a.clone() - b.clone(), counter("aac"));
+        assert_eq!(a.clone() | b.clone(), counter("aaabbbcd"));
+        let both = a & b;
+        assert_eq!(both, counter("abb"));
+        assert_eq!(both.total(), 3);
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A Bloom filter over the hashes stored in a map.
+
+use std::cmp::max;
+use std::error::Error;
+use std::f64::consts::LN_2;
+use std::fmt;
+use std::hash::Hash;
+use std::collections::hash_state::HashState;
+
+use mix;
+use table::make_hash;
+
+/// The length of the serialized header: the seed, the number of probes and
+/// the number of bits.
+const HEADER_LEN: usize = 8 + 4 + 8;
+
+/// A Bloom filter over keys hashed with a map's hash state.
+///
+/// The filter works on the hashes a `HashMap` already stores, so building
+/// it from a map doesn't hash any key again. Each hash is mixed with the
+/// filter's seed, and probes use double hashing.
+///
+/// To query a filter, keys must be hashed with the same hash state as the
+/// map it was built from. The serialized filter holds its own seed, but not
+/// the map's hash state, so a receiver must rebuild that, e.g. with
+/// `RandomState::with_keys` and keys sent along with the filter. A filter
+/// from a map with random keys is only usable locally.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::{HashFilter, HashMap, RandomState};
+///
+/// let mut map = HashMap::with_hash_state(RandomState::new());
+/// for i in 0..1000 {
+///     map.insert(i, ());
+/// }
+/// let filter = map.to_bloom_filter(0.01);
+/// let bytes = filter.to_bytes();
+/// let (k0, k1) = map.hash_state().keys();
+///
+/// // On the receiving side:
+/// let received = HashFilter::from_bytes(&bytes).unwrap();
+/// let hash_state = RandomState::with_keys(k0, k1);
+/// assert!(received.contains(&hash_state, &10));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_probes: u32,
+    seed: u64,
+}
+
+/// An error from deserializing a `HashFilter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterError {
+    /// The input ends before the filter does.
+    Truncated,
+
+    /// The header describes an impossible filter.
+    InvalidHeader,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for FilterError {
+    fn description(&self) -> &str {
+        match *self {
+            FilterError::Truncated => "serialized filter is truncated",
+            FilterError::InvalidHeader => "serialized filter has an invalid header",
+        }
+    }
+}
+
+impl HashFilter {
+    /// Creates an empty filter sized for `expected_items` entries at the
+    /// given false positive rate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `false_positive_rate` isn't between 0 and 1, exclusive.
+    pub fn with_rate(expected_items: usize, false_positive_rate: f64, seed: u64) -> HashFilter {
+        assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0,
+                "false positive rate must be between 0 and 1");
+
+        let n = max(expected_items, 1) as f64;
+        let num_bits = (-n * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let num_bits = max(num_bits, 64);
+        let num_probes = (num_bits as f64 / n * LN_2).round() as u32;
+        HashFilter::with_size(num_bits, max(num_probes, 1), seed)
+    }
+
+    fn with_size(num_bits: u64, num_probes: u32, seed: u64) -> HashFilter {
+        HashFilter {
+            bits: vec![0; ((num_bits + 63) / 64) as usize],
+            num_bits: num_bits,
+            num_probes: num_probes,
+            seed: seed,
+        }
+    }
+
+    /// Returns the seed mixed into every hash.
+    pub fn seed(&self) -> u64 { self.seed }
+
+    /// Returns the number of bits in the filter.
+    pub fn num_bits(&self) -> u64 { self.num_bits }
+
+    /// Returns the number of bits probed per key.
+    pub fn num_probes(&self) -> u32 { self.num_probes }
+
+    /// Adds a key, hashed with `hash_state`.
+    pub fn insert<Q: ?Sized + Hash, S: HashState>(&mut self, hash_state: &S, q: &Q) {
+        self.insert_hash(make_hash(hash_state, q).inspect());
+    }
+
+    /// Returns true if the key, hashed with `hash_state`, may have been
+    /// added. Returns false if it certainly wasn't.
+    pub fn contains<Q: ?Sized + Hash, S: HashState>(&self, hash_state: &S, q: &Q) -> bool {
+        self.contains_hash(make_hash(hash_state, q).inspect())
+    }
+
+    /// Adds a hash, as stored in a map's table.
+    pub fn insert_hash(&mut self, hash: u64) {
+        let (a, b) = self.probe_start(hash);
+        for i in 0..self.num_probes as u64 {
+            let bit = a.wrapping_add(i.wrapping_mul(b)) % self.num_bits;
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns true if the hash may have been added.
+    pub fn contains_hash(&self, hash: u64) -> bool {
+        let (a, b) = self.probe_start(hash);
+        (0..self.num_probes as u64).all(|i| {
+            let bit = a.wrapping_add(i.wrapping_mul(b)) % self.num_bits;
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    /// Derives the two hashes for double hashing. The step is odd, so it
+    /// doesn't collapse onto a few bits.
+    fn probe_start(&self, hash: u64) -> (u64, u64) {
+        let a = mix(hash ^ self.seed);
+        let b = mix(a ^ 0x9e3779b97f4a7c15) | 1;
+        (a, b)
+    }
+
+    /// Serializes the filter, with its seed, in little-endian byte order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.bits.len() * 8);
+        push_le(&mut bytes, self.seed, 8);
+        push_le(&mut bytes, self.num_probes as u64, 4);
+        push_le(&mut bytes, self.num_bits, 8);
+        for &word in &self.bits {
+            push_le(&mut bytes, word, 8);
+        }
+        bytes
+    }
+
+    /// Deserializes a filter written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<HashFilter, FilterError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(FilterError::Truncated);
+        }
+        let seed = read_le(&bytes[0..8]);
+        let num_probes = read_le(&bytes[8..12]) as u32;
+        let num_bits = read_le(&bytes[12..20]);
+        if num_bits == 0 || num_probes == 0 {
+            return Err(FilterError::InvalidHeader);
+        }
+
+        let words = (num_bits / 64 + (num_bits % 64 != 0) as u64) as usize;
+        let body = &bytes[HEADER_LEN..];
+        if body.len() / 8 < words {
+            return Err(FilterError::Truncated);
+        }
+        if body.len() != words * 8 {
+            return Err(FilterError::InvalidHeader);
+        }
+
+        let mut filter = HashFilter::with_size(num_bits, num_probes, seed);
+        for (word, chunk) in filter.bits.iter_mut().zip(body.chunks(8)) {
+            *word = read_le(chunk);
+        }
+        Ok(filter)
+    }
+}
+
+fn push_le(bytes: &mut Vec<u8>, x: u64, len: usize) {
+    for i in 0..len {
+        bytes.push((x >> (8 * i)) as u8);
+    }
+}
+
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |x, &b| (x << 8) | b as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FilterError, HashFilter};
+    use {HashMap, RandomState};
+
+    #[test]
+    fn test_false_positive_rate() {
+        let map: HashMap<u32, ()> = (0..10000).map(|i| (i, ())).collect();
+        let filter = map.to_bloom_filter(0.01);
+        for i in 0..10000 {
+            assert!(filter.contains(map.hash_state(), &i));
+        }
+        let false_positives = (10000..110000).filter(|i| filter.contains(map.hash_state(), i)).count();
+        assert!(false_positives < 2000, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_seed_changes_bits() {
+        let map: HashMap<u32, ()> = (0..100).map(|i| (i, ())).collect();
+        let a = map.to_bloom_filter_with_seed(0.01, 1);
+        let b = map.to_bloom_filter_with_seed(0.01, 2);
+        assert!(a != b);
+        assert_eq!(a, map.to_bloom_filter_with_seed(0.01, 1));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let s = RandomState::new();
+        let mut filter = HashFilter::with_rate(100, 0.05, 42);
+        for i in 0..100 {
+            filter.insert(&s, &i);
+        }
+        let bytes = filter.to_bytes();
+        let decoded = HashFilter::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, filter);
+        assert_eq!(decoded.seed(), 42);
+        for i in 0..100 {
+            assert!(decoded.contains(&s, &i));
+        }
+
+        assert_eq!(HashFilter::from_bytes(&bytes[..10]), Err(FilterError::Truncated));
+        assert_eq!(HashFilter::from_bytes(&bytes[..bytes.len() - 1]), Err(FilterError::Truncated));
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(HashFilter::from_bytes(&longer), Err(FilterError::InvalidHeader));
+    }
+
+    #[test]
+    fn test_remote_query() {
+        let map: HashMap<u32, ()> = {
+            let mut map = HashMap::with_hash_state(RandomState::with_keys(7, 11));
+            map.extend((0..1000).map(|i| (i, ())));
+            map
+        };
+        let bytes = map.to_bloom_filter(0.01).to_bytes();
+
+        // A shard that only has the bytes and the keys.
+        let filter = HashFilter::from_bytes(&bytes).unwrap();
+        let hash_state = RandomState::with_keys(7, 11);
+        for i in 0..1000 {
+            assert!(filter.contains(&hash_state, &i));
+        }
+        let false_positives = (1000..11000).filter(|i| filter.contains(&hash_state, i)).count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+
+        // Other keys hash differently, and find next to nothing.
+        let other = RandomState::with_keys(7, 12);
+        assert!((0..1000).filter(|i| filter.contains(&other, i)).count() < 200);
+    }
+}
@@ -0,0 +1,333 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A hash map that lives in a buffer provided by the caller.
+
+use std::borrow::Borrow;
+#[cfg(feature = "std")]
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::mem::replace;
+use std::collections::hash_state::HashState;
+
+use table::{make_hash, RawTable};
+use SearchResult::{FoundExisting, TableRef};
+use {DefaultResizePolicy, Iter, IterMut, RandomState, insert_hashed, pop_internal,
+     search_hashed};
+
+/// A hash map with a fixed capacity, stored in a borrowed byte buffer. It
+/// never allocates.
+///
+/// The buffer holds the same arrays of hashes, keys and values as the
+/// table of a `HashMap`, and the map uses the same Robin Hood insertion and
+/// backward-shift deletion. The number of buckets is the largest power of
+/// two that fits in the buffer, and the map holds as many entries as a
+/// `HashMap` would before growing. Inserting past that fails with
+/// `CapacityFull`.
+///
+/// To keep the map on the stack, pass it a buffer on the stack. There is no
+/// variant that owns an array as its buffer: the table points into the
+/// buffer, so moving such a map would leave the table behind.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::FixedHashMap;
+///
+/// let mut buffer = [0; 1024];
+/// let mut map = FixedHashMap::new(&mut buffer);
+/// let capacity = map.capacity() as u32;
+///
+/// for i in 0..capacity {
+///     assert_eq!(map.insert(i, i), Ok(None));
+/// }
+/// assert!(map.insert(capacity, 0).is_err());
+/// assert_eq!(map.insert(0, 10), Ok(Some(0)));
+/// ```
+pub struct FixedHashMap<'buf, K, V, S = RandomState> {
+    hash_state: S,
+    table: RawTable<K, V>,
+    resize_policy: DefaultResizePolicy,
+    marker: PhantomData<&'buf mut [u8]>,
+}
+
+/// The error returned by `FixedHashMap::insert` when the map is full. It
+/// holds the key and value that weren't inserted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapacityFull<K, V>(pub K, pub V);
+
+impl<K, V> fmt::Display for CapacityFull<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("fixed hash map is full")
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Debug, V: Debug> Error for CapacityFull<K, V> {
+    fn description(&self) -> &str {
+        "fixed hash map is full"
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'buf, K: Hash + Eq, V> FixedHashMap<'buf, K, V, RandomState> {
+    /// Creates an empty `FixedHashMap` in the given buffer.
+    #[inline]
+    pub fn new(buffer: &'buf mut [u8]) -> FixedHashMap<'buf, K, V, RandomState> {
+        FixedHashMap::with_hash_state(buffer, RandomState::new())
+    }
+}
+
+impl<'buf, K, V, S> FixedHashMap<'buf, K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Creates an empty `FixedHashMap` in the given buffer, which will use
+    /// the given hash state to hash keys.
+    pub fn with_hash_state(buffer: &'buf mut [u8], hash_state: S)
+                           -> FixedHashMap<'buf, K, V, S> {
+        FixedHashMap {
+            hash_state: hash_state,
+            table: unsafe { RawTable::in_buffer(buffer) },
+            resize_policy: DefaultResizePolicy::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of bytes a buffer needs for the map to hold
+    /// `capacity` entries, if the buffer is aligned for `u64`, `K` and `V`.
+    pub fn buffer_size_for(capacity: usize) -> usize {
+        if capacity == 0 {
+            return 0;
+        }
+        let resize_policy = DefaultResizePolicy::new();
+        let mut raw_cap = 1usize;
+        while resize_policy.usable_capacity(raw_cap) < capacity {
+            raw_cap = raw_cap.checked_mul(2).expect("capacity overflow");
+        }
+        RawTable::<K, V>::size_of_allocation(raw_cap)
+    }
+
+    /// Returns the number of elements the map can hold.
+    pub fn capacity(&self) -> usize {
+        self.resize_policy.usable_capacity(self.table.capacity())
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize { self.table.size() }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        search_hashed(&self.table, hash, |key| k.eq(key.borrow()))
+            .into_option()
+            .map(|bucket| bucket.into_refs().1)
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get(k).is_some()
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        search_hashed(&mut self.table, hash, |key| k.eq(key.borrow()))
+            .into_option()
+            .map(|bucket| bucket.into_mut_refs().1)
+    }
+
+    /// Inserts a key-value pair into the map, and returns the old value if
+    /// the key was present. The key is not updated.
+    ///
+    /// Fails if the key is absent and the map is at capacity, handing back
+    /// the key and value.
+    pub fn insert(&mut self, k: K, v: V) -> Result<Option<V>, CapacityFull<K, V>> {
+        let hash = make_hash(&self.hash_state, &k);
+        let capacity = self.capacity();
+        match search_hashed(&mut self.table, hash, |key| *key == k) {
+            FoundExisting(mut bucket) => Ok(Some(replace(bucket.read_mut().1, v))),
+            TableRef(table) => {
+                if table.size() == capacity {
+                    return Err(CapacityFull(k, v));
+                }
+                insert_hashed(table, hash, k, v);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes a key from the map, returning the value at the key if the
+    /// key was previously in the map.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.take(k).map(|(_, v)| v)
+    }
+
+    /// Removes a key from the map, returning the stored key and value if
+    /// the key was previously in the map.
+    pub fn take<Q: ?Sized>(&mut self, k: &Q) -> Option<(K, V)>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        search_hashed(&mut self.table, hash, |key| k.eq(key.borrow()))
+            .into_option()
+            .map(pop_internal)
+    }
+
+    /// Clears the map, removing all key-value pairs.
+    pub fn clear(&mut self) {
+        self.table.drain();
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { inner: self.table.iter() }
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order, with
+    /// mutable references to the values.
+    pub fn iter_mut(&mut self) -> IterMut<K, V> {
+        IterMut { inner: self.table.iter_mut() }
+    }
+}
+
+impl<'buf, K, V, S> Debug for FixedHashMap<'buf, K, V, S>
+    where K: Eq + Hash + Debug, V: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, 'buf, K, V, S> IntoIterator for &'a FixedHashMap<'buf, K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::mem::align_of;
+    use std::rc::Rc;
+
+    use super::{CapacityFull, FixedHashMap};
+    use RandomState;
+
+    #[test]
+    fn test_fill_and_remove() {
+        let mut buffer = [0u8; 4096];
+        let mut map = FixedHashMap::new(&mut buffer);
+        let cap = map.capacity();
+        assert!(cap > 0);
+
+        for i in 0..cap {
+            assert_eq!(map.insert(i, i * 2), Ok(None));
+        }
+        assert_eq!(map.insert(cap, 0), Err(CapacityFull(cap, 0)));
+        assert_eq!(map.len(), cap);
+
+        for i in (0..cap).filter(|i| i % 3 == 0) {
+            assert_eq!(map.remove(&i), Some(i * 2));
+        }
+        for i in 0..cap {
+            assert_eq!(map.get(&i).cloned(), if i % 3 == 0 { None } else { Some(i * 2) });
+        }
+        assert_eq!(map.insert(cap, 0), Ok(None));
+
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_unaligned_buffer() {
+        // 768 bytes hold exactly 32 buckets of 8 + 8 + 8 bytes.
+        let mut buffer = [0u8; 800];
+        let align = align_of::<u64>();
+        let start = (align - buffer.as_ptr() as usize % align) % align;
+
+        {
+            let map: FixedHashMap<u64, u64, _> =
+                FixedHashMap::with_hash_state(&mut buffer[start..start + 768], RandomState::new());
+            assert_eq!(map.capacity(), 29);
+        }
+
+        // Starting a byte later, the table must skip to the next aligned
+        // address, and 32 buckets no longer fit.
+        let mut map = FixedHashMap::with_hash_state(&mut buffer[start + 1..start + 769],
+                                                    RandomState::new());
+        assert_eq!(map.capacity(), 14);
+        for i in 0..14u64 {
+            map.insert(i, i).unwrap();
+        }
+        assert_eq!(map.iter().count(), 14);
+
+        assert_eq!(FixedHashMap::<u64, u64, RandomState>::buffer_size_for(29), 768);
+        assert_eq!(FixedHashMap::<u64, u64, RandomState>::buffer_size_for(0), 0);
+    }
+
+    #[test]
+    fn test_drops() {
+        let drops = Rc::new(Cell::new(0));
+        struct Counted(Rc<Cell<usize>>);
+
+        impl Drop for Counted {
+            fn drop(&mut self) { self.0.set(self.0.get() + 1); }
+        }
+
+        let mut buffer = [0u8; 512];
+        {
+            let mut map = FixedHashMap::new(&mut buffer);
+            for i in 0..5 {
+                map.insert(i, Counted(drops.clone())).ok().unwrap();
+            }
+            map.remove(&0);
+            assert_eq!(drops.get(), 1);
+        }
+        assert_eq!(drops.get(), 5);
+    }
+
+    #[test]
+    fn test_panicking_drop() {
+        use std::thread;
+
+        struct Bomb;
+
+        impl Drop for Bomb {
+            fn drop(&mut self) { panic!("boom"); }
+        }
+
+        // Unwinding out of a value's destructor must not free the buffer,
+        // which the allocator never handed out.
+        let result = thread::spawn(|| {
+            let mut buffer = [0u8; 512];
+            let mut map = FixedHashMap::new(&mut buffer);
+            map.insert(1, Bomb).ok().unwrap();
+        }).join();
+        assert!(result.is_err());
+    }
+}
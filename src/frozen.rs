@@ -0,0 +1,450 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A read-only map indexed by a minimal perfect hash function.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::iter::IntoIterator;
+use std::ops::Index;
+use std::slice;
+use std::collections::hash_state::HashState;
+
+use table::make_hash;
+use {HashMap, RandomState, mix};
+
+/// The average number of keys per displacement bucket. Larger buckets save
+/// memory, but make building the map slower.
+const LAMBDA: usize = 5;
+
+/// Marks a slot that holds no entry yet, while building the map.
+const FREE: usize = !0;
+
+/// The number of displacements a bucket tries before the seed is given up.
+const MAX_ATTEMPTS: usize = 16384;
+
+/// A read-only map, built by `HashMap::freeze`.
+///
+/// The entries are stored densely, one per slot, and placed by a minimal
+/// perfect hash function built with the CHD algorithm ("Hash, displace, and
+/// compress"). Each key's hash picks a bucket, and each bucket has a pair
+/// of displacements that send its keys to distinct slots. A lookup hashes
+/// the key once, reads one displacement pair and compares one key.
+///
+/// Keys whose stored hashes are equal can't be told apart by any function
+/// of the hashes. All but one of them are kept in a short overflow list,
+/// sorted by hash, which a lookup only searches if the key isn't in its
+/// slot.
+///
+/// Besides the entries, the map stores two `u32`s for every five entries,
+/// on average. It has no interior mutability, so it is `Sync` whenever its
+/// keys, values and hash state are.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::HashMap;
+///
+/// let routes: HashMap<&str, u16> = vec![("/", 1), ("/about", 2), ("/blog", 3)]
+///     .into_iter().collect();
+/// let routes = routes.freeze();
+///
+/// assert_eq!(routes.get("/about"), Some(&2));
+/// assert_eq!(routes.get("/missing"), None);
+///
+/// let mut routes = routes.thaw();
+/// routes.insert("/contact", 4);
+/// ```
+#[derive(Clone)]
+pub struct FrozenHashMap<K, V, S = RandomState> {
+    hash_state: S,
+
+    // Mixed into the stored hashes, and changed until the displacement
+    // search succeeds.
+    seed: u64,
+
+    displacements: Vec<(u32, u32)>,
+    entries: Vec<(K, V)>,
+
+    // The entries whose hash equals that of an entry in `entries`, sorted
+    // by hash.
+    overflow: Vec<(u64, K, V)>,
+}
+
+/// The parts of a key's hash used by the perfect hash function.
+#[derive(Clone, Copy)]
+struct Parts {
+    bucket: u32,
+    f1: u32,
+    f2: u32,
+}
+
+impl Parts {
+    fn new(hash: u64, seed: u64) -> Parts {
+        let h1 = mix(hash ^ seed);
+        let h2 = mix(h1 ^ 0x9e3779b97f4a7c15);
+        Parts {
+            bucket: (h1 >> 32) as u32,
+            f1: h1 as u32,
+            f2: h2 as u32,
+        }
+    }
+
+    fn slot(&self, (d0, d1): (u32, u32), len: usize) -> usize {
+        let x = d1.wrapping_add(self.f1.wrapping_mul(d0)).wrapping_add(self.f2);
+        x as usize % len
+    }
+}
+
+impl<K, V, S> HashMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Converts the map into a read-only `FrozenHashMap`, whose lookups
+    /// take a single probe. The stored hashes are reused, so no key is
+    /// hashed again.
+    ///
+    /// Building the perfect hash function tries a bounded number of
+    /// displacements for each bucket of keys, and starts over with a new
+    /// seed if one of them can't be placed.
+    pub fn freeze(self) -> FrozenHashMap<K, V, S> {
+        let HashMap { hash_state, table, .. } = self;
+        let entries = table.into_iter().map(|(hash, k, v)| (hash.inspect(), k, v)).collect();
+        FrozenHashMap::build(hash_state, entries)
+    }
+}
+
+impl<K, V, S> FrozenHashMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Builds the map from entries with their stored hashes.
+    fn build(hash_state: S, mut all: Vec<(u64, K, V)>) -> FrozenHashMap<K, V, S> {
+        assert!(all.len() <= u32::max_value() as usize, "too many entries to freeze");
+
+        // Keep the first entry of each hash for the perfect hash function.
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut entries = Vec::with_capacity(all.len());
+        let mut overflow = Vec::new();
+        for entry in all {
+            if entries.last().map_or(false, |last: &(u64, K, V)| last.0 == entry.0) {
+                overflow.push(entry);
+            } else {
+                entries.push(entry);
+            }
+        }
+
+        // Each seed fails with a small probability, so this ends quickly.
+        let mut seed = 0;
+        let mut result = displace(&entries, seed);
+        while result.is_none() {
+            seed += 1;
+            result = displace(&entries, seed);
+        }
+        let (displacements, slots) = result.unwrap();
+
+        let mut entries: Vec<Option<(K, V)>> =
+            entries.into_iter().map(|(_, k, v)| Some((k, v))).collect();
+        FrozenHashMap {
+            hash_state: hash_state,
+            seed: seed,
+            displacements: displacements,
+            entries: slots.into_iter().map(|i| entries[i].take().unwrap()).collect(),
+            overflow: overflow,
+        }
+    }
+}
+
+/// Finds displacements that send every entry to its own slot. Returns them
+/// along with the entry placed in each slot, or `None` if some bucket
+/// can't be placed.
+fn displace<K, V>(entries: &[(u64, K, V)], seed: u64) -> Option<(Vec<(u32, u32)>, Vec<usize>)> {
+    let len = entries.len();
+    let num_buckets = (len + LAMBDA - 1) / LAMBDA;
+    let parts: Vec<Parts> = entries.iter().map(|&(hash, _, _)| Parts::new(hash, seed)).collect();
+
+    let mut buckets = vec![Vec::new(); num_buckets];
+    for (i, p) in parts.iter().enumerate() {
+        buckets[p.bucket as usize % num_buckets].push(i);
+    }
+
+    // Place the largest buckets first, while most slots are free. Each
+    // attempt picks `d1` to send the bucket's first key to a free slot, so
+    // only the other keys can collide, and a bucket gets `MAX_ATTEMPTS` of
+    // them before the seed is given up.
+    let mut order: Vec<usize> = (0..num_buckets).collect();
+    order.sort_by(|&a, &b| buckets[b].len().cmp(&buckets[a].len()));
+
+    let mut displacements = vec![(0, 0); num_buckets];
+    let mut slots = vec![FREE; len];
+
+    // The free slots, and the position of each one in that list.
+    let mut free: Vec<usize> = (0..len).collect();
+    let mut free_position: Vec<usize> = (0..len).collect();
+
+    // The attempt that last claimed each slot, to catch two keys of one
+    // bucket landing on the same slot.
+    let mut claimed = vec![0u64; len];
+    let mut attempt = 0u64;
+    let mut placed = Vec::with_capacity(LAMBDA);
+
+    'buckets: for &b in &order {
+        let first = match buckets[b].first() {
+            Some(&i) => parts[i],
+            None => break,
+        };
+        'attempts: for j in 0..MAX_ATTEMPTS {
+            attempt += 1;
+            let target = free[(first.bucket as usize).wrapping_add(j) % free.len()] as u32;
+            let d0 = j as u32;
+            let d1 = target.wrapping_sub(first.f1.wrapping_mul(d0)).wrapping_sub(first.f2);
+
+            placed.clear();
+            for &i in &buckets[b] {
+                let slot = parts[i].slot((d0, d1), len);
+                if slots[slot] != FREE || claimed[slot] == attempt {
+                    continue 'attempts;
+                }
+                claimed[slot] = attempt;
+                placed.push((slot, i));
+            }
+
+            displacements[b] = (d0, d1);
+            for &(slot, i) in &placed {
+                slots[slot] = i;
+                let position = free_position[slot];
+                free.swap_remove(position);
+                if position < free.len() {
+                    free_position[free[position]] = position;
+                }
+            }
+            continue 'buckets;
+        }
+        return None;
+    }
+
+    Some((displacements, slots))
+}
+
+impl<K, V, S> FrozenHashMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize { self.entries.len() + self.overflow.len() }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// Returns the key-value pair corresponding to the key.
+    pub fn get_key_value<Q: ?Sized>(&self, k: &Q) -> Option<(&K, &V)>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let hash = make_hash(&self.hash_state, k).inspect();
+        let parts = Parts::new(hash, self.seed);
+        let displacement = self.displacements[parts.bucket as usize % self.displacements.len()];
+        let (ref key, ref value) = self.entries[parts.slot(displacement, self.entries.len())];
+        if k.eq(key.borrow()) {
+            return Some((key, value));
+        }
+
+        // Never equal, so this finds the first entry with the hash, if any.
+        let first = self.overflow
+            .binary_search_by(|e| if e.0 < hash { Ordering::Less } else { Ordering::Greater })
+            .unwrap_err();
+        self.overflow[first..].iter()
+            .take_while(|e| e.0 == hash)
+            .find(|e| k.eq(e.1.borrow()))
+            .map(|&(_, ref key, ref value)| (key, value))
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get_key_value(k).map(|(_, v)| v)
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get_key_value(k).is_some()
+    }
+
+    /// An iterator visiting all key-value pairs in slot order, then the
+    /// overflowing ones.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { inner: self.entries.iter(), overflow: self.overflow.iter() }
+    }
+
+    /// Converts the map back into a `HashMap` with the same hash state.
+    /// This hashes every key again, since the frozen map doesn't keep the
+    /// hashes.
+    pub fn thaw(self) -> HashMap<K, V, S> {
+        let len = self.len();
+        let mut map = HashMap::with_capacity_and_hash_state(len, self.hash_state);
+        let overflow = self.overflow.into_iter().map(|(_, k, v)| (k, v));
+        for (k, v) in self.entries.into_iter().chain(overflow) {
+            let hash = map.make_hash(&k);
+            map.insert_hashed_nocheck(hash, k, v);
+        }
+        map.debug_check_invariants();
+        map
+    }
+}
+
+impl<K, V, S> Debug for FrozenHashMap<K, V, S>
+    where K: Eq + Hash + Debug, V: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K, Q: ?Sized, V, S> Index<&'a Q> for FrozenHashMap<K, V, S>
+    where K: Eq + Hash + Borrow<Q>, Q: Eq + Hash, S: HashState
+{
+    type Output = V;
+
+    #[inline]
+    fn index(&self, index: &Q) -> &V {
+        self.get(index).expect("no entry found for key")
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a FrozenHashMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// FrozenHashMap iterator.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: slice::Iter<'a, (K, V)>,
+    overflow: slice::Iter<'a, (u64, K, V)>,
+}
+
+// FIXME(#19839) Remove in favor of `#[derive(Clone)]`
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter { inner: self.inner.clone(), overflow: self.overflow.clone() }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    #[inline] fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        match self.inner.next() {
+            Some(&(ref k, ref v)) => Some((k, v)),
+            None => self.overflow.next().map(|&(_, ref k, ref v)| (k, v)),
+        }
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.len();
+        (len, Some(len))
+    }
+}
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
+    #[inline] fn len(&self) -> usize { self.inner.len() + self.overflow.len() }
+}
+
+#[cfg(test)]
+mod test {
+    use HashMap;
+
+    #[test]
+    fn test_freeze_thaw() {
+        let map: HashMap<u32, u32> = (0..10000).map(|i| (i, i * 3)).collect();
+        let frozen = map.freeze();
+        assert_eq!(frozen.len(), 10000);
+        for i in 0..10000 {
+            assert_eq!(frozen.get(&i), Some(&(i * 3)));
+        }
+        for i in 10000..20000 {
+            assert!(!frozen.contains_key(&i));
+        }
+        assert_eq!(frozen.iter().count(), 10000);
+
+        let map = frozen.thaw();
+        assert_eq!(map.len(), 10000);
+        assert_eq!(map[&1234], 1234 * 3);
+    }
+
+    #[test]
+    fn test_large_map() {
+        use super::FrozenHashMap;
+        use table::make_hash;
+        use RandomState;
+
+        // Build from hashed entries, to skip the invariant checks of
+        // inserting into a `HashMap`.
+        let state = RandomState::new();
+        let entries = (0..200000u32).map(|i| (make_hash(&state, &i).inspect(), i, i)).collect();
+        let frozen = FrozenHashMap::build(state, entries);
+        assert!(frozen.seed < 4);
+        for i in 0..200000 {
+            assert_eq!(frozen.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_small_maps() {
+        let empty: HashMap<String, ()> = HashMap::new();
+        let frozen = empty.freeze();
+        assert!(frozen.is_empty());
+        assert_eq!(frozen.get("a"), None);
+
+        for n in 1..20 {
+            let map: HashMap<String, usize> = (0..n).map(|i| (i.to_string(), i)).collect();
+            let frozen = map.freeze();
+            for i in 0..n {
+                assert_eq!(frozen[&*i.to_string()], i);
+            }
+            assert!(!frozen.contains_key("x"));
+        }
+    }
+
+    #[test]
+    fn test_equal_hashes() {
+        use test_map::IdentityState;
+
+        // Stored hashes have their top bit set, so these keys share one.
+        let top = 1 << 63;
+        let mut map = HashMap::with_hash_state(IdentityState);
+        for i in 0..100u64 {
+            map.insert(i, i);
+            if i % 10 == 0 {
+                map.insert(i | top, i + 1000);
+            }
+        }
+        let frozen = map.freeze();
+        assert_eq!(frozen.len(), 110);
+        assert_eq!(frozen.iter().len(), 110);
+        for i in 0..100u64 {
+            assert_eq!(frozen.get(&i), Some(&i));
+            let other = frozen.get(&(i | top)).cloned();
+            assert_eq!(other, if i % 10 == 0 { Some(i + 1000) } else { None });
+        }
+
+        let map = frozen.thaw();
+        assert_eq!(map.len(), 110);
+        assert_eq!(map[&(50 | top)], 1050);
+    }
+}
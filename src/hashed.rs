@@ -0,0 +1,181 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp::max;
+use std::mem::replace;
+
+use table::{self, Bucket, RawTable, SafeHash};
+use table::BucketState::{Empty, Full};
+use {DefaultResizePolicy, INITIAL_CAPACITY, insert_hashed, pop_internal, retain_in_place,
+     search_hashed};
+
+/// A Robin Hood table whose entries are located by their stored hash and a
+/// caller-supplied predicate, rather than by comparing keys.
+///
+/// This is the building block for structures that keep their entries
+/// elsewhere and only need a hash index over them, such as a `RawTable`
+/// of positions into a `Vec`. Since entries are never re-hashed, growing the
+/// table doesn't need access to the original keys.
+pub struct HashedTable<K, V> {
+    table: RawTable<K, V>,
+    resize_policy: DefaultResizePolicy,
+}
+
+impl<K, V> HashedTable<K, V> {
+    pub fn new() -> HashedTable<K, V> {
+        HashedTable {
+            table: RawTable::new(0),
+            resize_policy: DefaultResizePolicy::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.size()
+    }
+
+    pub fn iter(&self) -> table::Iter<K, V> {
+        self.table.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> table::IterMut<K, V> {
+        self.table.iter_mut()
+    }
+
+    pub fn into_iter(self) -> table::IntoIter<K, V> {
+        self.table.into_iter()
+    }
+
+    pub fn drain(&mut self) -> table::Drain<K, V> {
+        self.table.drain()
+    }
+
+    /// Finds the first entry stored with `hash` that satisfies `is_match`.
+    pub fn find<F>(&self, hash: SafeHash, is_match: F) -> Option<(&K, &V)>
+        where F: FnMut(&K) -> bool
+    {
+        search_hashed(&self.table, hash, is_match)
+            .into_option()
+            .map(|bucket| bucket.into_refs())
+    }
+
+    pub fn find_mut<F>(&mut self, hash: SafeHash, is_match: F) -> Option<(&mut K, &mut V)>
+        where F: FnMut(&K) -> bool
+    {
+        search_hashed(&mut self.table, hash, is_match)
+            .into_option()
+            .map(|bucket| bucket.into_mut_refs())
+    }
+
+    /// Calls `f` on every entry stored with `hash`, in probe order.
+    pub fn for_each_hashed<F>(&self, hash: SafeHash, mut f: F)
+        where F: FnMut(&K, &V)
+    {
+        let cap = self.table.capacity();
+        if cap == 0 {
+            return;
+        }
+
+        let mut probe = Bucket::new(&self.table, hash);
+        for displacement in 0..cap {
+            let full = match probe.peek() {
+                Empty(..) => return,
+                Full(full) => full
+            };
+            if full.distance() < displacement {
+                return;
+            }
+            if full.hash() == hash {
+                let (k, v) = full.read();
+                f(k, v);
+            }
+            probe.next();
+        }
+    }
+
+    /// Inserts an entry without checking for an existing one.
+    pub fn insert(&mut self, hash: SafeHash, k: K, v: V) -> &mut V {
+        self.reserve(1);
+        insert_hashed(&mut self.table, hash, k, v)
+    }
+
+    /// Removes the first entry stored with `hash` that satisfies `is_match`.
+    pub fn remove<F>(&mut self, hash: SafeHash, is_match: F) -> Option<(K, V)>
+        where F: FnMut(&K) -> bool
+    {
+        search_hashed(&mut self.table, hash, is_match)
+            .into_option()
+            .map(pop_internal)
+    }
+
+    /// Removes the entries for which `keep` returns false, and returns how
+    /// many were removed.
+    pub fn retain<F>(&mut self, keep: F) -> usize
+        where F: FnMut(&K, &mut V) -> bool
+    {
+        retain_in_place(&mut self.table, keep)
+    }
+
+    /// Returns true if inserting `additional` entries would grow the table.
+    pub fn would_grow(&self, additional: usize) -> bool {
+        let new_size = self.len().checked_add(additional).expect("capacity overflow");
+        self.table.capacity() < self.resize_policy.min_capacity(new_size)
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        if self.would_grow(additional) {
+            let new_size = self.len() + additional;
+            let min_cap = self.resize_policy.min_capacity(new_size);
+            assert!(new_size <= min_cap);
+            let new_capacity = max(min_cap.next_power_of_two(), INITIAL_CAPACITY);
+
+            let old_table = replace(&mut self.table, RawTable::new(new_capacity));
+            for (h, k, v) in old_table.into_iter() {
+                insert_hashed(&mut self.table, h, k, v);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.table.drain();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HashedTable;
+    use table::make_hash;
+    use RandomState;
+
+    #[test]
+    fn test_hashed_table() {
+        let s = RandomState::new();
+        let mut t = HashedTable::new();
+
+        // Two entries under the same hash, told apart by the predicate.
+        for i in 0..100 {
+            t.insert(make_hash(&s, &(i / 2)), i, ());
+        }
+        assert_eq!(t.len(), 100);
+
+        for i in 0..100 {
+            assert_eq!(t.find(make_hash(&s, &(i / 2)), |&k| k == i).map(|(&k, _)| k), Some(i));
+        }
+
+        let mut found = vec![];
+        t.for_each_hashed(make_hash(&s, &7), |&k, _| found.push(k));
+        found.sort();
+        assert_eq!(found, [14, 15]);
+
+        assert_eq!(t.remove(make_hash(&s, &7), |&k| k == 14), Some((14, ())));
+        assert_eq!(t.remove(make_hash(&s, &7), |&k| k == 14), None);
+        assert!(t.find(make_hash(&s, &7), |&k| k == 15).is_some());
+        assert_eq!(t.len(), 99);
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::boxed::Box;
+use std::hash::Hash;
+use std::mem::size_of;
+use std::string::String;
+use std::vec::Vec;
+use std::collections::hash_state::HashState;
+
+use HashMap;
+use table::Layout;
+
+/// Types that can report how much heap memory they own.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::{HashMap, HeapSize};
+///
+/// let mut map = HashMap::new();
+/// map.insert(1u32, String::with_capacity(100));
+///
+/// assert!(map.heap_size_of_children() >= map.allocation_size() + 100);
+/// ```
+pub trait HeapSize {
+    /// Returns the number of bytes of heap memory owned by this value, not
+    /// counting the memory the value itself takes up.
+    fn heap_size_of_children(&self) -> usize;
+}
+
+macro_rules! no_heap_size {
+    ($($t:ty),*) => {
+        $(
+            impl HeapSize for $t {
+                #[inline]
+                fn heap_size_of_children(&self) -> usize { 0 }
+            }
+        )*
+    }
+}
+
+no_heap_size!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize,
+              f32, f64, bool, char, ());
+
+// Borrowed data isn't owned.
+impl<'a, T: ?Sized> HeapSize for &'a T {
+    #[inline]
+    fn heap_size_of_children(&self) -> usize { 0 }
+}
+
+impl HeapSize for String {
+    fn heap_size_of_children(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.capacity() * size_of::<T>() +
+            self.iter().map(HeapSize::heap_size_of_children).fold(0, |a, b| a + b)
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size_of_children(&self) -> usize {
+        size_of::<T>() + (**self).heap_size_of_children()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size_of_children)
+    }
+}
+
+impl<A: HeapSize, B: HeapSize> HeapSize for (A, B) {
+    fn heap_size_of_children(&self) -> usize {
+        self.0.heap_size_of_children() + self.1.heap_size_of_children()
+    }
+}
+
+impl<K, V, S, L: Layout> HeapSize for HashMap<K, V, S, L>
+    where K: Eq + Hash + HeapSize, V: HeapSize, S: HashState
+{
+    fn heap_size_of_children(&self) -> usize {
+        self.iter().fold(self.allocation_size(), |size, (k, v)| {
+            size + k.heap_size_of_children() + v.heap_size_of_children()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HeapSize;
+    use HashMap;
+
+    #[test]
+    fn test_heap_size() {
+        assert_eq!(5u32.heap_size_of_children(), 0);
+        assert_eq!(String::with_capacity(10).heap_size_of_children(), 10);
+
+        let v = vec![String::with_capacity(3), String::with_capacity(4)];
+        assert_eq!(v.heap_size_of_children(),
+                   v.capacity() * ::std::mem::size_of::<String>() + 7);
+
+        let mut m = HashMap::new();
+        assert_eq!(m.heap_size_of_children(), 0);
+        m.insert(1u8, Some(Box::new(7u64)));
+        m.insert(2u8, None);
+        assert_eq!(m.heap_size_of_children(), m.allocation_size() + 8);
+    }
+
+    #[test]
+    fn test_heap_size_layouts() {
+        use {InterleavedLayout, RandomState};
+
+        let mut m: HashMap<u8, String, _, InterleavedLayout> =
+            HashMap::with_layout(0, RandomState::new());
+        assert_eq!(m.heap_size_of_children(), 0);
+        m.insert(1, String::with_capacity(5));
+        assert!(m.allocation_size() > 0);
+        assert_eq!(m.heap_size_of_children(), m.allocation_size() + 5);
+    }
+}
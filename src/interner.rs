@@ -0,0 +1,369 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A string interner.
+
+use std::borrow::Borrow;
+use std::cmp::max;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::u32;
+use std::collections::hash_state::HashState;
+
+use {HashMap, RandomState, insert_hashed, search_hashed};
+
+/// The size of a fresh arena chunk, unless a longer string needs more.
+const CHUNK_SIZE: usize = 4096;
+
+/// A compact handle to a string in an `Interner`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    /// Returns the symbol's number. Symbols are numbered from zero, in the
+    /// order their strings were interned.
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
+/// A string stored in an interner's arena.
+///
+/// The arena never moves or frees a string while the interner is alive, so
+/// this pointer stays valid.
+struct ArenaStr(*const str);
+
+impl ArenaStr {
+    fn as_str(&self) -> &str {
+        unsafe { &*self.0 }
+    }
+}
+
+impl Borrow<str> for ArenaStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq for ArenaStr {
+    fn eq(&self, other: &ArenaStr) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for ArenaStr {}
+
+impl Hash for ArenaStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+/// An interner, which stores one copy of each distinct string and maps it
+/// to a `Symbol`.
+///
+/// Like `entry2`, interning looks the string up by reference, hashing it
+/// once. It's only copied when it's seen for the first time. Strings are
+/// copied into a chunked arena, whose chunks are never reallocated, so
+/// the references returned by `resolve` stay valid as the interner grows.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::Interner;
+///
+/// let mut interner = Interner::new();
+/// let a = interner.intern("apple");
+/// let b = interner.intern("banana");
+/// assert_eq!(interner.intern("apple"), a);
+///
+/// assert_eq!(interner.resolve(b), Some("banana"));
+/// assert_eq!(interner.get("cherry"), None);
+/// assert_eq!(interner.len(), 2);
+/// ```
+pub struct Interner<S = RandomState> {
+    map: HashMap<ArenaStr, Symbol, S>,
+
+    // The strings, indexed by symbol.
+    strings: Vec<ArenaStr>,
+
+    // Chunks of string data. A chunk is never filled past its capacity.
+    chunks: Vec<String>,
+}
+
+// The raw pointers only point into `chunks`, which the interner owns.
+unsafe impl<S: Send> Send for Interner<S> {}
+unsafe impl<S: Sync> Sync for Interner<S> {}
+
+impl Interner<RandomState> {
+    /// Creates an empty interner.
+    pub fn new() -> Interner<RandomState> {
+        Interner::with_hash_state(Default::default())
+    }
+}
+
+impl<S: HashState> Interner<S> {
+    /// Creates an empty interner which will use the given hash state to
+    /// hash strings.
+    pub fn with_hash_state(hash_state: S) -> Interner<S> {
+        Interner {
+            map: HashMap::with_hash_state(hash_state),
+            strings: Vec::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Returns the number of distinct strings interned.
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Returns true if no string was interned.
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    /// Returns the symbol for `s`, interning it if it's new.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of symbols overflows `u32`.
+    pub fn intern(&mut self, s: &str) -> Symbol {
+        let hash = self.map.make_hash(s);
+        if let Some(bucket) = search_hashed(&self.map.table, hash, |k| k.as_str() == s)
+                                  .into_option() {
+            return *bucket.into_refs().1;
+        }
+
+        // Without a displacement limit, reserving never reseeds the hash
+        // state, so the hash is still good afterwards.
+        self.map.reserve(1);
+
+        assert!(self.strings.len() < u32::MAX as usize, "too many symbols");
+        let symbol = Symbol(self.strings.len() as u32);
+        let key = self.alloc(s);
+        self.strings.push(ArenaStr(key));
+
+        // The string is known to be missing, so don't compare it again.
+        insert_hashed(&mut self.map.table, hash, ArenaStr(key), symbol);
+        self.map.debug_check_invariants();
+        symbol
+    }
+
+    /// Returns the symbol for `s`, if it was interned.
+    pub fn get(&self, s: &str) -> Option<Symbol> {
+        self.map.get(s).cloned()
+    }
+
+    /// Returns the string for a symbol of this interner.
+    pub fn resolve(&self, symbol: Symbol) -> Option<&str> {
+        self.strings.get(symbol.0 as usize).map(|s| s.as_str())
+    }
+
+    /// An iterator visiting all symbols and their strings, in the order they
+    /// were interned.
+    pub fn iter(&self) -> Iter {
+        Iter {
+            inner: self.strings.iter(),
+            next: 0,
+        }
+    }
+
+    /// Copies `s` into the arena.
+    fn alloc(&mut self, s: &str) -> *const str {
+        let fits = self.chunks.last().map_or(false, |chunk| {
+            chunk.capacity() - chunk.len() >= s.len()
+        });
+        if !fits {
+            self.chunks.push(String::with_capacity(max(CHUNK_SIZE, s.len())));
+        }
+
+        let chunk = self.chunks.last_mut().unwrap();
+        let start = chunk.len();
+        chunk.push_str(s);
+        &chunk[start..] as *const str
+    }
+}
+
+impl Default for Interner<RandomState> {
+    fn default() -> Interner<RandomState> {
+        Interner::new()
+    }
+}
+
+impl<S: HashState> Debug for Interner<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, S: HashState> IntoIterator for &'a Interner<S> {
+    type Item = (Symbol, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Interner iterator.
+pub struct Iter<'a> {
+    inner: ::std::slice::Iter<'a, ArenaStr>,
+    next: u32,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Symbol, &'a str);
+
+    fn next(&mut self) -> Option<(Symbol, &'a str)> {
+        self.inner.next().map(|s| {
+            let symbol = Symbol(self.next);
+            self.next += 1;
+            (symbol, s.as_str())
+        })
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+impl<'a> ExactSizeIterator for Iter<'a> {
+    #[inline] fn len(&self) -> usize { self.inner.len() }
+}
+
+/// A thread-safe interner, which can be shared by reference.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use hashmap2::interner::SyncInterner;
+///
+/// let interner = Arc::new(SyncInterner::new());
+/// let handles: Vec<_> = (0..4).map(|_| {
+///     let interner = interner.clone();
+///     thread::spawn(move || interner.intern("shared"))
+/// }).collect();
+///
+/// let symbols: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+/// assert!(symbols.iter().all(|&s| s == symbols[0]));
+/// assert_eq!(interner.resolve(symbols[0]), Some("shared"));
+/// ```
+pub struct SyncInterner<S = RandomState> {
+    inner: Mutex<Interner<S>>,
+}
+
+impl SyncInterner<RandomState> {
+    /// Creates an empty interner.
+    pub fn new() -> SyncInterner<RandomState> {
+        SyncInterner::with_hash_state(Default::default())
+    }
+}
+
+impl<S: HashState> SyncInterner<S> {
+    /// Creates an empty interner which will use the given hash state to
+    /// hash strings.
+    pub fn with_hash_state(hash_state: S) -> SyncInterner<S> {
+        SyncInterner { inner: Mutex::new(Interner::with_hash_state(hash_state)) }
+    }
+
+    /// Returns the number of distinct strings interned.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    /// Returns true if no string was interned.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the symbol for `s`, interning it if it's new.
+    pub fn intern(&self, s: &str) -> Symbol {
+        self.inner.lock().unwrap().intern(s)
+    }
+
+    /// Returns the symbol for `s`, if it was interned.
+    pub fn get(&self, s: &str) -> Option<Symbol> {
+        self.inner.lock().unwrap().get(s)
+    }
+
+    /// Returns the string for a symbol of this interner.
+    pub fn resolve(&self, symbol: Symbol) -> Option<&str> {
+        let interner = self.inner.lock().unwrap();
+        // Strings are never moved or freed while the interner is alive, so
+        // the reference may outlive the lock.
+        interner.resolve(symbol).map(|s| unsafe { &*(s as *const str) })
+    }
+
+    /// Consumes the `SyncInterner`, returning the underlying interner.
+    pub fn into_inner(self) -> Interner<S> {
+        self.inner.into_inner().unwrap()
+    }
+}
+
+impl Default for SyncInterner<RandomState> {
+    fn default() -> SyncInterner<RandomState> {
+        SyncInterner::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Interner, Symbol};
+
+    #[test]
+    fn test_intern() {
+        let mut interner = Interner::new();
+        let words: Vec<String> = (0..2000).map(|i| format!("word{}", i % 1000)).collect();
+        let symbols: Vec<Symbol> = words.iter().map(|w| interner.intern(w)).collect();
+
+        assert_eq!(interner.len(), 1000);
+        for (i, &symbol) in symbols.iter().enumerate() {
+            assert_eq!(symbol.as_u32() as usize, i % 1000);
+            assert_eq!(interner.resolve(symbol), Some(&words[i][..]));
+            assert_eq!(interner.get(&words[i]), Some(symbol));
+        }
+        assert_eq!(interner.resolve(Symbol(1000)), None);
+
+        let collected: Vec<_> = interner.iter().map(|(s, w)| (s.as_u32(), w.to_string())).collect();
+        assert_eq!(collected[999], (999, "word999".to_string()));
+    }
+
+    #[test]
+    fn test_references_survive_growth() {
+        let mut interner = Interner::new();
+        let first = interner.intern("first");
+        let ptr = interner.resolve(first).unwrap().as_ptr();
+
+        let long: String = ::std::iter::repeat('x').take(10000).collect();
+        interner.intern(&long);
+        for i in 0..5000 {
+            interner.intern(&i.to_string());
+        }
+        assert_eq!(interner.resolve(first).unwrap().as_ptr(), ptr);
+        assert_eq!(interner.get(&long[..]).map(|s| s.as_u32()), Some(1));
+        assert_eq!(interner.intern(""), interner.intern(""));
+    }
+
+    #[test]
+    fn test_intern_existing_at_load_limit() {
+        let mut interner = Interner::new();
+        interner.intern("0");
+        let mut i = 1;
+        while !interner.map.would_grow(1) {
+            interner.intern(&i.to_string());
+            i += 1;
+        }
+        let capacity = interner.map.capacity();
+        assert_eq!(interner.intern("0").as_u32(), 0);
+        assert_eq!(interner.map.capacity(), capacity);
+    }
+}
@@ -7,8 +7,15 @@
 // <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
+
+// Without the `std` feature only `HashMap` and its table are built, on top
+// of `core`, `alloc` and `collections`.
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(feature = "std"), feature(collections))]
+
 #![feature(
     alloc,
+    core_intrinsics,
     dropck_parametricity,
     filling_drop,
     hashmap_hasher,
@@ -20,17 +27,66 @@
 #![cfg_attr(test, feature(range_inclusive))]
 
 extern crate alloc;
+#[cfg(not(feature = "std"))]
+extern crate collections;
+#[cfg(feature = "std")]
 extern crate rand;
 
+mod batch;
+#[cfg(feature = "std")] pub mod bimap;
+#[cfg(feature = "std")] pub mod bounded;
+#[cfg(feature = "std")] pub mod counter;
+#[cfg(feature = "std")] pub mod filter;
+pub mod fixed;
+#[cfg(feature = "std")] pub mod frozen;
+#[cfg(feature = "std")] mod hashed;
+mod heap_size;
+#[cfg(feature = "std")] pub mod interner;
+#[cfg(feature = "std")] pub mod lru;
+#[cfg(feature = "std")] pub mod memo;
+#[cfg(feature = "std")] pub mod multi_index;
+#[cfg(feature = "std")] pub mod multimap;
+pub mod observed;
 mod recover;
+#[cfg(feature = "std")] pub mod small;
+#[cfg(feature = "std")] pub mod snapshot;
 mod table;
+#[cfg(feature = "std")] pub mod transaction;
+#[cfg(feature = "std")] pub mod ttl;
+#[cfg(feature = "std")] pub mod weak;
+
+/// The parts of `std` the crate uses, gathered from `core`, `alloc` and
+/// `collections` so that `std::` paths resolve the same way in a `no_std`
+/// build.
+#[cfg(not(feature = "std"))]
+mod std {
+    pub use core::{cmp, default, fmt, hash, intrinsics, iter, marker, mem, ops, option, ptr};
+    pub use alloc::boxed;
+    pub use collections::{borrow, string, vec};
+
+    pub mod collections {
+        pub mod hash_state {
+            use core::hash::Hasher;
+
+            /// A factory for hashers, mirroring the unstable trait of the
+            /// same name in `std`.
+            pub trait HashState {
+                /// Type of the hasher that will be created.
+                type Hasher: Hasher;
+
+                /// Creates a new hasher based on the given state.
+                fn hasher(&self) -> Self::Hasher;
+            }
+        }
+    }
+}
 
 use self::Entry::*;
 use self::SearchResult::*;
 use self::VacantEntryState::*;
 
-use std::borrow::{Borrow, Cow};
-use std::cmp::{max, Eq, PartialEq};
+use std::borrow::{Borrow, Cow, ToOwned};
+use std::cmp::{max, min, Eq, PartialEq};
 use std::default::Default;
 use std::fmt::{self, Debug};
 use std::hash::{Hash, SipHasher};
@@ -38,9 +94,31 @@ use std::iter::{self, Iterator, ExactSizeIterator, IntoIterator, FromIterator, E
 use std::mem::{self, replace};
 use std::ops::{Deref, FnMut, FnOnce, Index};
 use std::option::Option::{Some, None};
+use std::vec::Vec;
+#[cfg(feature = "std")]
 use rand::{Rng};
 use recover::Recover;
 
+#[cfg(feature = "std")] pub use bimap::BiHashMap;
+#[cfg(feature = "std")] pub use bounded::{BoundedHashMap, EvictionPolicy};
+#[cfg(feature = "std")] pub use counter::Counter;
+#[cfg(feature = "std")] pub use filter::{FilterError, HashFilter};
+pub use fixed::{CapacityFull, FixedHashMap};
+#[cfg(feature = "std")] pub use frozen::FrozenHashMap;
+pub use heap_size::HeapSize;
+#[cfg(not(feature = "std"))] pub use std::collections::hash_state::HashState;
+#[cfg(feature = "std")] pub use interner::{Interner, Symbol};
+#[cfg(feature = "std")] pub use lru::LruCache;
+#[cfg(feature = "std")] pub use memo::{Memo, SyncMemo};
+#[cfg(feature = "std")] pub use multi_index::MultiIndexMap;
+#[cfg(feature = "std")] pub use multimap::MultiMap;
+pub use observed::{ObservedHashMap, Observer};
+#[cfg(feature = "std")] pub use small::SmallHashMap;
+#[cfg(feature = "std")] pub use snapshot::{Snapshot, SnapshotHashMap};
+#[cfg(feature = "std")] pub use transaction::{Savepoint, Transaction};
+#[cfg(feature = "std")] pub use ttl::TtlHashMap;
+#[cfg(feature = "std")] pub use weak::{WeakKeyHashMap, WeakValueHashMap};
+
 use table::{
     Bucket,
     EmptyBucket,
@@ -50,10 +128,12 @@ use table::{
     RawTable,
     SafeHash
 };
+pub use table::{Allocation, HybridLayout, InterleavedLayout, Layout, SplitLayout};
 use table::BucketState::{
     Empty,
     Full,
 };
+#[cfg(feature = "std")]
 use std::collections::hash_state::HashState;
 
 const INITIAL_LOG2_CAP: usize = 5;
@@ -318,22 +398,35 @@ fn test_resize_policy() {
 ///     println!("{:?} has {} hp", viking, health);
 /// }
 /// ```
-#[derive(Clone)]
-pub struct HashMap<K, V, S = RandomState> {
+pub struct HashMap<K, V, S = RandomState, L: Layout = SplitLayout> {
     // All hashes are keyed on these values, to prevent hash collision attacks.
     hash_state: S,
 
-    table: RawTable<K, V>,
+    table: RawTable<K, V, L>,
 
     resize_policy: DefaultResizePolicy,
+
+    displacement_limit: Option<DisplacementLimit<S>>,
+}
+
+// Derived, this would require `L: Clone`.
+impl<K: Clone, V: Clone, S: Clone, L: Layout> Clone for HashMap<K, V, S, L> {
+    fn clone(&self) -> HashMap<K, V, S, L> {
+        HashMap {
+            hash_state: self.hash_state.clone(),
+            table: self.table.clone(),
+            resize_policy: self.resize_policy.clone(),
+            displacement_limit: self.displacement_limit,
+        }
+    }
 }
 
 /// Search for a pre-hashed key.
-fn search_hashed<K, V, M, F>(table: M,
+fn search_hashed<K, V, L: Layout, M, F>(table: M,
                              hash: SafeHash,
                              mut is_match: F)
                              -> SearchResult<K, V, M> where
-    M: Deref<Target=RawTable<K, V>>,
+    M: Deref<Target=RawTable<K, V, L>>,
     F: FnMut(&K) -> bool,
 {
     // This is the only function where capacity can be zero. To avoid
@@ -343,11 +436,13 @@ fn search_hashed<K, V, M, F>(table: M,
         return TableRef(table);
     }
 
-    let size = table.size();
+    // No entry is further than `max_displacement` from its ideal bucket,
+    // which bounds misses even where the early exit below doesn't kick in.
+    let probes = min(table.size(), table.max_displacement() + 1);
     let mut probe = Bucket::new(table, hash);
     let ib = probe.index();
 
-    while probe.index() != ib + size {
+    while probe.index() != ib + probes {
         let full = match probe.peek() {
             Empty(b) => return TableRef(b.into_table()), // hit an empty bucket
             Full(b) => b
@@ -373,7 +468,7 @@ fn search_hashed<K, V, M, F>(table: M,
     TableRef(probe.into_table())
 }
 
-fn pop_internal<K, V>(starting_bucket: FullBucketMut<K, V>) -> (K, V) {
+fn pop_internal<K, V, L: Layout>(starting_bucket: FullBucketMut<K, V, L>) -> (K, V) {
     let (empty, retkey, retval) = starting_bucket.take();
     let mut gap = match empty.gap_peek() {
         Some(b) => b,
@@ -391,17 +486,59 @@ fn pop_internal<K, V>(starting_bucket: FullBucketMut<K, V>) -> (K, V) {
     (retkey, retval)
 }
 
+/// Removes the entries for which `keep` returns false, in a single sweep
+/// over the buckets. Returns the number of removed entries.
+///
+/// The sweep starts right after an empty bucket. Backward-shift deletion
+/// moves entries back by one bucket, but never past an empty bucket, so
+/// every entry is visited exactly once.
+fn retain_in_place<K, V, L: Layout, F>(table: &mut RawTable<K, V, L>, mut keep: F) -> usize
+    where F: FnMut(&K, &mut V) -> bool
+{
+    let cap = table.capacity();
+    if table.size() == 0 {
+        return 0;
+    }
+
+    let start = (0..cap).find(|&i| {
+        match Bucket::at_index(&*table, i).peek() {
+            Empty(..) => true,
+            Full(..) => false,
+        }
+    }).expect("a table always has an empty bucket");
+
+    let mut removed = 0;
+    let mut index = start + 1;
+    while index <= start + cap {
+        let remove = match Bucket::at_index(&mut *table, index).peek() {
+            Full(mut full) => {
+                let (k, v) = full.read_mut();
+                !keep(k, v)
+            }
+            Empty(..) => false,
+        };
+        if remove {
+            pop_internal(Bucket::at_index(&mut *table, index).peek().expect_full());
+            removed += 1;
+            // The next entry may have shifted into this bucket.
+        } else {
+            index += 1;
+        }
+    }
+    removed
+}
+
 /// Perform robin hood bucket stealing at the given `bucket`. You must
 /// also pass the position of that bucket's initial bucket so we don't have
 /// to recalculate it.
 ///
 /// `hash`, `k`, and `v` are the elements to "robin hood" into the hashtable.
-fn robin_hood<'a, K: 'a, V: 'a>(mut bucket: FullBucketMut<'a, K, V>,
+fn robin_hood<'a, K: 'a, V: 'a, L: 'a + Layout>(mut bucket: FullBucketMut<'a, K, V, L>,
                         mut ib: usize,
                         mut hash: SafeHash,
                         mut k: K,
                         mut v: V)
-                        -> &'a mut V {
+                        -> (&'a mut K, &'a mut V) {
     let starting_index = bucket.index();
     let size = {
         let table = bucket.table(); // FIXME "lifetime too short".
@@ -427,8 +564,7 @@ fn robin_hood<'a, K: 'a, V: 'a>(mut bucket: FullBucketMut<'a, K, V>,
                     return Bucket::at_index(b.into_table(), starting_index)
                                .peek()
                                .expect_full()
-                               .into_mut_refs()
-                               .1;
+                               .into_mut_refs();
                 },
                 Full(bucket) => bucket
             };
@@ -449,6 +585,92 @@ fn robin_hood<'a, K: 'a, V: 'a>(mut bucket: FullBucketMut<'a, K, V>,
     }
 }
 
+/// Places an entry for `HashMap::rehash_in_place`, with Robin Hood hashing
+/// among the entries already placed. A bucket whose bit is set in
+/// `unsettled` holds an entry not placed yet, and counts as free: the entry
+/// takes it, and the entry found there is returned, to be placed next. An
+/// entry equal to one already placed is dropped.
+fn settle<K: Eq, V, L: Layout>(table: &mut RawTable<K, V, L>, unsettled: &mut [u64],
+                               mut hash: SafeHash, mut k: K, mut v: V)
+                               -> Option<(SafeHash, K, V)> {
+    let mask = table.capacity() - 1;
+    let mut probe = Bucket::new(table, hash);
+    let mut displacement = 0;
+
+    // Placed entries are unique, so only the first entry needs comparing.
+    let mut placed = false;
+    loop {
+        let mut bucket = match probe.peek() {
+            Empty(bucket) => {
+                bucket.put(hash, k, v);
+                return None;
+            }
+            Full(bucket) => bucket
+        };
+
+        let index = bucket.index() & mask;
+        if unsettled[index / 64] & (1 << (index % 64)) != 0 {
+            unsettled[index / 64] &= !(1 << (index % 64));
+            return Some(bucket.replace(hash, k, v));
+        }
+
+        if !placed && bucket.hash() == hash && *bucket.read().0 == k {
+            return None;
+        }
+
+        let distance = bucket.distance();
+        if distance < displacement {
+            // Robin hood! Steal the spot, and place the entry found there.
+            let (h, key, value) = bucket.replace(hash, k, v);
+            hash = h;
+            k = key;
+            v = value;
+            displacement = distance;
+            placed = true;
+        }
+
+        probe = bucket.next();
+        displacement += 1;
+    }
+}
+
+/// Inserts an entry with Robin Hood hashing, without comparing it to any
+/// other entry. There must be room for it.
+fn insert_hashed<K, V, L: Layout>(table: &mut RawTable<K, V, L>, hash: SafeHash, k: K, v: V) -> &mut V {
+    let size = table.size();
+    let mut probe = Bucket::new(table, hash);
+    let ib = probe.index();
+
+    loop {
+        let bucket = match probe.peek() {
+            Empty(bucket) => {
+                // Found a hole!
+                return bucket.put(hash, k, v).into_mut_refs().1;
+            }
+            Full(bucket) => bucket
+        };
+
+        let robin_ib = bucket.index() as isize - bucket.distance() as isize;
+
+        if (ib as isize) < robin_ib {
+            // Found a luckier bucket than me. Better steal his spot.
+            return robin_hood(bucket, robin_ib as usize, hash, k, v).1;
+        }
+
+        probe = bucket.next();
+        assert!(probe.index() != ib + size + 1);
+    }
+}
+
+/// The finalizer of SplitMix64, which spreads every input bit over the
+/// output.
+#[cfg(feature = "std")]
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
 /// A result that works like Option<FullBucket<..>> but preserves
 /// the reference that grants us access to the table in any case.
 enum SearchResult<K, V, M> {
@@ -468,7 +690,7 @@ impl<K, V, M> SearchResult<K, V, M> {
     }
 }
 
-impl<K, V, S> HashMap<K, V, S>
+impl<K, V, S, L: Layout> HashMap<K, V, S, L>
     where K: Eq + Hash, S: HashState
 {
     fn make_hash<X: ?Sized>(&self, x: &X) -> SafeHash where X: Hash {
@@ -478,7 +700,7 @@ impl<K, V, S> HashMap<K, V, S>
     /// Search for a key, yielding the index if it's found in the hashtable.
     /// If you already have the hash for the key lying around, use
     /// search_hashed.
-    fn search<'a, Q: ?Sized>(&'a self, q: &Q) -> Option<FullBucketImm<'a, K, V>>
+    fn search<'a, Q: ?Sized>(&'a self, q: &Q) -> Option<FullBucketImm<'a, K, V, L>>
         where K: Borrow<Q>, Q: Eq + Hash
     {
         let hash = self.make_hash(q);
@@ -486,7 +708,7 @@ impl<K, V, S> HashMap<K, V, S>
             .into_option()
     }
 
-    fn search_mut<'a, Q: ?Sized>(&'a mut self, q: &Q) -> Option<FullBucketMut<'a, K, V>>
+    fn search_mut<'a, Q: ?Sized>(&'a mut self, q: &Q) -> Option<FullBucketMut<'a, K, V, L>>
         where K: Borrow<Q>, Q: Eq + Hash
     {
         let hash = self.make_hash(q);
@@ -516,6 +738,7 @@ impl<K, V, S> HashMap<K, V, S>
     }
 }
 
+#[cfg(feature = "std")]
 impl<K: Hash + Eq, V> HashMap<K, V, RandomState> {
     /// Creates an empty HashMap.
     ///
@@ -565,11 +788,7 @@ impl<K, V, S> HashMap<K, V, S>
     /// ```
     #[inline]
     pub fn with_hash_state(hash_state: S) -> HashMap<K, V, S> {
-        HashMap {
-            hash_state:    hash_state,
-            resize_policy: DefaultResizePolicy::new(),
-            table:         RawTable::new(0),
-        }
+        HashMap::with_layout(0, hash_state)
     }
 
     /// Creates an empty HashMap with space for at least `capacity`
@@ -595,6 +814,40 @@ impl<K, V, S> HashMap<K, V, S>
     #[inline]
     pub fn with_capacity_and_hash_state(capacity: usize, hash_state: S)
                                         -> HashMap<K, V, S> {
+        HashMap::with_layout(capacity, hash_state)
+    }
+}
+
+impl<K, V, S, L: Layout> HashMap<K, V, S, L>
+    where K: Eq + Hash, S: HashState
+{
+    /// Creates an empty HashMap with space for at least `capacity`
+    /// elements, using `hasher` to hash the keys, and arranging its table
+    /// with the layout `L`.
+    ///
+    /// The other constructors use the default `SplitLayout`, which keeps
+    /// the hashes, keys and values in separate arrays.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::{HashMap, InterleavedLayout, RandomState};
+    ///
+    /// let mut map: HashMap<u64, u64, _, InterleavedLayout> =
+    ///     HashMap::with_layout(0, RandomState::new());
+    /// map.insert(1, 2);
+    /// assert_eq!(map[&1], 2);
+    /// ```
+    pub fn with_layout(capacity: usize, hash_state: S) -> HashMap<K, V, S, L> {
+        if capacity == 0 {
+            return HashMap {
+                hash_state:    hash_state,
+                resize_policy: DefaultResizePolicy::new(),
+                table:         RawTable::new(0),
+                displacement_limit: None,
+            };
+        }
+
         let resize_policy = DefaultResizePolicy::new();
         let min_cap = max(INITIAL_CAPACITY, resize_policy.min_capacity(capacity));
         let internal_cap = min_cap.checked_next_power_of_two().expect("capacity overflow");
@@ -603,9 +856,27 @@ impl<K, V, S> HashMap<K, V, S>
             hash_state:    hash_state,
             resize_policy: resize_policy,
             table:         RawTable::new(internal_cap),
+            displacement_limit: None,
         }
     }
 
+    /// Returns a reference to the map's hash state.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let mut map = HashMap::new();
+    /// map.insert("a", 1);
+    /// let filter = map.to_bloom_filter(0.01);
+    /// assert!(filter.contains(map.hash_state(), "a"));
+    /// ```
+    #[inline]
+    pub fn hash_state(&self) -> &S {
+        &self.hash_state
+    }
+
     /// Returns the number of elements the map can hold without reallocating.
     ///
     /// This number is a lower bound; the `HashMap<K, V>` might be able to hold
@@ -623,6 +894,89 @@ impl<K, V, S> HashMap<K, V, S>
         self.resize_policy.usable_capacity(self.table.capacity())
     }
 
+    /// Returns the number of bytes allocated for the map's table, including
+    /// padding. This doesn't count any heap memory owned by the keys and
+    /// values themselves; see `HeapSize` for that.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    /// let mut map: HashMap<u32, u32> = HashMap::new();
+    /// assert_eq!(map.allocation_size(), 0);
+    /// map.insert(1, 2);
+    /// assert!(map.allocation_size() > 0);
+    /// ```
+    pub fn allocation_size(&self) -> usize {
+        RawTable::<K, V, L>::size_of_allocation(self.table.capacity())
+    }
+
+    /// Estimates the number of bytes a map's table allocates to hold `n`
+    /// entries, after the resize policy and rounding of the capacity to a
+    /// power of two.
+    ///
+    /// This is exactly what `with_capacity(n)` allocates for nonzero `n`, and
+    /// what a map built by inserting `n` entries one by one ends up with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation size overflows `usize`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    /// let mut map: HashMap<u32, u32> = HashMap::with_capacity(1000);
+    /// assert_eq!(map.allocation_size(), HashMap::<u32, u32>::estimated_bytes_for(1000));
+    /// ```
+    pub fn estimated_bytes_for(n: usize) -> usize {
+        if n == 0 {
+            return 0;
+        }
+
+        let min_cap = max(INITIAL_CAPACITY, DefaultResizePolicy::new().min_capacity(n));
+        let internal_cap = min_cap.checked_next_power_of_two().expect("capacity overflow");
+        RawTable::<K, V, L>::size_of_allocation(internal_cap)
+    }
+
+    /// Builds a Bloom filter over the map's keys with the given false
+    /// positive rate, using a random seed.
+    ///
+    /// The filter is built from the hashes the table already stores, so no
+    /// key is hashed again. Probe it with keys hashed by this map's hash
+    /// state; see `HashFilter`. The seed only perturbs the filter's bits and
+    /// is serialized with it. What a receiver can't reproduce is the map's
+    /// hash state: a map with `RandomState::new()` gives a filter that is
+    /// only usable locally, unless its `RandomState::keys` are sent along.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `false_positive_rate` isn't between 0 and 1, exclusive.
+    #[cfg(feature = "std")]
+    pub fn to_bloom_filter(&self, false_positive_rate: f64) -> HashFilter {
+        self.to_bloom_filter_with_seed(false_positive_rate, rand::thread_rng().gen())
+    }
+
+    /// Builds a Bloom filter over the map's keys, like `to_bloom_filter`,
+    /// with the given seed.
+    #[cfg(feature = "std")]
+    pub fn to_bloom_filter_with_seed(&self, false_positive_rate: f64, seed: u64) -> HashFilter {
+        let mut filter = HashFilter::with_rate(self.len(), false_positive_rate, seed);
+        let cap = self.table.capacity();
+        if cap == 0 {
+            return filter;
+        }
+
+        let mut probe = Bucket::first(&self.table);
+        for _ in 0..cap {
+            if let Full(full) = probe.peek() {
+                filter.insert_hash(full.hash().inspect());
+            }
+            probe.next();
+        }
+        filter
+    }
+
     /// Reserves capacity for at least `additional` more elements to be inserted
     /// in the `HashMap`. The collection may reserve more space to avoid
     /// frequent reallocations.
@@ -650,6 +1004,47 @@ impl<K, V, S> HashMap<K, V, S>
             let new_capacity = max(min_cap.next_power_of_two(), INITIAL_CAPACITY);
             self.resize(new_capacity);
         }
+
+        self.enforce_displacement_limit();
+    }
+
+    /// Grows or reseeds the table if the last insertions displaced an
+    /// entry past the limit. Entries don't have access to the map, so this
+    /// runs before the next insertion rather than right after one.
+    fn enforce_displacement_limit(&mut self) {
+        let limit = match self.displacement_limit {
+            Some(limit) if self.table.max_displacement() > limit.limit => limit,
+            _ => return,
+        };
+
+        match limit.reseed {
+            Some(reseed) => {
+                reseed(&mut self.hash_state);
+                self.rehash_in_place();
+            }
+            None => {
+                let new_capacity = self.table.capacity() * 2;
+                self.resize(new_capacity);
+            }
+        }
+
+        // Keys whose hashes collide outright stay displaced however large
+        // the table is, so don't retry until the displacement doubles.
+        let displacement = self.table.max_displacement();
+        if displacement > limit.limit {
+            self.displacement_limit = Some(DisplacementLimit {
+                limit: displacement.saturating_mul(2),
+                reseed: limit.reseed,
+            });
+        }
+    }
+
+    /// Returns true if making room for `additional` more elements would
+    /// resize the table.
+    #[cfg(feature = "std")]
+    fn would_grow(&self, additional: usize) -> bool {
+        let new_size = self.len().checked_add(additional).expect("capacity overflow");
+        self.table.capacity() < self.resize_policy.min_capacity(new_size)
     }
 
     /// Resizes the internal vectors to a new capacity. It's your responsibility to:
@@ -731,6 +1126,7 @@ impl<K, V, S> HashMap<K, V, S>
         }
 
         assert_eq!(self.table.size(), old_size);
+        self.debug_check_invariants();
     }
 
     /// Shrinks the capacity of the map as much as possible. It will drop
@@ -767,6 +1163,7 @@ impl<K, V, S> HashMap<K, V, S>
 
             debug_assert_eq!(self.table.size(), old_size);
         }
+        self.debug_check_invariants();
     }
 
     /// Insert a pre-hashed key-value pair, without first checking
@@ -817,7 +1214,7 @@ impl<K, V, S> HashMap<K, V, S>
 
             if (ib as isize) < robin_ib {
                 // Found a luckier bucket than me. Better steal his spot.
-                return robin_hood(bucket, robin_ib as usize, hash, k, v);
+                return robin_hood(bucket, robin_ib as usize, hash, k, v).1;
             }
 
             probe = bucket.next();
@@ -842,7 +1239,7 @@ impl<K, V, S> HashMap<K, V, S>
     ///     println!("{}", key);
     /// }
     /// ```
-    pub fn keys<'a>(&'a self) -> Keys<'a, K, V> {
+    pub fn keys<'a>(&'a self) -> Keys<'a, K, V, L> {
         fn first<A, B>((a, _): (A, B)) -> A { a }
         let first: fn((&'a K,&'a V)) -> &'a K = first; // coerce to fn ptr
 
@@ -866,7 +1263,7 @@ impl<K, V, S> HashMap<K, V, S>
     ///     println!("{}", val);
     /// }
     /// ```
-    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
+    pub fn values<'a>(&'a self) -> Values<'a, K, V, L> {
         fn second<A, B>((_, b): (A, B)) -> B { b }
         let second: fn((&'a K,&'a V)) -> &'a V = second; // coerce to fn ptr
 
@@ -890,7 +1287,7 @@ impl<K, V, S> HashMap<K, V, S>
     ///     println!("key: {} val: {}", key, val);
     /// }
     /// ```
-    pub fn iter(&self) -> Iter<K, V> {
+    pub fn iter(&self) -> Iter<K, V, L> {
         Iter { inner: self.table.iter() }
     }
 
@@ -917,7 +1314,7 @@ impl<K, V, S> HashMap<K, V, S>
     ///     println!("key: {} val: {}", key, val);
     /// }
     /// ```
-    pub fn iter_mut(&mut self) -> IterMut<K, V> {
+    pub fn iter_mut(&mut self) -> IterMut<K, V, L> {
         IterMut { inner: self.table.iter_mut() }
     }
 
@@ -940,7 +1337,11 @@ impl<K, V, S> HashMap<K, V, S>
     /// assert_eq!(letters[&'u'], 1);
     /// assert_eq!(letters.get(&'y'), None);
     /// ```
-    pub fn entry(&mut self, key: K) -> Entry<K, V> {
+    pub fn entry(&mut self, key: K) -> Entry<K, V, L> {
+        // Entries can't check the map once they are done, so check for any
+        // changes made through the previous one.
+        self.debug_check_invariants();
+
         // Gotta resize now.
         self.reserve(1);
 
@@ -964,9 +1365,11 @@ impl<K, V, S> HashMap<K, V, S>
     /// assert_eq!(m["foo"], 0);
     /// assert_eq!(m["bar"], 1);
     /// ```
-    pub fn entry2<'a, Q: ?Sized>(&mut self, key: Cow<'a, Q>) -> Entry<K, V>
+    pub fn entry2<'a, Q: ?Sized>(&mut self, key: Cow<'a, Q>) -> Entry<K, V, L>
             where K: Clone + Borrow<Q>,
                   Q: 'a + ToOwned<Owned=K> + Hash + Eq {
+        self.debug_check_invariants();
+
         // Gotta resize now.
         self.reserve(1);
 
@@ -1029,7 +1432,7 @@ impl<K, V, S> HashMap<K, V, S>
     /// assert!(a.is_empty());
     /// ```
     #[inline]
-    pub fn drain(&mut self) -> Drain<K, V> {
+    pub fn drain(&mut self) -> Drain<K, V, L> {
         fn last_two<A, B, C>((_, b, c): (A, B, C)) -> (B, C) { (b, c) }
         let last_two: fn((SafeHash, K, V)) -> (K, V) = last_two; // coerce to fn pointer
 
@@ -1054,6 +1457,28 @@ impl<K, V, S> HashMap<K, V, S>
     #[inline]
     pub fn clear(&mut self) {
         self.drain();
+        self.debug_check_invariants();
+    }
+
+    /// Retains only the elements for which the predicate returns true, and
+    /// returns the number of elements removed. The elements are visited in
+    /// a single pass, without rehashing any key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let mut map: HashMap<u32, u32> = (0..8).map(|i| (i, i * 10)).collect();
+    /// assert_eq!(map.retain(|&k, _| k % 2 == 0), 4);
+    /// assert_eq!(map.len(), 4);
+    /// ```
+    pub fn retain<F>(&mut self, keep: F) -> usize
+        where F: FnMut(&K, &mut V) -> bool
+    {
+        let removed = retain_in_place(&mut self.table, keep);
+        self.debug_check_invariants();
+        removed
     }
 
     /// Returns a reference to the value corresponding to the key.
@@ -1148,13 +1573,15 @@ impl<K, V, S> HashMap<K, V, S>
     /// assert_eq!(map[&37], "c");
     /// ```
     pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        let hash = self.make_hash(&k);
+        // Reserving can reseed the hash state, so hash afterwards.
         self.reserve(1);
+        let hash = self.make_hash(&k);
 
         let mut retval = None;
         self.insert_or_replace_with(hash, k, v, |_, val_ref, _, val| {
             retval = Some(replace(val_ref, val));
         });
+        self.debug_check_invariants();
         retval
     }
 
@@ -1182,7 +1609,9 @@ impl<K, V, S> HashMap<K, V, S>
             return None
         }
 
-        self.search_mut(k).map(|bucket| pop_internal(bucket).1)
+        let result = self.search_mut(k).map(|bucket| pop_internal(bucket).1);
+        self.debug_check_invariants();
+        result
     }
 
     /// Removes a key from the map, returning the (key, value) tuple at the key
@@ -1209,12 +1638,387 @@ impl<K, V, S> HashMap<K, V, S>
             return None
         }
 
-        self.search_mut(k).map(|bucket| pop_internal(bucket))
+        let result = self.search_mut(k).map(|bucket| pop_internal(bucket));
+        self.debug_check_invariants();
+        result
+    }
+
+    /// Incrementally iterates over the map with a cursor, in the manner of
+    /// the Redis `SCAN` command.
+    ///
+    /// Start a scan by passing a cursor of `0`. Each call returns the cursor
+    /// to pass to the next call, along with a batch of at least `count`
+    /// key-value pairs, unless the scan has finished. The scan is complete
+    /// once the returned cursor is `0`.
+    ///
+    /// The map may be modified and resized between calls. Every entry that
+    /// is present in the map for the whole duration of the scan is returned
+    /// at least once. Entries may be returned more than once, and entries
+    /// inserted or removed during the scan may or may not be returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let mut map = HashMap::new();
+    /// for i in 0..100 {
+    ///     map.insert(i, i * 2);
+    /// }
+    ///
+    /// let mut seen = Vec::new();
+    /// let mut cursor = 0;
+    /// loop {
+    ///     let (next, batch) = map.scan(cursor, 10);
+    ///     seen.extend(batch.into_iter().map(|(&k, _)| k));
+    ///     cursor = next;
+    ///     if cursor == 0 { break }
+    /// }
+    ///
+    /// seen.sort();
+    /// seen.dedup();
+    /// assert_eq!(seen.len(), 100);
+    /// ```
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&K, &V)>) {
+        let mut entries = Vec::new();
+        let cap = self.table.capacity();
+        if cap == 0 {
+            return (0, entries);
+        }
+
+        // Initial buckets are visited in the order given by incrementing the
+        // cursor's bits in reverse. Because the capacity is always a power of
+        // two, the buckets visited so far still cover the same hashes after
+        // the table doubles or halves.
+        let mask = cap as u64 - 1;
+        let mut cursor = cursor;
+        loop {
+            self.scan_initial_bucket((cursor & mask) as usize, |k, v| entries.push((k, v)));
+            cursor = next_scan_cursor(cursor, mask);
+            if cursor == 0 || entries.len() >= count {
+                return (cursor, entries);
+            }
+        }
     }
+
+    /// Calls `f` on every entry whose initial bucket is `ib`.
+    fn scan_initial_bucket<'a, F>(&'a self, ib: usize, mut f: F)
+        where F: FnMut(&'a K, &'a V)
+    {
+        let mut probe = Bucket::at_index(&self.table, ib);
+
+        // Robin Hood hashing keeps the entries of a cluster ordered by their
+        // initial buckets, so all entries that belong to `ib` are adjacent.
+        for displacement in 0..self.table.capacity() {
+            let full = match probe.peek() {
+                Empty(..) => return,
+                Full(full) => full
+            };
+
+            let distance = full.distance();
+            if distance < displacement {
+                // This entry and all after it belong to a later initial bucket.
+                return;
+            }
+            if distance == displacement {
+                let (k, v) = full.into_refs();
+                f(k, v);
+            }
+
+            probe.next();
+        }
+    }
+
+    /// Checks the internal invariants of the map, returning the first
+    /// violation found.
+    ///
+    /// Besides the structure of the Robin Hood table, this re-hashes every
+    /// key and compares the result with the hash stored alongside it. A
+    /// mismatch means that a key's hash changed after insertion, typically
+    /// through a `Cell`/`RefCell`, or that its `Hash` implementation is
+    /// nondeterministic.
+    ///
+    /// With the `debug-invariants` feature enabled, the map runs this check
+    /// after each of its mutating methods and panics on a violation. An
+    /// `Entry` can't run it once it's done, so `entry` and `entry2` check
+    /// the changes made through the previous entry instead, and those made
+    /// through the last one are never checked. Neither are writes through
+    /// the `&mut V` of `get_mut`, `iter_mut` or `values_mut`: they can only
+    /// break an invariant by changing a key through interior mutability,
+    /// which the next check catches.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let mut map = HashMap::new();
+    /// map.insert(1, "a");
+    /// assert_eq!(map.check_invariants(), Ok(()));
+    /// ```
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        check_table(&self.table, |hash, k| self.make_hash(k) == hash)
+    }
+
+    /// Returns statistics about the layout of the map's table.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let map: HashMap<u32, u32> = (0..100).map(|i| (i, i)).collect();
+    /// let stats = map.stats();
+    /// assert_eq!(stats.len, 100);
+    /// assert!(stats.max_displacement < stats.buckets);
+    /// ```
+    pub fn stats(&self) -> TableStats {
+        TableStats {
+            len: self.table.size(),
+            buckets: self.table.capacity(),
+            max_displacement: self.table.max_displacement(),
+        }
+    }
+
+    /// Sets what the map does when an insertion displaces an entry further
+    /// than a limit from its ideal bucket, or `None` to do nothing, which is
+    /// the default.
+    ///
+    /// Long displacements mean clustered hashes, from a weak hash function
+    /// or from keys chosen to collide. Growing spreads the clusters out;
+    /// reseeding breaks them up if the keys were chosen against the old
+    /// seed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::{DisplacementLimit, HashMap};
+    ///
+    /// let mut map = HashMap::new();
+    /// map.set_displacement_limit(Some(DisplacementLimit::reseed(32)));
+    /// for i in 0..1000 {
+    ///     map.insert(i, i);
+    /// }
+    /// assert!(map.stats().max_displacement <= 32);
+    /// ```
+    pub fn set_displacement_limit(&mut self, limit: Option<DisplacementLimit<S>>) {
+        self.displacement_limit = limit;
+        self.enforce_displacement_limit();
+    }
+
+    /// Returns the keys whose hash no longer matches the hash stored for
+    /// them when they were inserted.
+    ///
+    /// A key's hash can change through a `Cell` or `RefCell`, or because of a
+    /// buggy `Hash` implementation. Lookups for such keys silently miss. Use
+    /// `rehash_in_place` to repair the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let mut map = HashMap::new();
+    /// map.insert("a", 1);
+    /// assert!(map.find_stale_hashes().is_empty());
+    /// ```
+    pub fn find_stale_hashes(&self) -> Vec<&K> {
+        let mut stale = Vec::new();
+        let cap = self.table.capacity();
+        if cap == 0 {
+            return stale;
+        }
+
+        let mut bucket = Bucket::first(&self.table);
+        for _ in 0..cap {
+            if let Full(full) = bucket.peek() {
+                let hash = full.hash();
+                let (k, _) = full.into_refs();
+                if self.make_hash(k) != hash {
+                    stale.push(k);
+                }
+            }
+            bucket.next();
+        }
+        stale
+    }
+
+    /// Rebuilds the table from freshly computed hashes of all keys, moving
+    /// the entries within the current allocation. Returns the number of
+    /// keys whose hash had changed.
+    ///
+    /// The table is swept once, with one bit of scratch space per bucket to
+    /// mark the entries not yet moved. No entry is copied out of the table.
+    ///
+    /// If several keys have become equal to each other, the one moved first
+    /// is kept with its value, and the others are dropped. Which one that
+    /// is depends on where they were in the table, so it is unspecified.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::cell::Cell;
+    /// use std::hash::{Hash, Hasher};
+    /// use hashmap2::HashMap;
+    ///
+    /// #[derive(PartialEq, Eq)]
+    /// struct Key(Cell<u32>);
+    ///
+    /// impl Hash for Key {
+    ///     fn hash<H: Hasher>(&self, state: &mut H) { self.0.get().hash(state) }
+    /// }
+    ///
+    /// let mut map = HashMap::new();
+    /// map.insert(Key(Cell::new(1)), "a");
+    /// map.keys().next().unwrap().0.set(2);
+    /// assert_eq!(map.get(&Key(Cell::new(2))), None);
+    ///
+    /// assert_eq!(map.rehash_in_place(), 1);
+    /// assert_eq!(map.get(&Key(Cell::new(2))), Some(&"a"));
+    /// ```
+    pub fn rehash_in_place(&mut self) -> usize {
+        let cap = self.table.capacity();
+        if self.table.size() == 0 {
+            return 0;
+        }
+
+        let mut unsettled: Vec<u64> = iter::repeat(0).take((cap + 63) / 64).collect();
+        {
+            let mut bucket = Bucket::first(&self.table);
+            for i in 0..cap {
+                if let Full(_) = bucket.peek() {
+                    unsettled[i / 64] |= 1 << (i % 64);
+                }
+                bucket.next();
+            }
+        }
+        self.table.reset_max_displacement();
+
+        let mut stale = 0;
+        for i in 0..cap {
+            if unsettled[i / 64] & (1 << (i % 64)) == 0 {
+                continue;
+            }
+            unsettled[i / 64] &= !(1 << (i % 64));
+
+            // Take the entry out, and place it. Each unsettled entry it
+            // lands on is placed in turn, until one lands in an empty bucket.
+            let bucket = Bucket::at_index(&mut self.table, i).peek().expect_full();
+            let old_hash = bucket.hash();
+            let (_, k, v) = bucket.take();
+            let mut next = Some((old_hash, k, v));
+            while let Some((old_hash, k, v)) = next {
+                let hash = self.make_hash(&k);
+                if hash != old_hash {
+                    stale += 1;
+                }
+                next = settle(&mut self.table, &mut unsettled, hash, k, v);
+            }
+        }
+
+        self.debug_check_invariants();
+        stale
+    }
+
+    /// Runs `check_invariants` if the `debug-invariants` feature is enabled.
+    #[inline]
+    fn debug_check_invariants(&self) {
+        if cfg!(feature = "debug-invariants") {
+            if let Err(violation) = self.check_invariants() {
+                panic!("HashMap invariant violated: {}", violation);
+            }
+        }
+    }
+}
+
+/// Walks every bucket of `table`, checking the invariants of Robin Hood
+/// hashing. `hash_matches` is called on each full bucket to verify its
+/// stored hash.
+fn check_table<K, V, L: Layout, F>(table: &RawTable<K, V, L>, mut hash_matches: F)
+                        -> Result<(), InvariantViolation> where
+    F: FnMut(SafeHash, &K) -> bool,
+{
+    let cap = table.capacity();
+    let mut full_buckets = 0;
+
+    if cap > 0 {
+        // The bucket before the first one is the last one.
+        let mut previous = match Bucket::at_index(table, cap - 1).peek() {
+            Full(full) => Some(full.distance()),
+            Empty(..) => None,
+        };
+
+        let mut bucket = Bucket::first(table);
+        for index in 0..cap {
+            previous = match bucket.peek() {
+                Empty(..) => None,
+                Full(full) => {
+                    full_buckets += 1;
+
+                    if full.hash().inspect() & (1 << 63) == 0 {
+                        return Err(InvariantViolation::InvalidHash { index: index });
+                    }
+
+                    // Displacement can only grow by one from a full bucket,
+                    // and must be zero right after an empty one.
+                    let distance = full.distance();
+                    if distance > previous.map_or(0, |d| d + 1) {
+                        return Err(InvariantViolation::DisplacementJump {
+                            index: index,
+                            distance: distance,
+                            previous: previous,
+                        });
+                    }
+
+                    if distance > table.max_displacement() {
+                        return Err(InvariantViolation::DisplacementOverBound {
+                            index: index,
+                            distance: distance,
+                            bound: table.max_displacement(),
+                        });
+                    }
+
+                    if !hash_matches(full.hash(), full.read().0) {
+                        return Err(InvariantViolation::StaleHash { index: index });
+                    }
+
+                    Some(distance)
+                }
+            };
+            bucket.next();
+        }
+    }
+
+    if full_buckets != table.size() {
+        return Err(InvariantViolation::SizeMismatch {
+            size: table.size(),
+            full_buckets: full_buckets,
+        });
+    }
+
+    Ok(())
+}
+
+/// Advances a `HashMap::scan` cursor by incrementing its bits in reverse
+/// order, starting from the highest bit covered by `mask`.
+fn next_scan_cursor(cursor: u64, mask: u64) -> u64 {
+    // Setting the bits above the mask makes the reversed increment carry
+    // through them, which clears them again.
+    let reversed = reverse_bits(cursor | !mask);
+    reverse_bits(reversed.wrapping_add(1))
+}
+
+fn reverse_bits(mut x: u64) -> u64 {
+    let mut reversed = 0;
+    for _ in 0..64 {
+        reversed = (reversed << 1) | (x & 1);
+        x >>= 1;
+    }
+    reversed
 }
 
-fn search_entry_hashed<'a, K: Eq, V>(table: &'a mut RawTable<K,V>, hash: SafeHash, k: K)
-        -> Entry<'a, K, V>
+fn search_entry_hashed<'a, K: Eq, V, L: Layout>(table: &'a mut RawTable<K, V, L>, hash: SafeHash, k: K)
+        -> Entry<'a, K, V, L>
 {
     // Worst case, we'll find one empty bucket among `size + 1` buckets.
     let size = table.size();
@@ -1261,8 +2065,8 @@ fn search_entry_hashed<'a, K: Eq, V>(table: &'a mut RawTable<K,V>, hash: SafeHas
 }
 
 // Not copying this requires specialization
-fn search_entry_hashed2<'a, K: Eq, V, Q: ?Sized>(table: &'a mut RawTable<K,V>, hash: SafeHash, k: Cow<Q>)
-        -> Entry<'a, K, V>
+fn search_entry_hashed2<'a, K: Eq, V, L: Layout, Q: ?Sized>(table: &'a mut RawTable<K, V, L>, hash: SafeHash, k: Cow<Q>)
+        -> Entry<'a, K, V, L>
         where K: Borrow<Q>, Q: ToOwned<Owned=K> + Eq,
 {
     // Worst case, we'll find one empty bucket among `size + 1` buckets.
@@ -1311,10 +2115,10 @@ fn search_entry_hashed2<'a, K: Eq, V, Q: ?Sized>(table: &'a mut RawTable<K,V>, h
     }
 }
 
-impl<K, V, S> PartialEq for HashMap<K, V, S>
+impl<K, V, S, L: Layout> PartialEq for HashMap<K, V, S, L>
     where K: Eq + Hash, V: PartialEq, S: HashState
 {
-    fn eq(&self, other: &HashMap<K, V, S>) -> bool {
+    fn eq(&self, other: &HashMap<K, V, S, L>) -> bool {
         if self.len() != other.len() { return false; }
 
         self.iter().all(|(key, value)|
@@ -1323,11 +2127,11 @@ impl<K, V, S> PartialEq for HashMap<K, V, S>
     }
 }
 
-impl<K, V, S> Eq for HashMap<K, V, S>
+impl<K, V, S, L: Layout> Eq for HashMap<K, V, S, L>
     where K: Eq + Hash, V: Eq, S: HashState
 {}
 
-impl<K, V, S> Debug for HashMap<K, V, S>
+impl<K, V, S, L: Layout> Debug for HashMap<K, V, S, L>
     where K: Eq + Hash + Debug, V: Debug, S: HashState
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -1335,16 +2139,16 @@ impl<K, V, S> Debug for HashMap<K, V, S>
     }
 }
 
-impl<K, V, S> Default for HashMap<K, V, S>
+impl<K, V, S, L: Layout> Default for HashMap<K, V, S, L>
     where K: Eq + Hash,
           S: HashState + Default,
 {
-    fn default() -> HashMap<K, V, S> {
-        HashMap::with_hash_state(Default::default())
+    fn default() -> HashMap<K, V, S, L> {
+        HashMap::with_layout(0, Default::default())
     }
 }
 
-impl<'a, K, Q: ?Sized, V, S> Index<&'a Q> for HashMap<K, V, S>
+impl<'a, K, Q: ?Sized, V, S, L: Layout> Index<&'a Q> for HashMap<K, V, S, L>
     where K: Eq + Hash + Borrow<Q>,
           Q: Eq + Hash,
           S: HashState,
@@ -1358,13 +2162,13 @@ impl<'a, K, Q: ?Sized, V, S> Index<&'a Q> for HashMap<K, V, S>
 }
 
 /// HashMap iterator.
-pub struct Iter<'a, K: 'a, V: 'a> {
-    inner: table::Iter<'a, K, V>
+pub struct Iter<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
+    inner: table::Iter<'a, K, V, L>
 }
 
 // FIXME(#19839) Remove in favor of `#[derive(Clone)]`
-impl<'a, K, V> Clone for Iter<'a, K, V> {
-    fn clone(&self) -> Iter<'a, K, V> {
+impl<'a, K, V, L: Layout> Clone for Iter<'a, K, V, L> {
+    fn clone(&self) -> Iter<'a, K, V, L> {
         Iter {
             inner: self.inner.clone()
         }
@@ -1372,23 +2176,23 @@ impl<'a, K, V> Clone for Iter<'a, K, V> {
 }
 
 /// HashMap mutable values iterator.
-pub struct IterMut<'a, K: 'a, V: 'a> {
-    inner: table::IterMut<'a, K, V>
+pub struct IterMut<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
+    inner: table::IterMut<'a, K, V, L>
 }
 
 /// HashMap move iterator.
-pub struct IntoIter<K, V> {
-    inner: iter::Map<table::IntoIter<K, V>, fn((SafeHash, K, V)) -> (K, V)>
+pub struct IntoIter<K, V, L: Layout = SplitLayout> {
+    inner: iter::Map<table::IntoIter<K, V, L>, fn((SafeHash, K, V)) -> (K, V)>
 }
 
 /// HashMap keys iterator.
-pub struct Keys<'a, K: 'a, V: 'a> {
-    inner: Map<Iter<'a, K, V>, fn((&'a K, &'a V)) -> &'a K>
+pub struct Keys<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
+    inner: Map<Iter<'a, K, V, L>, fn((&'a K, &'a V)) -> &'a K>
 }
 
 // FIXME(#19839) Remove in favor of `#[derive(Clone)]`
-impl<'a, K, V> Clone for Keys<'a, K, V> {
-    fn clone(&self) -> Keys<'a, K, V> {
+impl<'a, K, V, L: Layout> Clone for Keys<'a, K, V, L> {
+    fn clone(&self) -> Keys<'a, K, V, L> {
         Keys {
             inner: self.inner.clone()
         }
@@ -1396,13 +2200,13 @@ impl<'a, K, V> Clone for Keys<'a, K, V> {
 }
 
 /// HashMap values iterator.
-pub struct Values<'a, K: 'a, V: 'a> {
-    inner: Map<Iter<'a, K, V>, fn((&'a K, &'a V)) -> &'a V>
+pub struct Values<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
+    inner: Map<Iter<'a, K, V, L>, fn((&'a K, &'a V)) -> &'a V>
 }
 
 // FIXME(#19839) Remove in favor of `#[derive(Clone)]`
-impl<'a, K, V> Clone for Values<'a, K, V> {
-    fn clone(&self) -> Values<'a, K, V> {
+impl<'a, K, V, L: Layout> Clone for Values<'a, K, V, L> {
+    fn clone(&self) -> Values<'a, K, V, L> {
         Values {
             inner: self.inner.clone()
         }
@@ -1410,29 +2214,29 @@ impl<'a, K, V> Clone for Values<'a, K, V> {
 }
 
 /// HashMap drain iterator.
-pub struct Drain<'a, K: 'a, V: 'a> {
-    inner: iter::Map<table::Drain<'a, K, V>, fn((SafeHash, K, V)) -> (K, V)>
+pub struct Drain<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
+    inner: iter::Map<table::Drain<'a, K, V, L>, fn((SafeHash, K, V)) -> (K, V)>
 }
 
 /// A view into a single occupied location in a HashMap.
-pub struct OccupiedEntry<'a, K: 'a, V: 'a> {
-    elem: FullBucket<K, V, &'a mut RawTable<K, V>>,
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
+    elem: FullBucket<K, V, &'a mut RawTable<K, V, L>>,
 }
 
 /// A view into a single empty location in a HashMap.
-pub struct VacantEntry<'a, K: 'a, V: 'a> {
+pub struct VacantEntry<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
     hash: SafeHash,
     key: K,
-    elem: VacantEntryState<K, V, &'a mut RawTable<K, V>>,
+    elem: VacantEntryState<K, V, &'a mut RawTable<K, V, L>>,
 }
 
 /// A view into a single location in a map, which may be vacant or occupied.
-pub enum Entry<'a, K: 'a, V: 'a> {
+pub enum Entry<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
     /// An occupied Entry.
-    Occupied(OccupiedEntry<'a, K, V>),
+    Occupied(OccupiedEntry<'a, K, V, L>),
 
     /// A vacant Entry.
-    Vacant(VacantEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V, L>),
 }
 
 /// Possible states of a VacantEntry.
@@ -1444,33 +2248,33 @@ enum VacantEntryState<K, V, M> {
     NoElem(EmptyBucket<K, V, M>),
 }
 
-impl<'a, K, V, S> IntoIterator for &'a HashMap<K, V, S>
+impl<'a, K, V, S, L: Layout> IntoIterator for &'a HashMap<K, V, S, L>
     where K: Eq + Hash, S: HashState
 {
     type Item = (&'a K, &'a V);
-    type IntoIter = Iter<'a, K, V>;
+    type IntoIter = Iter<'a, K, V, L>;
 
-    fn into_iter(self) -> Iter<'a, K, V> {
+    fn into_iter(self) -> Iter<'a, K, V, L> {
         self.iter()
     }
 }
 
-impl<'a, K, V, S> IntoIterator for &'a mut HashMap<K, V, S>
+impl<'a, K, V, S, L: Layout> IntoIterator for &'a mut HashMap<K, V, S, L>
     where K: Eq + Hash, S: HashState
 {
     type Item = (&'a K, &'a mut V);
-    type IntoIter = IterMut<'a, K, V>;
+    type IntoIter = IterMut<'a, K, V, L>;
 
-    fn into_iter(mut self) -> IterMut<'a, K, V> {
+    fn into_iter(mut self) -> IterMut<'a, K, V, L> {
         self.iter_mut()
     }
 }
 
-impl<K, V, S> IntoIterator for HashMap<K, V, S>
+impl<K, V, S, L: Layout> IntoIterator for HashMap<K, V, S, L>
     where K: Eq + Hash, S: HashState
 {
     type Item = (K, V);
-    type IntoIter = IntoIter<K, V>;
+    type IntoIter = IntoIter<K, V, L>;
 
     /// Creates a consuming iterator, that is, one that moves each key-value
     /// pair out of the map in arbitrary order. The map cannot be used after
@@ -1489,7 +2293,7 @@ impl<K, V, S> IntoIterator for HashMap<K, V, S>
     /// // Not possible with .iter()
     /// let vec: Vec<(&str, isize)> = map.into_iter().collect();
     /// ```
-    fn into_iter(self) -> IntoIter<K, V> {
+    fn into_iter(self) -> IntoIter<K, V, L> {
         fn last_two<A, B, C>((_, b, c): (A, B, C)) -> (B, C) { (b, c) }
         let last_two: fn((SafeHash, K, V)) -> (K, V) = last_two;
 
@@ -1499,67 +2303,67 @@ impl<K, V, S> IntoIterator for HashMap<K, V, S>
     }
 }
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for Iter<'a, K, V, L> {
     type Item = (&'a K, &'a V);
 
     #[inline] fn next(&mut self) -> Option<(&'a K, &'a V)> { self.inner.next() }
     #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
 }
-impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
+impl<'a, K, V, L: Layout> ExactSizeIterator for Iter<'a, K, V, L> {
     #[inline] fn len(&self) -> usize { self.inner.len() }
 }
 
-impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for IterMut<'a, K, V, L> {
     type Item = (&'a K, &'a mut V);
 
     #[inline] fn next(&mut self) -> Option<(&'a K, &'a mut V)> { self.inner.next() }
     #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
 }
-impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {
+impl<'a, K, V, L: Layout> ExactSizeIterator for IterMut<'a, K, V, L> {
     #[inline] fn len(&self) -> usize { self.inner.len() }
 }
 
-impl<K, V> Iterator for IntoIter<K, V> {
+impl<K, V, L: Layout> Iterator for IntoIter<K, V, L> {
     type Item = (K, V);
 
     #[inline] fn next(&mut self) -> Option<(K, V)> { self.inner.next() }
     #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
 }
-impl<K, V> ExactSizeIterator for IntoIter<K, V> {
+impl<K, V, L: Layout> ExactSizeIterator for IntoIter<K, V, L> {
     #[inline] fn len(&self) -> usize { self.inner.len() }
 }
 
-impl<'a, K, V> Iterator for Keys<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for Keys<'a, K, V, L> {
     type Item = &'a K;
 
     #[inline] fn next(&mut self) -> Option<(&'a K)> { self.inner.next() }
     #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
 }
-impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {
+impl<'a, K, V, L: Layout> ExactSizeIterator for Keys<'a, K, V, L> {
     #[inline] fn len(&self) -> usize { self.inner.len() }
 }
 
-impl<'a, K, V> Iterator for Values<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for Values<'a, K, V, L> {
     type Item = &'a V;
 
     #[inline] fn next(&mut self) -> Option<(&'a V)> { self.inner.next() }
     #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
 }
-impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {
+impl<'a, K, V, L: Layout> ExactSizeIterator for Values<'a, K, V, L> {
     #[inline] fn len(&self) -> usize { self.inner.len() }
 }
 
-impl<'a, K, V> Iterator for Drain<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for Drain<'a, K, V, L> {
     type Item = (K, V);
 
     #[inline] fn next(&mut self) -> Option<(K, V)> { self.inner.next() }
     #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
 }
-impl<'a, K, V> ExactSizeIterator for Drain<'a, K, V> {
+impl<'a, K, V, L: Layout> ExactSizeIterator for Drain<'a, K, V, L> {
     #[inline] fn len(&self) -> usize { self.inner.len() }
 }
 
-impl<'a, K, V> Entry<'a, K, V> {
+impl<'a, K, V, L: Layout> Entry<'a, K, V, L> {
     /// Returns the entry key
     ///
     /// # Examples
@@ -1597,7 +2401,7 @@ impl<'a, K, V> Entry<'a, K, V> {
     }
 }
 
-impl<'a, K, V> OccupiedEntry<'a, K, V> {
+impl<'a, K, V, L: Layout> OccupiedEntry<'a, K, V, L> {
     /// Gets a reference to the value in the entry.
     pub fn get(&self) -> &V {
         self.elem.read().1
@@ -1643,16 +2447,21 @@ impl<'a, K, V> OccupiedEntry<'a, K, V> {
     }
 }
 
-impl<'a, K: 'a, V: 'a> VacantEntry<'a, K, V> {
+impl<'a, K: 'a, V: 'a, L: Layout> VacantEntry<'a, K, V, L> {
     /// Sets the value of the entry with the VacantEntry's key,
     /// and returns a mutable reference to it
     pub fn insert(self, value: V) -> &'a mut V {
+        self.insert_refs(value).1
+    }
+
+    /// Like `insert`, but also returns a reference to the stored key.
+    fn insert_refs(self, value: V) -> (&'a mut K, &'a mut V) {
         match self.elem {
             NeqElem(bucket, ib) => {
                 robin_hood(bucket, ib, self.hash, self.key, value)
             }
             NoElem(bucket) => {
-                bucket.put(self.hash, self.key, value).into_mut_refs().1
+                bucket.put(self.hash, self.key, value).into_mut_refs()
             }
         }
     }
@@ -1673,20 +2482,19 @@ impl<'a, K: 'a, V: 'a> VacantEntry<'a, K, V> {
     }
 }
 
-impl<K, V, S> FromIterator<(K, V)> for HashMap<K, V, S>
+impl<K, V, S, L: Layout> FromIterator<(K, V)> for HashMap<K, V, S, L>
     where K: Eq + Hash, S: HashState + Default
 {
-    fn from_iter<T: IntoIterator<Item=(K, V)>>(iterable: T) -> HashMap<K, V, S> {
+    fn from_iter<T: IntoIterator<Item=(K, V)>>(iterable: T) -> HashMap<K, V, S, L> {
         let iter = iterable.into_iter();
         let lower = iter.size_hint().0;
-        let mut map = HashMap::with_capacity_and_hash_state(lower,
-                                                            Default::default());
+        let mut map = HashMap::with_layout(lower, Default::default());
         map.extend(iter);
         map
     }
 }
 
-impl<K, V, S> Extend<(K, V)> for HashMap<K, V, S>
+impl<K, V, S, L: Layout> Extend<(K, V)> for HashMap<K, V, S, L>
     where K: Eq + Hash, S: HashState
 {
     fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
@@ -1696,7 +2504,7 @@ impl<K, V, S> Extend<(K, V)> for HashMap<K, V, S>
     }
 }
 
-impl<'a, K, V, S> Extend<(&'a K, &'a V)> for HashMap<K, V, S>
+impl<'a, K, V, S, L: Layout> Extend<(&'a K, &'a V)> for HashMap<K, V, S, L>
     where K: Eq + Hash + Copy, V: Copy, S: HashState
 {
     fn extend<T: IntoIterator<Item=(&'a K, &'a V)>>(&mut self, iter: T) {
@@ -1704,11 +2512,157 @@ impl<'a, K, V, S> Extend<(&'a K, &'a V)> for HashMap<K, V, S>
     }
 }
 
+/// Statistics about the table of a `HashMap`, from `HashMap::stats`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableStats {
+    /// The number of entries.
+    pub len: usize,
+
+    /// The number of buckets, full or empty.
+    pub buckets: usize,
+
+    /// An upper bound on how far any entry is from its ideal bucket, which
+    /// is also how far a lookup probes before giving up. It rises with
+    /// insertions but is only reset by emptying the map, since finding the
+    /// new maximum after a removal would take a full scan.
+    pub max_displacement: usize,
+}
+
+/// A limit on how far an insertion may displace an entry from its ideal
+/// bucket, and what a `HashMap` does when it's passed. See
+/// `HashMap::set_displacement_limit`.
+///
+/// If growing or reseeding doesn't bring the displacement back under the
+/// limit, which happens when keys' hashes collide outright, the limit is
+/// raised to twice the displacement, so that the map doesn't keep trying.
+pub struct DisplacementLimit<S> {
+    limit: usize,
+    reseed: Option<fn(&mut S)>,
+}
+
+impl<S> DisplacementLimit<S> {
+    /// Doubles the capacity of the table past `limit`.
+    pub fn grow(limit: usize) -> DisplacementLimit<S> {
+        DisplacementLimit { limit: limit, reseed: None }
+    }
+
+    /// Reseeds the hash state past `limit`, and rehashes every key.
+    pub fn reseed(limit: usize) -> DisplacementLimit<S> where S: Reseed {
+        DisplacementLimit { limit: limit, reseed: Some(S::reseed) }
+    }
+
+    /// Returns the limit.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+// Derived, these would require `S: Clone`.
+impl<S> Clone for DisplacementLimit<S> {
+    fn clone(&self) -> DisplacementLimit<S> { *self }
+}
+impl<S> Copy for DisplacementLimit<S> {}
+
+impl<S> Debug for DisplacementLimit<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = if self.reseed.is_some() { "reseed" } else { "grow" };
+        write!(f, "DisplacementLimit {{ limit: {}, action: {} }}", self.limit, action)
+    }
+}
+
+/// A hash state that can replace its keys, to defeat keys chosen to collide
+/// under the old ones.
+pub trait Reseed {
+    /// Replaces the keys of the hash state.
+    fn reseed(&mut self);
+}
+
+/// A broken invariant of a `HashMap`, as reported by
+/// `HashMap::check_invariants`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// The table's element count differs from its number of full buckets.
+    SizeMismatch {
+        /// The element count recorded by the table.
+        size: usize,
+        /// The number of full buckets actually found.
+        full_buckets: usize,
+    },
+
+    /// The bucket at `index` holds a hash that was not produced by hashing a
+    /// key. Such hashes always have their highest bit set, so that they can
+    /// never equal the hash of an empty bucket.
+    InvalidHash {
+        /// The index of the offending bucket.
+        index: usize,
+    },
+
+    /// The displacement of the entry at `index` grows by more than one from
+    /// the bucket before it.
+    DisplacementJump {
+        /// The index of the offending bucket.
+        index: usize,
+        /// The displacement of the entry in this bucket.
+        distance: usize,
+        /// The displacement of the previous entry, or `None` if the
+        /// previous bucket is empty.
+        previous: Option<usize>,
+    },
+
+    /// The key at `index` no longer hashes to the hash stored with it.
+    StaleHash {
+        /// The index of the offending bucket.
+        index: usize,
+    },
+
+    /// The entry at `index` is further from its ideal bucket than the
+    /// maximum displacement the table tracks, so lookups would miss it.
+    DisplacementOverBound {
+        /// The index of the offending bucket.
+        index: usize,
+        /// The displacement of the entry in this bucket.
+        distance: usize,
+        /// The maximum displacement recorded by the table.
+        bound: usize,
+    },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvariantViolation::SizeMismatch { size, full_buckets } => {
+                write!(f, "table size is {}, but {} buckets are full", size, full_buckets)
+            }
+            InvariantViolation::InvalidHash { index } => {
+                write!(f, "bucket {} holds an invalid hash", index)
+            }
+            InvariantViolation::DisplacementJump { index, distance, previous: Some(previous) } => {
+                write!(f, "bucket {} has displacement {} after displacement {}",
+                       index, distance, previous)
+            }
+            InvariantViolation::DisplacementJump { index, distance, previous: None } => {
+                write!(f, "bucket {} has displacement {} after an empty bucket",
+                       index, distance)
+            }
+            InvariantViolation::StaleHash { index } => {
+                write!(f, "key in bucket {} no longer matches its stored hash", index)
+            }
+            InvariantViolation::DisplacementOverBound { index, distance, bound } => {
+                write!(f, "entry in bucket {} has displacement {}, over the table's bound of {}",
+                       index, distance, bound)
+            }
+        }
+    }
+}
+
 /// `RandomState` is the default state for `HashMap` types.
 ///
 /// A particular instance `RandomState` will create the same instances of
 /// `Hasher`, but the hashers created by two different `RandomState`
 /// instances are unlikely to produce the same result for the same values.
+///
+/// Without the `std` feature there is no source of randomness, so a
+/// `RandomState` can only be built from keys with `with_keys`.
 #[derive(Clone)]
 pub struct RandomState {
     k0: u64,
@@ -1717,12 +2671,39 @@ pub struct RandomState {
 
 impl RandomState {
     /// Constructs a new `RandomState` that is initialized with random keys.
+    #[cfg(feature = "std")]
     #[inline]
     #[allow(deprecated)] // rand
     pub fn new() -> RandomState {
         let mut r = rand::thread_rng();
         RandomState { k0: r.gen(), k1: r.gen() }
     }
+
+    /// Constructs a `RandomState` with the given keys.
+    ///
+    /// The keys should be secret and random; predictable keys make the map
+    /// open to the collision attacks `RandomState` is meant to prevent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::{HashMap, RandomState};
+    ///
+    /// let mut map = HashMap::with_hash_state(RandomState::with_keys(0x1234, 0x5678));
+    /// map.insert(1, 2);
+    /// ```
+    #[inline]
+    pub fn with_keys(k0: u64, k1: u64) -> RandomState {
+        RandomState { k0: k0, k1: k1 }
+    }
+
+    /// Returns the keys, from which `with_keys` builds an identical
+    /// `RandomState`, e.g. to hash keys the same way on another machine.
+    /// Anyone who knows them can choose keys that collide.
+    #[inline]
+    pub fn keys(&self) -> (u64, u64) {
+        (self.k0, self.k1)
+    }
 }
 
 impl HashState for RandomState {
@@ -1733,6 +2714,7 @@ impl HashState for RandomState {
     }
 }
 
+#[cfg(feature = "std")]
 impl Default for RandomState {
     #[inline]
     fn default() -> RandomState {
@@ -1740,6 +2722,13 @@ impl Default for RandomState {
     }
 }
 
+#[cfg(feature = "std")]
+impl Reseed for RandomState {
+    fn reseed(&mut self) {
+        *self = RandomState::new();
+    }
+}
+
 impl<K, S, Q: ?Sized> Recover<Q> for HashMap<K, (), S>
     where K: Eq + Hash + Borrow<Q>, S: HashState, Q: Eq + Hash
 {
@@ -1754,17 +2743,20 @@ impl<K, S, Q: ?Sized> Recover<Q> for HashMap<K, (), S>
             return None
         }
 
-        self.search_mut(key).map(|bucket| pop_internal(bucket).0)
+        let result = self.search_mut(key).map(|bucket| pop_internal(bucket).0);
+        self.debug_check_invariants();
+        result
     }
 
     fn replace(&mut self, key: K) -> Option<K> {
-        let hash = self.make_hash(&key);
         self.reserve(1);
+        let hash = self.make_hash(&key);
 
         let mut retkey = None;
         self.insert_or_replace_with(hash, key, (), |key_ref, _, key, _| {
             retkey = Some(replace(key_ref, key));
         });
+        self.debug_check_invariants();
         retkey
     }
 }
@@ -1775,6 +2767,9 @@ mod test_map {
     use super::Entry::{Occupied, Vacant};
     use std::iter::range_inclusive;
     use std::cell::RefCell;
+    use std::hash::Hasher;
+    use std::collections::hash_state::HashState;
+    use recover::Recover;
     use rand::{thread_rng, Rng};
 
     #[test]
@@ -2482,4 +3477,358 @@ mod test_map {
         assert_eq!(k, "foo");
         assert_eq!(v, 1);
     }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut m = HashMap::new();
+        assert_eq!(m.check_invariants(), Ok(()));
+
+        for i in 0..1000 {
+            m.insert(i, i);
+        }
+        for i in 0..500 {
+            m.remove(&(i * 2));
+        }
+        assert_eq!(m.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_check_invariants_stale_hash() {
+        use std::cell::Cell;
+        use std::hash::{Hash, Hasher};
+        use super::InvariantViolation::StaleHash;
+
+        #[derive(PartialEq, Eq)]
+        struct Key(Cell<u32>);
+
+        impl Hash for Key {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.0.get().hash(state)
+            }
+        }
+
+        let mut m = HashMap::new();
+        for i in 0..10 {
+            m.insert(Key(Cell::new(i)), i);
+        }
+        assert_eq!(m.check_invariants(), Ok(()));
+
+        m.keys().next().unwrap().0.set(100);
+        match m.check_invariants() {
+            Err(StaleHash { .. }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_rehash_in_place() {
+        use std::cell::RefCell;
+        use std::hash::{Hash, Hasher};
+
+        #[derive(PartialEq, Eq)]
+        struct Key(RefCell<String>);
+
+        impl Hash for Key {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.0.borrow().hash(state)
+            }
+        }
+
+        fn key(s: &str) -> Key {
+            Key(RefCell::new(s.to_string()))
+        }
+
+        let mut m = HashMap::new();
+        for i in 0..100 {
+            m.insert(key(&i.to_string()), i);
+        }
+        assert!(m.find_stale_hashes().is_empty());
+
+        for k in m.keys() {
+            if *k.0.borrow() == "7" || *k.0.borrow() == "42" {
+                k.0.borrow_mut().push('!');
+            }
+        }
+        {
+            let mut stale: Vec<String> =
+                m.find_stale_hashes().iter().map(|k| k.0.borrow().clone()).collect();
+            stale.sort();
+            assert_eq!(stale, ["42!", "7!"]);
+        }
+        assert_eq!(m.get(&key("7!")), None);
+
+        let cap = m.capacity();
+        assert_eq!(m.rehash_in_place(), 2);
+        assert_eq!(m.capacity(), cap);
+        assert!(m.find_stale_hashes().is_empty());
+        assert_eq!(m.len(), 100);
+        assert_eq!(m.get(&key("7!")), Some(&7));
+        assert_eq!(m.get(&key("42!")), Some(&42));
+        assert_eq!(m.get(&key("8")), Some(&8));
+
+        // Every key past 300 becomes equal to one below it.
+        let mut m = HashMap::new();
+        for i in 0..1000 {
+            m.insert(key(&i.to_string()), i);
+        }
+        for (k, &i) in m.iter() {
+            *k.0.borrow_mut() = (i % 300).to_string();
+        }
+        let cap = m.capacity();
+        assert_eq!(m.rehash_in_place(), 700);
+        assert_eq!(m.capacity(), cap);
+        assert_eq!(m.len(), 300);
+        assert_eq!(m.check_invariants(), Ok(()));
+        for j in 0..300 {
+            assert_eq!(m[&key(&j.to_string())] % 300, j);
+        }
+    }
+
+    #[test]
+    fn test_estimated_bytes_for() {
+        for &n in &[1, 10, 28, 29, 30, 100, 1000] {
+            let mut m = HashMap::new();
+            for i in 0..n {
+                m.insert(i, i);
+            }
+            assert_eq!(m.allocation_size(), HashMap::<usize, usize>::estimated_bytes_for(n));
+        }
+        assert_eq!(HashMap::<u8, u8>::estimated_bytes_for(0), 0);
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut m = HashMap::new();
+        let (cursor, entries) = m.scan(0, 10);
+        assert_eq!(cursor, 0);
+        assert!(entries.is_empty());
+
+        for i in 0..100 {
+            m.insert(i, i);
+        }
+
+        let mut seen = vec![0; 100];
+        let mut cursor = 0;
+        loop {
+            let (next, entries) = m.scan(cursor, 7);
+            for (&k, &v) in entries {
+                assert_eq!(k, v);
+                seen[k] += 1;
+            }
+            cursor = next;
+            if cursor == 0 { break }
+        }
+        // Without modifications, every entry is returned exactly once.
+        assert!(seen.iter().all(|&n| n == 1));
+    }
+
+    #[test]
+    fn test_scan_while_resizing() {
+        let mut m = HashMap::new();
+        for i in 0..1000 {
+            m.insert(i, ());
+        }
+
+        let mut seen = vec![false; 1000];
+        let mut cursor = 0;
+        let mut extra = 1000;
+        loop {
+            let (next, keys) = {
+                let (next, entries) = m.scan(cursor, 50);
+                (next, entries.into_iter().map(|(&k, _)| k).collect::<Vec<_>>())
+            };
+            for k in keys {
+                if k < 1000 {
+                    seen[k] = true;
+                }
+            }
+            cursor = next;
+            if cursor == 0 { break }
+
+            // Grow the table, then shrink it back down.
+            for _ in 0..500 {
+                m.insert(extra, ());
+                extra += 1;
+            }
+            if extra % 2000 == 0 {
+                for k in 1000..extra {
+                    m.remove(&k);
+                }
+                m.shrink_to_fit();
+            }
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+
+    fn check_layout<L: super::Layout>() {
+        for &capacity in &[0, 1, 2, 32, 1024] {
+            let a = L::allocation::<u16, (u8, String)>(capacity).unwrap();
+            assert_eq!(L::offsets::<u16, (u8, String)>(capacity), (a.key_offset, a.val_offset));
+            let a = L::allocation::<u8, u64>(capacity).unwrap();
+            assert_eq!(L::offsets::<u8, u64>(capacity), (a.key_offset, a.val_offset));
+        }
+
+        let mut m: HashMap<u16, (u8, String), super::RandomState, L> =
+            HashMap::with_layout(0, super::RandomState::new());
+        for i in 0..1000u16 {
+            m.insert(i, (i as u8, i.to_string()));
+        }
+        for i in (0..1000u16).filter(|i| i % 3 == 0) {
+            assert_eq!(m.remove(&i), Some((i as u8, i.to_string())));
+        }
+        assert_eq!(m.len(), 666);
+        assert_eq!(m.check_invariants(), Ok(()));
+
+        let c = m.clone();
+        for i in 0..1000u16 {
+            let expected = if i % 3 == 0 { None } else { Some((i as u8, i.to_string())) };
+            assert_eq!(c.get(&i).cloned(), expected);
+        }
+        assert_eq!(c.iter().count(), 666);
+        assert_eq!(m.drain().count(), 666);
+
+        let mut units: HashMap<u64, (), super::RandomState, L> =
+            HashMap::with_layout(10, super::RandomState::new());
+        units.insert(1, ());
+        assert_eq!(units.into_iter().collect::<Vec<_>>(), [(1, ())]);
+    }
+
+    #[test]
+    fn test_layouts() {
+        check_layout::<super::SplitLayout>();
+        check_layout::<super::InterleavedLayout>();
+        check_layout::<super::HybridLayout>();
+    }
+
+    /// Hashes a `u64` to itself, to place keys in chosen buckets.
+    pub struct IdentityState;
+
+    pub struct IdentityHasher(u64);
+
+    impl Hasher for IdentityHasher {
+        fn finish(&self) -> u64 { self.0 }
+        fn write(&mut self, bytes: &[u8]) {
+            for &b in bytes {
+                self.0 = (self.0 << 8) | b as u64;
+            }
+        }
+        fn write_u64(&mut self, i: u64) { self.0 = i; }
+    }
+
+    impl HashState for IdentityState {
+        type Hasher = IdentityHasher;
+        fn hasher(&self) -> IdentityHasher { IdentityHasher(0) }
+    }
+
+    #[test]
+    fn test_max_displacement() {
+        // Every key's ideal bucket is 0.
+        let mut m = HashMap::with_hash_state(IdentityState);
+        for i in 0..8u64 {
+            m.insert(i << 32, i);
+        }
+        assert_eq!(m.stats().max_displacement, 7);
+        assert_eq!(m.stats().buckets, 32);
+
+        // Removals keep the bound, and lookups still find everything.
+        assert_eq!(m.remove(&0), Some(0));
+        assert_eq!(m.stats().max_displacement, 7);
+        assert_eq!(m.check_invariants(), Ok(()));
+        for i in 1..8u64 {
+            assert_eq!(m.get(&(i << 32)), Some(&i));
+        }
+        assert_eq!(m.get(&(8 << 32)), None);
+        assert_eq!(m.get(&1), None);
+
+        m.clear();
+        assert_eq!(m.stats().max_displacement, 0);
+    }
+
+    /// Hashes a `u64` by multiplying it with a seed, which starts out
+    /// putting small keys in the same bucket.
+    pub struct MultiplyState(pub u64);
+
+    impl HashState for MultiplyState {
+        type Hasher = MultiplyHasher;
+        fn hasher(&self) -> MultiplyHasher { MultiplyHasher(self.0, 0) }
+    }
+
+    impl super::Reseed for MultiplyState {
+        fn reseed(&mut self) { self.0 = 0x9e3779b97f4a7c15; }
+    }
+
+    pub struct MultiplyHasher(u64, u64);
+
+    impl Hasher for MultiplyHasher {
+        fn finish(&self) -> u64 { self.1 }
+        fn write(&mut self, _: &[u8]) { panic!("only u64 keys are hashed") }
+        fn write_u64(&mut self, i: u64) { self.1 = i.wrapping_mul(self.0); }
+    }
+
+    /// Counts the keys hashed, in the shared counter.
+    pub struct CountingState(pub ::std::rc::Rc<::std::cell::Cell<usize>>);
+
+    impl HashState for CountingState {
+        type Hasher = <super::RandomState as HashState>::Hasher;
+        fn hasher(&self) -> Self::Hasher {
+            self.0.set(self.0.get() + 1);
+            super::RandomState::with_keys(0, 0).hasher()
+        }
+    }
+
+    #[test]
+    fn test_displacement_limit_reseed() {
+        let mut m = HashMap::with_hash_state(MultiplyState(1 << 32));
+        m.set_displacement_limit(Some(super::DisplacementLimit::reseed(4)));
+        for i in 0..100u64 {
+            assert_eq!(m.insert(i, i), None);
+        }
+        assert_eq!(m.hash_state().0, 0x9e3779b97f4a7c15);
+        assert_eq!(m.check_invariants(), Ok(()));
+
+        // Inserting again finds every key under the new seed.
+        for i in 0..100u64 {
+            assert_eq!(m.insert(i, i + 1), Some(i));
+        }
+        assert_eq!(m.len(), 100);
+        assert!(m.stats().max_displacement <= 4);
+
+        let mut set: HashMap<u64, (), _> = HashMap::with_hash_state(MultiplyState(1 << 32));
+        set.set_displacement_limit(Some(super::DisplacementLimit::reseed(4)));
+        for i in 0..100u64 {
+            assert_eq!(Recover::replace(&mut set, i), None);
+        }
+        assert_eq!(set.hash_state().0, 0x9e3779b97f4a7c15);
+        assert_eq!(set.check_invariants(), Ok(()));
+        assert_eq!(set.len(), 100);
+    }
+
+    #[test]
+    fn test_displacement_limit() {
+        // The keys share bucket 0 with 32 buckets, but only pairs of them
+        // share buckets 0 and 32 with 64.
+        let mut m = HashMap::with_hash_state(IdentityState);
+        for &k in &[0u64, 32, 64, 96] {
+            m.insert(k, k);
+        }
+        assert_eq!(m.stats().max_displacement, 3);
+
+        m.set_displacement_limit(Some(super::DisplacementLimit::grow(1)));
+        assert_eq!(m.stats().buckets, 64);
+        assert_eq!(m.stats().max_displacement, 1);
+        assert_eq!(m.check_invariants(), Ok(()));
+
+        // No growth separates keys whose hashes collide outright, so the
+        // limit backs off instead of growing the table on every insertion.
+        let mut m = HashMap::with_hash_state(IdentityState);
+        m.set_displacement_limit(Some(super::DisplacementLimit::grow(1)));
+        for i in 0..64u64 {
+            m.insert(i << 40, i);
+        }
+        assert!(m.stats().buckets <= 1024);
+        assert_eq!(m.check_invariants(), Ok(()));
+        for i in 0..64u64 {
+            assert_eq!(m[&(i << 40)], i);
+        }
+    }
 }
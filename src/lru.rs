@@ -0,0 +1,694 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A least-recently-used cache.
+
+use self::Entry::*;
+
+use std::borrow::Borrow;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::mem::replace;
+use std::collections::hash_state::HashState;
+
+use hashed::HashedTable;
+use table::{make_hash, SafeHash};
+use RandomState;
+
+/// Marks the end of the recency list.
+const NIL: usize = !0;
+
+/// A cache holding up to a fixed number of entries, which evicts the least
+/// recently used entry to make room for a new one.
+///
+/// Entries are kept in a single vector, with the links of the recency list
+/// stored right next to each key and value. A hash index maps keys to their
+/// position in the vector. Inserting an entry doesn't allocate once the
+/// vector and the index have grown to the cache's capacity.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::LruCache;
+///
+/// let mut cache = LruCache::new(2);
+/// cache.put("a", 1);
+/// cache.put("b", 2);
+///
+/// // Reading "a" makes "b" the least recently used entry.
+/// assert_eq!(cache.get("a"), Some(&1));
+/// let mut evicted = None;
+/// cache.put_with_evicted("c", 3, |k, v| evicted = Some((k, v)));
+/// assert_eq!(evicted, Some(("b", 2)));
+///
+/// assert!(!cache.contains_key("b"));
+/// assert_eq!(cache.len(), 2);
+/// ```
+pub struct LruCache<K, V, S = RandomState> {
+    hash_state: S,
+
+    // Maps the hash of each key to the position of its node.
+    index: HashedTable<usize, ()>,
+
+    nodes: Vec<Node<K, V>>,
+
+    // Positions of the most and least recently used nodes.
+    head: usize,
+    tail: usize,
+
+    capacity: usize,
+}
+
+struct Node<K, V> {
+    hash: SafeHash,
+    key: K,
+    value: V,
+
+    // The neighbouring nodes in the recency list. `prev` is more recent.
+    prev: usize,
+    next: usize,
+}
+
+impl<K: Hash + Eq, V> LruCache<K, V, RandomState> {
+    /// Creates an empty cache that holds up to `capacity` entries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::LruCache;
+    /// let mut cache: LruCache<&str, isize> = LruCache::new(10);
+    /// ```
+    #[inline]
+    pub fn new(capacity: usize) -> LruCache<K, V, RandomState> {
+        LruCache::with_hash_state(capacity, Default::default())
+    }
+}
+
+impl<K, V, S> LruCache<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Creates an empty cache that holds up to `capacity` entries, using
+    /// `hash_state` to hash the keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_hash_state(capacity: usize, hash_state: S) -> LruCache<K, V, S> {
+        assert!(capacity > 0, "LruCache capacity must be nonzero");
+        LruCache {
+            hash_state: hash_state,
+            index: HashedTable::new(),
+            nodes: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            capacity: capacity,
+        }
+    }
+
+    /// Returns the number of entries in the cache.
+    pub fn len(&self) -> usize { self.nodes.len() }
+
+    /// Returns true if the cache contains no entries.
+    pub fn is_empty(&self) -> bool { self.nodes.is_empty() }
+
+    /// Returns the maximum number of entries the cache holds.
+    pub fn capacity(&self) -> usize { self.capacity }
+
+    fn find<Q: ?Sized>(&self, k: &Q) -> Option<usize>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find_hashed(make_hash(&self.hash_state, k), k)
+    }
+
+    fn find_hashed<Q: ?Sized>(&self, hash: SafeHash, k: &Q) -> Option<usize>
+        where K: Borrow<Q>, Q: Eq
+    {
+        let nodes = &self.nodes;
+        self.index.find(hash, |&pos| k.eq(nodes[pos].key.borrow()))
+                  .map(|(&pos, _)| pos)
+    }
+
+    /// Returns a reference to the value corresponding to the key, and marks
+    /// the entry as the most recently used.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but
+    /// `Hash` and `Eq` on the borrowed form *must* match those for
+    /// the key type.
+    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get_mut(k).map(|v| &*v)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key,
+    /// and marks the entry as the most recently used.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        match self.find(k) {
+            Some(pos) => {
+                self.promote(pos);
+                Some(&mut self.nodes[pos].value)
+            }
+            None => None,
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key, without
+    /// marking the entry as used.
+    pub fn peek<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find(k).map(|pos| &self.nodes[pos].value)
+    }
+
+    /// Returns the least recently used entry, without marking it as used.
+    pub fn peek_lru(&self) -> Option<(&K, &V)> {
+        if self.tail == NIL {
+            return None;
+        }
+        let node = &self.nodes[self.tail];
+        Some((&node.key, &node.value))
+    }
+
+    /// Returns true if the cache contains the key. Doesn't mark the entry
+    /// as used.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find(k).is_some()
+    }
+
+    /// Inserts a key-value pair as the most recently used entry. If the
+    /// cache was full, the least recently used entry is evicted and
+    /// dropped.
+    ///
+    /// If the key was already present, its value is replaced and returned.
+    /// The key isn't updated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::LruCache;
+    ///
+    /// let mut cache = LruCache::new(1);
+    /// assert_eq!(cache.put(1, "a"), None);
+    /// assert_eq!(cache.put(1, "b"), Some("a"));
+    /// assert_eq!(cache.put(2, "c"), None);
+    /// assert!(!cache.contains_key(&1));
+    /// ```
+    pub fn put(&mut self, k: K, v: V) -> Option<V> {
+        self.put_with_evicted(k, v, |_, _| {})
+    }
+
+    /// Inserts a key-value pair as the most recently used entry. If the
+    /// cache was full, the least recently used entry is evicted and passed
+    /// to `on_evict`.
+    ///
+    /// If the key was already present, its value is replaced and returned,
+    /// and nothing is evicted.
+    pub fn put_with_evicted<F>(&mut self, k: K, v: V, on_evict: F) -> Option<V>
+        where F: FnOnce(K, V)
+    {
+        let hash = make_hash(&self.hash_state, &k);
+        if let Some(pos) = self.find_hashed(hash, &k) {
+            self.promote(pos);
+            return Some(replace(&mut self.nodes[pos].value, v));
+        }
+
+        self.push_front(hash, k, v, on_evict);
+        None
+    }
+
+    /// Removes and returns the least recently used entry.
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        if self.tail == NIL {
+            return None;
+        }
+        let tail = self.tail;
+        Some(self.remove_at(tail))
+    }
+
+    /// Removes a key from the cache, returning its value if the key was
+    /// present.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.find(k).map(|pos| self.remove_at(pos).1)
+    }
+
+    /// Changes the maximum number of entries, evicting the least recently
+    /// used entries that no longer fit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn resize(&mut self, capacity: usize) {
+        assert!(capacity > 0, "LruCache capacity must be nonzero");
+        while self.len() > capacity {
+            self.pop_lru();
+        }
+        self.capacity = capacity;
+    }
+
+    /// Removes all entries, keeping the allocated memory for reuse.
+    pub fn clear(&mut self) {
+        self.index.clear();
+        self.nodes.clear();
+        self.head = NIL;
+        self.tail = NIL;
+    }
+
+    /// An iterator visiting all entries from the most to the least recently
+    /// used. Doesn't mark any entry as used.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter {
+            nodes: &self.nodes,
+            pos: self.head,
+            remaining: self.len(),
+        }
+    }
+
+    /// Gets the given key's corresponding entry in the cache for in-place
+    /// manipulation. An existing entry is marked as the most recently used.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::LruCache;
+    ///
+    /// let mut cache = LruCache::new(2);
+    /// *cache.entry("a").or_insert(0) += 1;
+    /// *cache.entry("a").or_insert(0) += 1;
+    /// assert_eq!(cache.peek("a"), Some(&2));
+    /// ```
+    pub fn entry(&mut self, key: K) -> Entry<K, V, S> {
+        let hash = make_hash(&self.hash_state, &key);
+        match self.find_hashed(hash, &key) {
+            Some(pos) => {
+                self.promote(pos);
+                Occupied(OccupiedEntry { cache: self, pos: pos })
+            }
+            None => Vacant(VacantEntry { cache: self, hash: hash, key: key }),
+        }
+    }
+
+    /// Adds a node at the front of the recency list, first evicting the
+    /// least recently used one if the cache is full. Returns its position.
+    fn push_front<F>(&mut self, hash: SafeHash, k: K, v: V, on_evict: F) -> usize
+        where F: FnOnce(K, V)
+    {
+        if self.len() == self.capacity {
+            if let Some((k, v)) = self.pop_lru() {
+                on_evict(k, v);
+            }
+        }
+
+        let pos = self.nodes.len();
+        self.nodes.push(Node {
+            hash: hash,
+            key: k,
+            value: v,
+            prev: NIL,
+            next: NIL,
+        });
+        self.index.insert(hash, pos, ());
+        self.link_front(pos);
+        pos
+    }
+
+    fn promote(&mut self, pos: usize) {
+        if self.head != pos {
+            self.unlink(pos);
+            self.link_front(pos);
+        }
+    }
+
+    fn link_front(&mut self, pos: usize) {
+        let head = self.head;
+        self.nodes[pos].prev = NIL;
+        self.nodes[pos].next = head;
+        if head == NIL {
+            self.tail = pos;
+        } else {
+            self.nodes[head].prev = pos;
+        }
+        self.head = pos;
+    }
+
+    fn unlink(&mut self, pos: usize) {
+        let (prev, next) = (self.nodes[pos].prev, self.nodes[pos].next);
+        if prev == NIL {
+            self.head = next;
+        } else {
+            self.nodes[prev].next = next;
+        }
+        if next == NIL {
+            self.tail = prev;
+        } else {
+            self.nodes[next].prev = prev;
+        }
+    }
+
+    /// Removes the node at `pos`. The last node takes its place.
+    fn remove_at(&mut self, pos: usize) -> (K, V) {
+        self.unlink(pos);
+        let hash = self.nodes[pos].hash;
+        self.index.remove(hash, |&p| p == pos);
+
+        let last = self.nodes.len() - 1;
+        if pos != last {
+            // Point the index and the neighbours of the last node to its new
+            // position.
+            let (hash, prev, next) = {
+                let node = &self.nodes[last];
+                (node.hash, node.prev, node.next)
+            };
+            if let Some((p, _)) = self.index.find_mut(hash, |&p| p == last) {
+                *p = pos;
+            }
+            if prev == NIL {
+                self.head = pos;
+            } else {
+                self.nodes[prev].next = pos;
+            }
+            if next == NIL {
+                self.tail = pos;
+            } else {
+                self.nodes[next].prev = pos;
+            }
+        }
+
+        let node = self.nodes.swap_remove(pos);
+        (node.key, node.value)
+    }
+}
+
+impl<K, V, S> Debug for LruCache<K, V, S>
+    where K: Eq + Hash + Debug, V: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a LruCache<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// LruCache iterator, from the most to the least recently used entry.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    nodes: &'a [Node<K, V>],
+    pos: usize,
+    remaining: usize,
+}
+
+// FIXME(#19839) Remove in favor of `#[derive(Clone)]`
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            nodes: self.nodes,
+            pos: self.pos,
+            remaining: self.remaining,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.pos == NIL {
+            return None;
+        }
+        let node = &self.nodes[self.pos];
+        self.pos = node.next;
+        self.remaining -= 1;
+        Some((&node.key, &node.value))
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
+    #[inline] fn len(&self) -> usize { self.remaining }
+}
+
+/// A view into a single location in a cache, which may be vacant or occupied.
+pub enum Entry<'a, K: 'a, V: 'a, S: 'a> {
+    /// An occupied Entry.
+    Occupied(OccupiedEntry<'a, K, V, S>),
+
+    /// A vacant Entry.
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+/// A view into a single occupied location in an LruCache.
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, S: 'a> {
+    cache: &'a mut LruCache<K, V, S>,
+    pos: usize,
+}
+
+/// A view into a single empty location in an LruCache.
+pub struct VacantEntry<'a, K: 'a, V: 'a, S: 'a> {
+    cache: &'a mut LruCache<K, V, S>,
+    hash: SafeHash,
+    key: K,
+}
+
+impl<'a, K, V, S> Entry<'a, K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Returns the entry key
+    pub fn key(&self) -> &K {
+        match *self {
+            Occupied(ref entry) => entry.key(),
+            Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the default if empty, and returns
+    /// a mutable reference to the value in the entry.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// and returns a mutable reference to the value in the entry.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.insert(default()),
+        }
+    }
+}
+
+impl<'a, K, V, S> OccupiedEntry<'a, K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Gets a reference to the entry key
+    pub fn key(&self) -> &K {
+        &self.cache.nodes[self.pos].key
+    }
+
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &V {
+        &self.cache.nodes[self.pos].value
+    }
+
+    /// Gets a mutable reference to the value in the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.cache.nodes[self.pos].value
+    }
+
+    /// Converts the OccupiedEntry into a mutable reference to the value in the entry
+    /// with a lifetime bound to the cache itself
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.cache.nodes[self.pos].value
+    }
+
+    /// Sets the value of the entry, and returns the entry's old value
+    pub fn insert(&mut self, value: V) -> V {
+        replace(self.get_mut(), value)
+    }
+
+    /// Takes the value out of the entry, and returns it
+    pub fn remove(self) -> V {
+        self.cache.remove_at(self.pos).1
+    }
+}
+
+impl<'a, K, V, S> VacantEntry<'a, K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Gets a reference to the entry key
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Sets the value of the entry with the VacantEntry's key, and returns a
+    /// mutable reference to it. Evicts and drops the least recently used
+    /// entry if the cache is full.
+    pub fn insert(self, value: V) -> &'a mut V {
+        self.insert_with_evicted(value, |_, _| {})
+    }
+
+    /// Sets the value of the entry with the VacantEntry's key, and returns a
+    /// mutable reference to it. If the cache is full, the least recently
+    /// used entry is evicted and passed to `on_evict`.
+    pub fn insert_with_evicted<F>(self, value: V, on_evict: F) -> &'a mut V
+        where F: FnOnce(K, V)
+    {
+        let cache = self.cache;
+        let pos = cache.push_front(self.hash, self.key, value, on_evict);
+        &mut cache.nodes[pos].value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LruCache;
+    use super::Entry::{Occupied, Vacant};
+
+    fn keys(cache: &LruCache<i32, i32>) -> Vec<i32> {
+        cache.iter().map(|(&k, _)| k).collect()
+    }
+
+    #[test]
+    fn test_put_get() {
+        let mut cache = LruCache::new(3);
+        assert_eq!(cache.put(1, 10), None);
+        assert_eq!(cache.put(2, 20), None);
+        assert_eq!(cache.put(3, 30), None);
+        assert_eq!(keys(&cache), [3, 2, 1]);
+
+        assert_eq!(cache.get(&1), Some(&10));
+        assert_eq!(keys(&cache), [1, 3, 2]);
+
+        assert_eq!(cache.peek(&2), Some(&20));
+        assert_eq!(cache.peek_lru(), Some((&2, &20)));
+        let mut evicted = None;
+        assert_eq!(cache.put_with_evicted(4, 40, |k, v| evicted = Some((k, v))), None);
+        assert_eq!(evicted, Some((2, 20)));
+        assert_eq!(keys(&cache), [4, 1, 3]);
+        assert_eq!(cache.get(&2), None);
+
+        // Replacing a value evicts nothing.
+        let mut evicted = None;
+        assert_eq!(cache.put_with_evicted(3, 31, |k, v| evicted = Some((k, v))), Some(30));
+        assert_eq!(evicted, None);
+        assert_eq!(keys(&cache), [3, 4, 1]);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_pop_remove_resize() {
+        let mut cache = LruCache::new(5);
+        for i in 0..5 {
+            cache.put(i, i);
+        }
+        assert_eq!(cache.pop_lru(), Some((0, 0)));
+        assert_eq!(cache.remove(&3), Some(3));
+        assert_eq!(cache.remove(&3), None);
+        assert_eq!(keys(&cache), [4, 2, 1]);
+
+        cache.resize(2);
+        assert_eq!(cache.capacity(), 2);
+        assert_eq!(keys(&cache), [4, 2]);
+        assert_eq!(cache.put(5, 5), None);
+        assert_eq!(keys(&cache), [5, 4]);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.pop_lru(), None);
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut cache = LruCache::new(2);
+        cache.put(1, 1);
+        cache.put(2, 2);
+
+        match cache.entry(1) {
+            Occupied(mut e) => assert_eq!(e.insert(10), 1),
+            Vacant(_) => unreachable!(),
+        }
+        assert_eq!(keys(&cache), [1, 2]);
+
+        match cache.entry(3) {
+            Occupied(_) => unreachable!(),
+            Vacant(e) => {
+                let mut evicted = None;
+                assert_eq!(*e.insert_with_evicted(30, |k, v| evicted = Some((k, v))), 30);
+                assert_eq!(evicted, Some((2, 2)));
+            }
+        }
+        assert_eq!(keys(&cache), [3, 1]);
+
+        match cache.entry(1) {
+            Occupied(e) => assert_eq!(e.remove(), 10),
+            Vacant(_) => unreachable!(),
+        }
+        assert_eq!(keys(&cache), [3]);
+    }
+
+    #[test]
+    fn test_put_entry_hash_once() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use test_map::CountingState;
+
+        let hashes = Rc::new(Cell::new(0));
+        let mut cache = LruCache::with_hash_state(2, CountingState(hashes.clone()));
+        cache.put(1, 1);
+        cache.put(1, 2);
+        match cache.entry(2) {
+            Occupied(_) => unreachable!(),
+            Vacant(e) => { e.insert(2); }
+        }
+        match cache.entry(1) {
+            Occupied(mut e) => { e.insert(3); }
+            Vacant(_) => unreachable!(),
+        }
+        assert_eq!(hashes.get(), 4);
+    }
+
+    #[test]
+    fn test_many_removals() {
+        let mut cache = LruCache::new(100);
+        for i in 0..100 {
+            cache.put(i, i * 2);
+        }
+        for i in 0..50 {
+            assert_eq!(cache.remove(&(i * 2)), Some(i * 4));
+        }
+        for i in 0..100 {
+            assert_eq!(cache.peek(&i).cloned(), if i % 2 == 0 { None } else { Some(i * 2) });
+        }
+        let order = keys(&cache);
+        assert_eq!(order, (0..50).rev().map(|i| i * 2 + 1).collect::<Vec<_>>());
+    }
+}
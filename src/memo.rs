@@ -0,0 +1,483 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Memoizing caches, which compute each value once.
+
+use self::Slot::*;
+
+use std::borrow::{Borrow, Cow, ToOwned};
+use std::cell::RefCell;
+use std::default::Default;
+use std::hash::Hash;
+use std::sync::{Condvar, Mutex};
+use std::collections::hash_state::HashState;
+
+use {HashMap, RandomState};
+use Entry::{Occupied, Vacant};
+
+/// The state of a key in a memo.
+enum Slot<V, P> {
+    /// The value is being computed by the owner `P`.
+    Pending(P),
+    Ready(V),
+}
+
+/// A memo, as seen by its loader.
+pub trait MemoLookup<Q: ?Sized, V> {
+    /// Returns the value of the key, computing it if it's missing.
+    fn get_or_compute(&self, q: &Q) -> V;
+}
+
+/// Runs a closure when dropped, unless disarmed. Undoes a pending
+/// computation if the loader panics.
+struct Cleanup<F: FnMut()> {
+    f: F,
+    armed: bool,
+}
+
+impl<F: FnMut()> Drop for Cleanup<F> {
+    fn drop(&mut self) {
+        if self.armed {
+            (self.f)();
+        }
+    }
+}
+
+/// A cache that computes the value of each key with a loader, once.
+///
+/// The loader gets the memo as a `MemoLookup`, so it can look up other
+/// keys; looking up the key being computed panics instead of recursing
+/// forever. Keys are looked up by reference with `entry2`, and only turned
+/// into an owned `Q::Owned` when they're missing.
+///
+/// If the loader panics, the key is left uncomputed.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::Memo;
+/// use hashmap2::memo::MemoLookup;
+///
+/// let fib = Memo::new(|&n: &u64, fib: &MemoLookup<u64, u64>| {
+///     if n < 2 { n } else { fib.get_or_compute(&(n - 1)) + fib.get_or_compute(&(n - 2)) }
+/// });
+/// assert_eq!(fib.get_or_compute(&80), 23416728348467685);
+/// assert_eq!(fib.len(), 81);
+/// ```
+pub struct Memo<Q: ?Sized + ToOwned, V, F, S = RandomState> {
+    map: RefCell<HashMap<Q::Owned, Slot<V, ()>, S>>,
+    loader: F,
+}
+
+impl<Q: ?Sized, V, F> Memo<Q, V, F, RandomState>
+    where Q: ToOwned + Hash + Eq, Q::Owned: Clone + Hash + Eq, V: Clone,
+          F: Fn(&Q, &MemoLookup<Q, V>) -> V
+{
+    /// Creates an empty memo with a loader.
+    pub fn new(loader: F) -> Memo<Q, V, F, RandomState> {
+        Memo::with_hash_state(loader, Default::default())
+    }
+}
+
+impl<Q: ?Sized, V, F, S> Memo<Q, V, F, S>
+    where Q: ToOwned + Hash + Eq, Q::Owned: Clone + Hash + Eq, V: Clone,
+          F: Fn(&Q, &MemoLookup<Q, V>) -> V, S: HashState
+{
+    /// Creates an empty memo with a loader, which will use the given hash
+    /// state to hash keys.
+    pub fn with_hash_state(loader: F, hash_state: S) -> Memo<Q, V, F, S> {
+        Memo {
+            map: RefCell::new(HashMap::with_hash_state(hash_state)),
+            loader: loader,
+        }
+    }
+
+    /// Returns the number of keys computed or being computed.
+    pub fn len(&self) -> usize {
+        self.map.borrow().len()
+    }
+
+    /// Returns true if no key was computed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value of the key, computing it if it's missing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the loader looks up the key it's computing.
+    pub fn get_or_compute(&self, q: &Q) -> V {
+        match self.map.borrow_mut().entry2(Cow::Borrowed(q)) {
+            Occupied(entry) => match *entry.get() {
+                Ready(ref v) => return v.clone(),
+                Pending(()) => panic!("Memo: the loader recursed into the key it's computing"),
+            },
+            Vacant(entry) => {
+                entry.insert(Pending(()));
+            }
+        }
+
+        let mut cleanup = Cleanup {
+            f: || { self.map.borrow_mut().remove(q); },
+            armed: true,
+        };
+        let v = (self.loader)(q, self);
+        cleanup.armed = false;
+
+        *self.map.borrow_mut().get_mut(q).unwrap() = Ready(v.clone());
+        v
+    }
+
+    /// Returns the value of the key, if it was computed.
+    pub fn get(&self, q: &Q) -> Option<V> {
+        match self.map.borrow().get(q) {
+            Some(&Ready(ref v)) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    /// Forgets the value of a computed key, so the next lookup computes it
+    /// again. Returns the forgotten value.
+    pub fn invalidate(&self, q: &Q) -> Option<V> {
+        let mut map = self.map.borrow_mut();
+        match map.get(q) {
+            Some(&Ready(_)) => {}
+            _ => return None,
+        }
+        match map.remove(q) {
+            Some(Ready(v)) => Some(v),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<Q: ?Sized, V, F, S> MemoLookup<Q, V> for Memo<Q, V, F, S>
+    where Q: ToOwned + Hash + Eq, Q::Owned: Clone + Hash + Eq, V: Clone,
+          F: Fn(&Q, &MemoLookup<Q, V>) -> V, S: HashState
+{
+    fn get_or_compute(&self, q: &Q) -> V {
+        Memo::get_or_compute(self, q)
+    }
+}
+
+/// Identifies the current thread, among the live threads.
+fn current_thread_token() -> usize {
+    thread_local!(static TOKEN: u8 = 0);
+    TOKEN.with(|token| token as *const u8 as usize)
+}
+
+/// A thread-safe cache that computes the value of each key with a loader,
+/// once.
+///
+/// A thread that looks up a key being computed by another thread waits for
+/// that computation, instead of running the loader a second time. Like in
+/// `Memo`, the loader gets the memo as a `MemoLookup`, and looking up the
+/// key being computed on the same thread panics.
+///
+/// If the loader panics, the key is left uncomputed, and one of the waiting
+/// threads computes it instead.
+///
+/// # Deadlocks
+///
+/// Keys that depend on each other across threads, such as thread A
+/// computing `k1` and waiting for `k2` while thread B computes `k2` and
+/// waits for `k1`, would wait forever. The memo records which key each
+/// thread waits for, and the thread that would close such a cycle panics
+/// instead of waiting.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::thread;
+/// use hashmap2::SyncMemo;
+/// use hashmap2::memo::MemoLookup;
+///
+/// let runs = Arc::new(AtomicUsize::new(0));
+/// let counter = runs.clone();
+/// let lengths = Arc::new(SyncMemo::new(move |s: &str, _: &MemoLookup<str, usize>| {
+///     counter.fetch_add(1, Ordering::SeqCst);
+///     s.len()
+/// }));
+///
+/// let threads: Vec<_> = (0..4).map(|_| {
+///     let lengths = lengths.clone();
+///     thread::spawn(move || lengths.get_or_compute("hello"))
+/// }).collect();
+/// for t in threads {
+///     assert_eq!(t.join().unwrap(), 5);
+/// }
+/// assert_eq!(runs.load(Ordering::SeqCst), 1);
+/// ```
+pub struct SyncMemo<Q: ?Sized + ToOwned, V, F, S = RandomState> {
+    map: Mutex<Slots<Q::Owned, V, S>>,
+    ready: Condvar,
+    loader: F,
+}
+
+/// The keys of a `SyncMemo`, and the key each blocked thread waits for.
+struct Slots<K, V, S> {
+    slots: HashMap<K, Slot<V, usize>, S>,
+    waiting: HashMap<usize, K>,
+}
+
+impl<K, V, S> Slots<K, V, S> where K: Hash + Eq, S: HashState {
+    /// Returns true if `owner`, by way of the threads it waits for, waits
+    /// for thread `me`.
+    fn waits_for(&self, mut owner: usize, me: usize) -> bool {
+        // A chain without cycles visits each waiting thread once.
+        for _ in 0..self.waiting.len() {
+            owner = match self.waiting.get(&owner).and_then(|k| self.slots.get(k)) {
+                Some(&Pending(next)) => next,
+                _ => return false,
+            };
+            if owner == me {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl<Q: ?Sized, V, F> SyncMemo<Q, V, F, RandomState>
+    where Q: ToOwned + Hash + Eq, Q::Owned: Clone + Hash + Eq, V: Clone,
+          F: Fn(&Q, &MemoLookup<Q, V>) -> V
+{
+    /// Creates an empty memo with a loader.
+    pub fn new(loader: F) -> SyncMemo<Q, V, F, RandomState> {
+        SyncMemo::with_hash_state(loader, Default::default())
+    }
+}
+
+impl<Q: ?Sized, V, F, S> SyncMemo<Q, V, F, S>
+    where Q: ToOwned + Hash + Eq, Q::Owned: Clone + Hash + Eq, V: Clone,
+          F: Fn(&Q, &MemoLookup<Q, V>) -> V, S: HashState
+{
+    /// Creates an empty memo with a loader, which will use the given hash
+    /// state to hash keys.
+    pub fn with_hash_state(loader: F, hash_state: S) -> SyncMemo<Q, V, F, S> {
+        SyncMemo {
+            map: Mutex::new(Slots {
+                slots: HashMap::with_hash_state(hash_state),
+                waiting: HashMap::new(),
+            }),
+            ready: Condvar::new(),
+            loader: loader,
+        }
+    }
+
+    /// Returns the number of keys computed or being computed.
+    pub fn len(&self) -> usize {
+        self.map.lock().unwrap().slots.len()
+    }
+
+    /// Returns true if no key was computed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value of the key, computing it if it's missing. Waits if
+    /// another thread is computing it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the loader looks up the key it's computing, or if waiting
+    /// for the key would deadlock, as the thread computing it waits for a
+    /// key this thread is computing.
+    pub fn get_or_compute(&self, q: &Q) -> V {
+        let me = current_thread_token();
+        let mut map = self.map.lock().unwrap();
+        loop {
+            let owner = match map.slots.entry2(Cow::Borrowed(q)) {
+                Occupied(entry) => match *entry.get() {
+                    Ready(ref v) => return v.clone(),
+                    Pending(owner) => owner,
+                },
+                Vacant(entry) => {
+                    entry.insert(Pending(me));
+                    break;
+                }
+            };
+            if owner == me {
+                // Don't poison the lock.
+                drop(map);
+                panic!("SyncMemo: the loader recursed into the key it's computing");
+            }
+            if map.waits_for(owner, me) {
+                drop(map);
+                panic!("SyncMemo: the loaders of two threads wait for each other's keys");
+            }
+            map.waiting.insert(me, q.to_owned());
+            map = self.ready.wait(map).unwrap();
+            map.waiting.remove(&me);
+        }
+        drop(map);
+
+        let mut cleanup = Cleanup {
+            f: || {
+                self.map.lock().unwrap().slots.remove(q);
+                self.ready.notify_all();
+            },
+            armed: true,
+        };
+        let v = (self.loader)(q, self);
+        cleanup.armed = false;
+
+        *self.map.lock().unwrap().slots.get_mut(q).unwrap() = Ready(v.clone());
+        self.ready.notify_all();
+        v
+    }
+
+    /// Returns the value of the key, if it was computed. Doesn't wait.
+    pub fn get(&self, q: &Q) -> Option<V> {
+        match self.map.lock().unwrap().slots.get(q) {
+            Some(&Ready(ref v)) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    /// Forgets the value of a computed key, so the next lookup computes it
+    /// again. Returns the forgotten value.
+    pub fn invalidate(&self, q: &Q) -> Option<V> {
+        let map = &mut self.map.lock().unwrap().slots;
+        match map.get(q) {
+            Some(&Ready(_)) => {}
+            _ => return None,
+        }
+        match map.remove(q) {
+            Some(Ready(v)) => Some(v),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<Q: ?Sized, V, F, S> MemoLookup<Q, V> for SyncMemo<Q, V, F, S>
+    where Q: ToOwned + Hash + Eq, Q::Owned: Clone + Hash + Eq, V: Clone,
+          F: Fn(&Q, &MemoLookup<Q, V>) -> V, S: HashState
+{
+    fn get_or_compute(&self, q: &Q) -> V {
+        SyncMemo::get_or_compute(self, q)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::panic;
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{Memo, MemoLookup, SyncMemo};
+
+    #[test]
+    fn test_memo_runs_loader_once() {
+        let runs = Cell::new(0);
+        let memo = Memo::new(|s: &str, _: &MemoLookup<str, String>| {
+            runs.set(runs.get() + 1);
+            s.to_uppercase()
+        });
+        assert_eq!(memo.get("a"), None);
+        assert_eq!(memo.get_or_compute("a"), "A");
+        assert_eq!(memo.get_or_compute("a"), "A");
+        assert_eq!(memo.get_or_compute("b"), "B");
+        assert_eq!(runs.get(), 2);
+
+        assert_eq!(memo.invalidate("a"), Some("A".to_string()));
+        assert_eq!(memo.invalidate("a"), None);
+        assert_eq!(memo.get_or_compute("a"), "A");
+        assert_eq!(runs.get(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "recursed")]
+    fn test_memo_recursion_panics() {
+        let memo = Memo::new(|&n: &u32, memo: &MemoLookup<u32, u32>| memo.get_or_compute(&n));
+        memo.get_or_compute(&1);
+    }
+
+    #[test]
+    fn test_memo_loader_panic_leaves_key_missing() {
+        let fail = Cell::new(true);
+        let memo = Memo::new(|&n: &u32, _: &MemoLookup<u32, u32>| {
+            if fail.get() {
+                panic!("loader failed");
+            }
+            n
+        });
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| memo.get_or_compute(&1)));
+        assert!(result.is_err());
+        assert!(memo.is_empty());
+
+        fail.set(false);
+        assert_eq!(memo.get_or_compute(&1), 1);
+    }
+
+    #[test]
+    fn test_sync_memo_waits_for_computation() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let memo = Arc::new(SyncMemo::new(move |&n: &u64, memo: &MemoLookup<u64, u64>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            if n == 0 { 0 } else { n + memo.get_or_compute(&(n - 1)) }
+        }));
+
+        let barrier = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8).map(|i| {
+            let memo = memo.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                memo.get_or_compute(&(10 + i % 2))
+            })
+        }).collect();
+        for (i, t) in threads.into_iter().enumerate() {
+            let n = 10 + i as u64 % 2;
+            assert_eq!(t.join().unwrap(), n * (n + 1) / 2);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 12);
+        assert_eq!(memo.len(), 12);
+    }
+
+    #[test]
+    fn test_sync_memo_cross_thread_cycle_panics() {
+        // Keys 0 and 1 need each other. Once both threads hold one, each
+        // asks for the other's.
+        let entered = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(2));
+        let memo = {
+            let (entered, barrier) = (entered.clone(), barrier.clone());
+            Arc::new(SyncMemo::new(move |&n: &u32, memo: &MemoLookup<u32, u32>| {
+                if entered.fetch_add(1, Ordering::SeqCst) < 2 {
+                    barrier.wait();
+                }
+                memo.get_or_compute(&(1 - n)) + 1
+            }))
+        };
+
+        let threads: Vec<_> = (0..2).map(|n| {
+            let memo = memo.clone();
+            thread::spawn(move || memo.get_or_compute(&n))
+        }).collect();
+        let messages: Vec<&str> = threads.into_iter().map(|t| {
+            *t.join().unwrap_err().downcast_ref::<&str>().unwrap()
+        }).collect();
+
+        // One thread finds the cycle. The other then computes both keys
+        // itself, and finds it's recursing.
+        assert!(messages.iter().any(|m| m.contains("wait for each other")));
+        assert!(messages.iter().any(|m| m.contains("recursed")));
+        assert!(memo.is_empty());
+    }
+}
@@ -0,0 +1,550 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A collection indexed by several keys.
+
+use std::any::Any;
+use std::borrow::Borrow;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::collections::hash_state::HashState;
+
+use hashed::HashedTable;
+use table::{make_hash, SafeHash};
+use RandomState;
+
+/// A stable reference to an entry of a `MultiIndexMap`. A handle to a
+/// removed entry is never valid again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Handle {
+    index: usize,
+    generation: usize,
+}
+
+/// A typed reference to one of the indexes of a `MultiIndexMap`, returned
+/// when the index is declared. `K` is the type of its keys. It can only be
+/// used with the map that declared it.
+pub struct IndexHandle<T, K> {
+    map: usize,
+    id: usize,
+    marker: PhantomData<fn(&T) -> K>,
+}
+
+// FIXME(#19839) Remove in favor of `#[derive(Clone, Copy)]`
+impl<T, K> Clone for IndexHandle<T, K> {
+    fn clone(&self) -> IndexHandle<T, K> { *self }
+}
+
+impl<T, K> Copy for IndexHandle<T, K> {}
+
+/// The source of the ids that tie index handles to their map.
+static NEXT_MAP_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// An entry store slot. A slot is reused after its entry is removed, with
+/// a new generation.
+struct Slot<T> {
+    generation: usize,
+    value: Option<T>,
+}
+
+/// A hash index over the entries, with its key type erased.
+struct Index<T, S> {
+    // A `Box<Fn(&T) -> K + Send>`, for the index's key type `K`.
+    extract: Box<Any + Send>,
+    hash: fn(&Index<T, S>, &S, &T) -> SafeHash,
+    eq: fn(&Index<T, S>, &T, &T) -> bool,
+    unique: bool,
+
+    // Maps the hash of each entry's key to the entry's slot.
+    table: HashedTable<usize, ()>,
+}
+
+impl<T: 'static, S> Index<T, S> {
+    /// Returns the key extractor. `K` must be the index's key type.
+    fn extract<K: 'static>(&self) -> &Fn(&T) -> K {
+        &**self.extract.downcast_ref::<Box<Fn(&T) -> K + Send>>().unwrap()
+    }
+}
+
+fn hash_key<T: 'static, K, S>(index: &Index<T, S>, hash_state: &S, value: &T) -> SafeHash
+    where K: Hash + 'static, S: HashState
+{
+    make_hash(hash_state, &index.extract::<K>()(value))
+}
+
+fn eq_keys<T: 'static, K, S>(index: &Index<T, S>, a: &T, b: &T) -> bool
+    where K: Eq + 'static
+{
+    let extract = index.extract::<K>();
+    extract(a) == extract(b)
+}
+
+/// A set of entries that can be looked up by several keys.
+///
+/// Each index extracts a key from every entry, and maps it to the entry in
+/// a hash table of its own. Entries are stored once and shared by all
+/// indexes. A unique index allows at most one entry per key; a non-unique
+/// index may map a key to any number of entries.
+///
+/// Indexes must be declared while the map is empty. Key extractors return
+/// their key by value, so extracting a `String` clones it.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::MultiIndexMap;
+///
+/// #[derive(Clone)]
+/// struct User { id: u32, name: String, team: &'static str }
+///
+/// let mut users = MultiIndexMap::new();
+/// let by_id = users.add_unique_index(|u: &User| u.id);
+/// let by_name = users.add_unique_index(|u: &User| u.name.clone());
+/// let by_team = users.add_index(|u: &User| u.team);
+///
+/// let alice = users.insert(User { id: 1, name: "alice".to_string(), team: "red" }).ok().unwrap();
+/// users.insert(User { id: 2, name: "bob".to_string(), team: "red" }).ok().unwrap();
+///
+/// // A second user with id 1 is rejected.
+/// assert!(users.insert(User { id: 1, name: "carol".to_string(), team: "blue" }).is_err());
+///
+/// assert_eq!(users.get_unique(&by_name, "bob").unwrap().1.id, 2);
+/// assert_eq!(users.get_all(&by_team, &"red").len(), 2);
+///
+/// // Changing a key re-indexes the entry.
+/// users.modify(alice, |u| u.team = "blue").ok().unwrap();
+/// assert_eq!(users.get_all(&by_team, &"red").len(), 1);
+/// assert_eq!(users.get_unique(&by_id, &1).unwrap().1.team, "blue");
+/// ```
+pub struct MultiIndexMap<T, S = RandomState> {
+    id: usize,
+    hash_state: S,
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+    len: usize,
+    indexes: Vec<Index<T, S>>,
+}
+
+impl<T> MultiIndexMap<T, RandomState> {
+    /// Creates an empty `MultiIndexMap` without indexes.
+    #[inline]
+    pub fn new() -> MultiIndexMap<T, RandomState> {
+        MultiIndexMap::with_hash_state(Default::default())
+    }
+}
+
+impl<T, S: HashState> MultiIndexMap<T, S> {
+    /// Creates an empty `MultiIndexMap` without indexes, which will use the
+    /// given hash state to hash keys.
+    pub fn with_hash_state(hash_state: S) -> MultiIndexMap<T, S> {
+        MultiIndexMap {
+            id: NEXT_MAP_ID.fetch_add(1, Ordering::Relaxed),
+            hash_state: hash_state,
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            indexes: Vec::new(),
+        }
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize { self.len }
+
+    /// Returns true if the map contains no entries.
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Declares a unique index, which allows at most one entry per key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map isn't empty.
+    pub fn add_unique_index<K, F>(&mut self, extract: F) -> IndexHandle<T, K>
+        where K: Hash + Eq + 'static, F: Fn(&T) -> K + Send + 'static, T: 'static
+    {
+        self.add_index_inner(Box::new(extract), true)
+    }
+
+    /// Declares a non-unique index, which allows any number of entries per
+    /// key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map isn't empty.
+    pub fn add_index<K, F>(&mut self, extract: F) -> IndexHandle<T, K>
+        where K: Hash + Eq + 'static, F: Fn(&T) -> K + Send + 'static, T: 'static
+    {
+        self.add_index_inner(Box::new(extract), false)
+    }
+
+    fn add_index_inner<K>(&mut self, extract: Box<Fn(&T) -> K + Send>, unique: bool)
+                          -> IndexHandle<T, K>
+        where K: Hash + Eq + 'static, T: 'static
+    {
+        assert!(self.is_empty(), "indexes must be declared before inserting");
+
+        self.indexes.push(Index {
+            extract: Box::new(extract),
+            hash: hash_key::<T, K, S>,
+            eq: eq_keys::<T, K, S>,
+            unique: unique,
+            table: HashedTable::new(),
+        });
+        IndexHandle {
+            map: self.id,
+            id: self.indexes.len() - 1,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the entry a handle refers to, if it's still present.
+    pub fn get(&self, handle: Handle) -> Option<&T> {
+        self.position(handle).map(|pos| self.value(pos))
+    }
+
+    /// Returns true if the handle refers to a present entry.
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.position(handle).is_some()
+    }
+
+    /// Finds the entry with the key in a unique index.
+    ///
+    /// The key may be any borrowed form of the index's key type, but
+    /// `Hash` and `Eq` on the borrowed form *must* match those for
+    /// the key type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index was declared on another map.
+    pub fn get_unique<K, Q: ?Sized>(&self, index: &IndexHandle<T, K>, key: &Q)
+                                    -> Option<(Handle, &T)>
+        where K: Borrow<Q> + 'static, Q: Hash + Eq, T: 'static
+    {
+        let hash = make_hash(&self.hash_state, key);
+        let slots = &self.slots;
+        let index = self.index(index);
+        let extract = index.extract::<K>();
+        index.table
+            .find(hash, |&pos| key.eq(extract(slot_value(slots, pos)).borrow()))
+            .map(|(&pos, _)| (self.handle(pos), self.value(pos)))
+    }
+
+    /// Finds all entries with the key in an index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index was declared on another map.
+    pub fn get_all<K, Q: ?Sized>(&self, index: &IndexHandle<T, K>, key: &Q)
+                                 -> Vec<(Handle, &T)>
+        where K: Borrow<Q> + 'static, Q: Hash + Eq, T: 'static
+    {
+        let hash = make_hash(&self.hash_state, key);
+        let index = self.index(index);
+        let extract = index.extract::<K>();
+        let mut found = vec![];
+        index.table.for_each_hashed(hash, |&pos, _| {
+            let value = self.value(pos);
+            if key.eq(extract(value).borrow()) {
+                found.push((self.handle(pos), value));
+            }
+        });
+        found
+    }
+
+    /// Inserts an entry into every index, and returns its handle. If a
+    /// unique index already has an entry with the same key, the map is left
+    /// unchanged and the entry is given back.
+    pub fn insert(&mut self, value: T) -> Result<Handle, T> {
+        let hashes = self.hashes(&value);
+        if self.violates_unique(&value, &hashes, None) {
+            return Err(value);
+        }
+
+        let pos = match self.free.pop() {
+            Some(pos) => {
+                self.slots[pos].value = Some(value);
+                pos
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, value: Some(value) });
+                self.slots.len() - 1
+            }
+        };
+        self.len += 1;
+
+        for (index, &hash) in self.indexes.iter_mut().zip(&hashes) {
+            index.table.insert(hash, pos, ());
+        }
+        Ok(self.handle(pos))
+    }
+
+    /// Modifies an entry in place, and re-indexes the keys that changed.
+    ///
+    /// If the modified entry has the same key as another entry in a unique
+    /// index, the entry keeps its old value, and the modified value is
+    /// given back. The entry is modified on a clone, so the old value can be
+    /// kept; no index is changed before the check.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the handle doesn't refer to a present entry.
+    pub fn modify<F>(&mut self, handle: Handle, f: F) -> Result<(), T>
+        where F: FnOnce(&mut T), T: Clone
+    {
+        let pos = self.position(handle).expect("invalid handle");
+
+        let old_hashes = self.hashes(self.value(pos));
+        let mut value = self.value(pos).clone();
+        f(&mut value);
+
+        let new_hashes = self.hashes(&value);
+        if self.violates_unique(&value, &new_hashes, Some(pos)) {
+            return Err(value);
+        }
+
+        self.slots[pos].value = Some(value);
+        for (index, (&old, &new)) in self.indexes.iter_mut().zip(old_hashes.iter().zip(new_hashes.iter())) {
+            if old != new {
+                index.table.remove(old, |&p| p == pos);
+                index.table.insert(new, pos, ());
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes an entry from every index, and returns it.
+    pub fn remove(&mut self, handle: Handle) -> Option<T> {
+        let pos = match self.position(handle) {
+            Some(pos) => pos,
+            None => return None,
+        };
+
+        for index in &mut self.indexes {
+            let hash = (index.hash)(index, &self.hash_state, slot_value(&self.slots, pos));
+            index.table.remove(hash, |&p| p == pos);
+        }
+        Some(self.free_slot(pos))
+    }
+
+    /// Clears the map, removing all entries. Handles to them become invalid,
+    /// but the indexes are kept.
+    pub fn clear(&mut self) {
+        for index in &mut self.indexes {
+            index.table.clear();
+        }
+        for pos in 0..self.slots.len() {
+            if self.slots[pos].value.is_some() {
+                self.free_slot(pos);
+            }
+        }
+    }
+
+    /// An iterator visiting all entries and their handles in arbitrary order.
+    pub fn iter(&self) -> Iter<T> {
+        Iter {
+            slots: self.slots.iter().enumerate(),
+            remaining: self.len,
+        }
+    }
+
+    fn position(&self, handle: Handle) -> Option<usize> {
+        match self.slots.get(handle.index) {
+            Some(slot) if slot.generation == handle.generation && slot.value.is_some() => {
+                Some(handle.index)
+            }
+            _ => None,
+        }
+    }
+
+    fn handle(&self, pos: usize) -> Handle {
+        Handle {
+            index: pos,
+            generation: self.slots[pos].generation,
+        }
+    }
+
+    fn value(&self, pos: usize) -> &T {
+        slot_value(&self.slots, pos)
+    }
+
+    /// Returns the index an index handle refers to.
+    fn index<K>(&self, handle: &IndexHandle<T, K>) -> &Index<T, S> {
+        assert!(handle.map == self.id, "index handle of another MultiIndexMap");
+        &self.indexes[handle.id]
+    }
+
+    /// Computes the hash of the entry's key for every index.
+    fn hashes(&self, value: &T) -> Vec<SafeHash> {
+        self.indexes.iter().map(|index| (index.hash)(index, &self.hash_state, value)).collect()
+    }
+
+    /// Returns true if a unique index has an entry other than the one at
+    /// `except` with the same key as `value`, whose `hashes` are given.
+    fn violates_unique(&self, value: &T, hashes: &[SafeHash], except: Option<usize>) -> bool {
+        self.indexes.iter().zip(hashes).filter(|&(index, _)| index.unique).any(|(index, &hash)| {
+            let mut found = false;
+            index.table.for_each_hashed(hash, |&pos, _| {
+                if Some(pos) != except && (index.eq)(index, self.value(pos), value) {
+                    found = true;
+                }
+            });
+            found
+        })
+    }
+
+    /// Takes the entry out of its slot, which must already be unindexed.
+    fn free_slot(&mut self, pos: usize) -> T {
+        let slot = &mut self.slots[pos];
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(pos);
+        self.len -= 1;
+        slot.value.take().unwrap()
+    }
+}
+
+fn slot_value<T>(slots: &[Slot<T>], pos: usize) -> &T {
+    slots[pos].value.as_ref().expect("indexed slot is empty")
+}
+
+impl<T: Debug, S: HashState> Debug for MultiIndexMap<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter().map(|(_, t)| t)).finish()
+    }
+}
+
+impl<T> Default for MultiIndexMap<T, RandomState> {
+    fn default() -> MultiIndexMap<T, RandomState> {
+        MultiIndexMap::new()
+    }
+}
+
+/// MultiIndexMap iterator.
+pub struct Iter<'a, T: 'a> {
+    slots: ::std::iter::Enumerate<::std::slice::Iter<'a, Slot<T>>>,
+    remaining: usize,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Handle, &'a T);
+
+    fn next(&mut self) -> Option<(Handle, &'a T)> {
+        while let Some((pos, slot)) = self.slots.next() {
+            if let Some(ref value) = slot.value {
+                self.remaining -= 1;
+                let handle = Handle { index: pos, generation: slot.generation };
+                return Some((handle, value));
+            }
+        }
+        None
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+impl<'a, T> ExactSizeIterator for Iter<'a, T> {
+    #[inline] fn len(&self) -> usize { self.remaining }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MultiIndexMap;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Record {
+        id: u32,
+        name: String,
+        group: u32,
+    }
+
+    fn record(id: u32, name: &str, group: u32) -> Record {
+        Record { id: id, name: name.to_string(), group: group }
+    }
+
+    #[test]
+    fn test_indexes_stay_consistent() {
+        let mut map = MultiIndexMap::new();
+        let by_id = map.add_unique_index(|r: &Record| r.id);
+        let by_name = map.add_unique_index(|r: &Record| r.name.clone());
+        let by_group = map.add_index(|r: &Record| r.group);
+
+        let mut handles = vec![];
+        for i in 0..100 {
+            handles.push(map.insert(record(i, &format!("r{}", i), i % 10)).ok().unwrap());
+        }
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get_all(&by_group, &3).len(), 10);
+
+        // Duplicate keys in any unique index are rejected.
+        assert_eq!(map.insert(record(5, "new", 0)), Err(record(5, "new", 0)));
+        assert_eq!(map.insert(record(500, "r5", 0)), Err(record(500, "r5", 0)));
+        assert_eq!(map.len(), 100);
+
+        for &h in &handles[..50] {
+            map.modify(h, |r| { r.id += 1000; r.group = 99; }).ok().unwrap();
+        }
+        assert_eq!(map.get_all(&by_group, &99).len(), 50);
+        assert_eq!(map.get_all(&by_group, &3).len(), 5);
+        assert!(map.get_unique(&by_id, &7).is_none());
+        assert_eq!(map.get_unique(&by_id, &1007).unwrap().1.name, "r7");
+
+        for &h in &handles[..25] {
+            assert!(map.remove(h).is_some());
+            assert!(map.remove(h).is_none());
+        }
+        assert_eq!(map.len(), 75);
+        assert_eq!(map.get_all(&by_group, &99).len(), 25);
+        assert!(map.get_unique(&by_name, "r3").is_none());
+        assert_eq!(map.get_unique(&by_name, "r30").unwrap().0, handles[30]);
+
+        // Freed slots are reused with fresh handles.
+        let h = map.insert(record(3, "r3", 3)).ok().unwrap();
+        assert!(!handles.contains(&h));
+        assert_eq!(map.get(h).unwrap().id, 3);
+        assert_eq!(map.iter().count(), 76);
+    }
+
+    #[test]
+    fn test_modify_violation_keeps_entry() {
+        let mut map = MultiIndexMap::new();
+        let by_id = map.add_unique_index(|r: &Record| r.id);
+        let by_group = map.add_index(|r: &Record| r.group);
+        let a = map.insert(record(1, "a", 0)).ok().unwrap();
+        let b = map.insert(record(2, "b", 0)).ok().unwrap();
+
+        // Keeping its own key isn't a violation.
+        assert!(map.modify(a, |r| r.group = 1).is_ok());
+
+        assert_eq!(map.modify(b, |r| { r.id = 1; r.group = 5; }), Err(record(1, "b", 5)));
+        assert_eq!(map.get(b), Some(&record(2, "b", 0)));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get_unique(&by_id, &1).unwrap().1, &record(1, "a", 1));
+        assert_eq!(map.get_unique(&by_id, &2).unwrap().0, b);
+        assert_eq!(map.get_all(&by_group, &0).len(), 1);
+        assert!(map.get_all(&by_group, &5).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "another MultiIndexMap")]
+    fn test_index_handle_of_other_map() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let mut first = MultiIndexMap::new();
+        let by_id = first.add_unique_index(|r: &Record| r.id);
+        let mut second = MultiIndexMap::new();
+        second.add_unique_index(|r: &Record| r.id);
+        second.insert(record(1, "a", 0)).ok().unwrap();
+        assert_send(&second);
+
+        second.get_unique(&by_id, &1);
+    }
+}
@@ -0,0 +1,502 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A hash map that stores several values per key.
+
+use self::Values::*;
+
+use std::borrow::Borrow;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::iter::{FromIterator, IntoIterator};
+use std::mem;
+use std::slice;
+use std::collections::hash_state::HashState;
+
+use {HashMap, RandomState};
+use Entry::{Occupied, Vacant};
+
+/// A hash map that stores several values per key, built on `HashMap`.
+///
+/// Inserting under an existing key appends the value to that key's list.
+/// A key with a single value stores it inline, so the common case doesn't
+/// allocate beyond the table itself.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::MultiMap;
+///
+/// let mut pets = MultiMap::new();
+/// pets.insert("cat", "Tom");
+/// pets.insert("dog", "Rex");
+/// pets.insert("cat", "Felix");
+///
+/// assert_eq!(pets.get_all("cat"), ["Tom", "Felix"]);
+/// assert_eq!(pets.len(), 3);
+/// assert_eq!(pets.key_len(), 2);
+///
+/// assert_eq!(pets.remove_one("cat", |&name| name == "Tom"), Some("Tom"));
+/// assert_eq!(pets.get_all("cat"), ["Felix"]);
+/// ```
+#[derive(Clone)]
+pub struct MultiMap<K, V, S = RandomState> {
+    map: HashMap<K, Values<V>, S>,
+
+    // The total number of values.
+    len: usize,
+}
+
+/// The values stored under one key.
+#[derive(Clone)]
+enum Values<V> {
+    /// A single value, stored without allocating.
+    One(V),
+    /// Any number of values. Only empty while a value is being removed.
+    Many(Vec<V>),
+}
+
+impl<V> Values<V> {
+    fn as_slice(&self) -> &[V] {
+        match *self {
+            One(ref v) => unsafe { slice::from_raw_parts(v, 1) },
+            Many(ref vs) => vs,
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [V] {
+        match *self {
+            One(ref mut v) => unsafe { slice::from_raw_parts_mut(v, 1) },
+            Many(ref mut vs) => vs,
+        }
+    }
+
+    fn push(&mut self, v: V) {
+        // An empty vector doesn't allocate.
+        let values = match mem::replace(self, Many(Vec::new())) {
+            One(first) => vec![first, v],
+            Many(mut vs) => {
+                vs.push(v);
+                vs
+            }
+        };
+        *self = Many(values);
+    }
+
+    /// Removes the first value matching `f`, preserving the order of the
+    /// others.
+    fn remove_first<F>(&mut self, mut f: F) -> Option<V>
+        where F: FnMut(&V) -> bool
+    {
+        let index = match self.as_slice().iter().position(|v| f(v)) {
+            Some(index) => index,
+            None => return None,
+        };
+
+        match *self {
+            Many(ref mut vs) => return Some(vs.remove(index)),
+            One(..) => {}
+        }
+        match mem::replace(self, Many(Vec::new())) {
+            One(v) => Some(v),
+            Many(..) => unreachable!(),
+        }
+    }
+
+    fn into_vec(self) -> Vec<V> {
+        match self {
+            One(v) => vec![v],
+            Many(vs) => vs,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> MultiMap<K, V, RandomState> {
+    /// Creates an empty `MultiMap`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::MultiMap;
+    /// let mut map: MultiMap<&str, isize> = MultiMap::new();
+    /// ```
+    #[inline]
+    pub fn new() -> MultiMap<K, V, RandomState> {
+        Default::default()
+    }
+
+    /// Creates an empty `MultiMap` with space for at least `capacity` keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::MultiMap;
+    /// let mut map: MultiMap<&str, isize> = MultiMap::with_capacity(10);
+    /// ```
+    #[inline]
+    pub fn with_capacity(capacity: usize) -> MultiMap<K, V, RandomState> {
+        MultiMap::with_capacity_and_hash_state(capacity, Default::default())
+    }
+}
+
+impl<K, V, S> MultiMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Creates an empty `MultiMap` which will use the given hash state to
+    /// hash keys.
+    #[inline]
+    pub fn with_hash_state(hash_state: S) -> MultiMap<K, V, S> {
+        MultiMap {
+            map: HashMap::with_hash_state(hash_state),
+            len: 0,
+        }
+    }
+
+    /// Creates an empty `MultiMap` with space for at least `capacity` keys,
+    /// using `hash_state` to hash the keys.
+    #[inline]
+    pub fn with_capacity_and_hash_state(capacity: usize, hash_state: S)
+                                        -> MultiMap<K, V, S> {
+        MultiMap {
+            map: HashMap::with_capacity_and_hash_state(capacity, hash_state),
+            len: 0,
+        }
+    }
+
+    /// Returns the number of values in the map.
+    pub fn len(&self) -> usize { self.len }
+
+    /// Returns the number of distinct keys in the map.
+    pub fn key_len(&self) -> usize { self.map.len() }
+
+    /// Returns true if the map contains no values.
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Appends a value to the list of values for the key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::MultiMap;
+    ///
+    /// let mut map = MultiMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(1, "b");
+    /// assert_eq!(map.get_all(&1), ["a", "b"]);
+    /// ```
+    pub fn insert(&mut self, k: K, v: V) {
+        match self.map.entry(k) {
+            Occupied(entry) => entry.into_mut().push(v),
+            Vacant(entry) => { entry.insert(One(v)); }
+        }
+        self.len += 1;
+    }
+
+    /// Returns the first value inserted for the key.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get_all(k).first()
+    }
+
+    /// Returns all values for the key in insertion order, or an empty slice
+    /// if the key isn't present.
+    pub fn get_all<Q: ?Sized>(&self, k: &Q) -> &[V]
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        match self.map.get(k) {
+            Some(values) => values.as_slice(),
+            None => &[],
+        }
+    }
+
+    /// Returns mutable references to all values for the key.
+    pub fn get_all_mut<Q: ?Sized>(&mut self, k: &Q) -> &mut [V]
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        match self.map.get_mut(k) {
+            Some(values) => values.as_mut_slice(),
+            None => &mut [],
+        }
+    }
+
+    /// Returns true if the map contains at least one value for the key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.contains_key(k)
+    }
+
+    /// Removes the key from the map, returning all of its values in
+    /// insertion order.
+    pub fn remove_all<Q: ?Sized>(&mut self, k: &Q) -> Vec<V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        match self.map.remove(k) {
+            Some(values) => {
+                let values = values.into_vec();
+                self.len -= values.len();
+                values
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes the first value for the key that matches `f`. The key is
+    /// removed along with its last value.
+    pub fn remove_one<Q: ?Sized, F>(&mut self, k: &Q, f: F) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq, F: FnMut(&V) -> bool
+    {
+        let (removed, now_empty) = match self.map.get_mut(k) {
+            Some(values) => {
+                let removed = values.remove_first(f);
+                (removed, values.as_slice().is_empty())
+            }
+            None => return None,
+        };
+
+        if now_empty {
+            self.map.remove(k);
+        }
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Removes all keys and values, keeping the allocated memory for reuse.
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.len = 0;
+    }
+
+    /// An iterator visiting all key-value pairs, with the values of each
+    /// key in insertion order. A key is yielded once per value.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter {
+            groups: self.groups(),
+            current: None,
+            remaining: self.len,
+        }
+    }
+
+    /// An iterator visiting every key along with all of its values.
+    pub fn groups(&self) -> Groups<K, V> {
+        Groups { inner: self.map.iter() }
+    }
+
+    /// An iterator visiting all distinct keys.
+    pub fn keys(&self) -> Keys<K, V> {
+        Keys { inner: self.map.keys() }
+    }
+}
+
+impl<K, V, S> PartialEq for MultiMap<K, V, S>
+    where K: Eq + Hash, V: PartialEq, S: HashState
+{
+    fn eq(&self, other: &MultiMap<K, V, S>) -> bool {
+        self.len == other.len && self.key_len() == other.key_len() &&
+            self.groups().all(|(k, values)| other.get_all(k) == values)
+    }
+}
+
+impl<K, V, S> Eq for MultiMap<K, V, S>
+    where K: Eq + Hash, V: Eq, S: HashState
+{}
+
+impl<K, V, S> Debug for MultiMap<K, V, S>
+    where K: Eq + Hash + Debug, V: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.groups()).finish()
+    }
+}
+
+impl<K, V, S> Default for MultiMap<K, V, S>
+    where K: Eq + Hash, S: HashState + Default
+{
+    fn default() -> MultiMap<K, V, S> {
+        MultiMap::with_hash_state(Default::default())
+    }
+}
+
+impl<K, V, S> FromIterator<(K, V)> for MultiMap<K, V, S>
+    where K: Eq + Hash, S: HashState + Default
+{
+    /// Groups the values by key, keeping the values of each key in
+    /// iteration order.
+    fn from_iter<T: IntoIterator<Item=(K, V)>>(iterable: T) -> MultiMap<K, V, S> {
+        let mut map = MultiMap::with_hash_state(Default::default());
+        map.extend(iterable);
+        map
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for MultiMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a MultiMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// MultiMap iterator over key-value pairs.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    groups: Groups<'a, K, V>,
+    current: Option<(&'a K, slice::Iter<'a, V>)>,
+    remaining: usize,
+}
+
+/// MultiMap iterator over keys with all of their values.
+pub struct Groups<'a, K: 'a, V: 'a> {
+    inner: ::Iter<'a, K, Values<V>>,
+}
+
+/// MultiMap keys iterator.
+pub struct Keys<'a, K: 'a, V: 'a> {
+    inner: ::Keys<'a, K, Values<V>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            if let Some((k, ref mut values)) = self.current {
+                if let Some(v) = values.next() {
+                    self.remaining -= 1;
+                    return Some((k, v));
+                }
+            }
+
+            match self.groups.next() {
+                Some((k, values)) => self.current = Some((k, values.iter())),
+                None => return None,
+            }
+        }
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
+    #[inline] fn len(&self) -> usize { self.remaining }
+}
+
+impl<'a, K, V> Iterator for Groups<'a, K, V> {
+    type Item = (&'a K, &'a [V]);
+
+    #[inline] fn next(&mut self) -> Option<(&'a K, &'a [V])> {
+        self.inner.next().map(|(k, values)| (k, values.as_slice()))
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+impl<'a, K, V> ExactSizeIterator for Groups<'a, K, V> {
+    #[inline] fn len(&self) -> usize { self.inner.len() }
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    #[inline] fn next(&mut self) -> Option<&'a K> { self.inner.next() }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {
+    #[inline] fn len(&self) -> usize { self.inner.len() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MultiMap;
+
+    #[test]
+    fn test_insert_get() {
+        let mut m = MultiMap::new();
+        assert!(m.is_empty());
+        assert_eq!(m.get_all(&1), &[] as &[i32]);
+        assert_eq!(m.get(&1), None);
+
+        m.insert(1, 10);
+        m.insert(2, 20);
+        m.insert(1, 11);
+        m.insert(1, 12);
+
+        assert_eq!(m.len(), 4);
+        assert_eq!(m.key_len(), 2);
+        assert_eq!(m.get_all(&1), [10, 11, 12]);
+        assert_eq!(m.get(&1), Some(&10));
+        assert_eq!(m.get_all(&2), [20]);
+
+        for v in m.get_all_mut(&2) {
+            *v += 1;
+        }
+        assert_eq!(m.get_all(&2), [21]);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut m = MultiMap::new();
+        m.insert("a", 1);
+        m.insert("a", 2);
+        m.insert("a", 3);
+        m.insert("b", 4);
+
+        assert_eq!(m.remove_one("a", |&v| v == 2), Some(2));
+        assert_eq!(m.remove_one("a", |&v| v == 2), None);
+        assert_eq!(m.remove_one("c", |_| true), None);
+        assert_eq!(m.get_all("a"), [1, 3]);
+        assert_eq!(m.len(), 3);
+
+        assert_eq!(m.remove_one("b", |_| true), Some(4));
+        assert!(!m.contains_key("b"));
+        assert_eq!(m.key_len(), 1);
+
+        assert_eq!(m.remove_all("a"), [1, 3]);
+        assert_eq!(m.remove_all("a"), Vec::<i32>::new());
+        assert!(m.is_empty());
+        assert_eq!(m.key_len(), 0);
+    }
+
+    #[test]
+    fn test_from_iter_groups() {
+        let words = ["apple", "avocado", "banana", "blueberry", "cherry", "apricot"];
+        let m: MultiMap<_, _> = words.iter().map(|w| (w.as_bytes()[0], *w)).collect();
+
+        assert_eq!(m.len(), 6);
+        assert_eq!(m.key_len(), 3);
+        assert_eq!(m.get_all(&b'a'), ["apple", "avocado", "apricot"]);
+
+        let mut groups: Vec<_> = m.groups().map(|(&k, vs)| (k, vs.len())).collect();
+        groups.sort();
+        assert_eq!(groups, [(b'a', 3), (b'b', 2), (b'c', 1)]);
+
+        let iter = m.iter();
+        assert_eq!(iter.len(), 6);
+        let mut pairs: Vec<_> = iter.map(|(&k, &v)| (k, v)).collect();
+        pairs.sort();
+        assert_eq!(pairs[0], (b'a', "apple"));
+        assert_eq!(pairs.len(), 6);
+    }
+}
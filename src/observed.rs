@@ -0,0 +1,551 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A hash map that reports every change to an observer.
+
+use std::borrow::Borrow;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::iter::IntoIterator;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::collections::hash_state::HashState;
+
+use {Drain, HashMap, Iter, RandomState, pop_internal};
+
+/// Callbacks for the changes made to an `ObservedHashMap`. Every method
+/// does nothing by default.
+pub trait Observer<K, V> {
+    /// Called when a key is inserted.
+    fn on_insert(&mut self, _key: &K, _value: &V) {}
+
+    /// Called when the value of a key is replaced.
+    fn on_replace(&mut self, _key: &K, _old: &V, _new: &V) {}
+
+    /// Called when a value may have been changed in place, through a
+    /// `ValueMut`. The old value isn't kept, so only the new one is passed.
+    fn on_modify(&mut self, _key: &K, _value: &V) {}
+
+    /// Called when a key is removed.
+    fn on_remove(&mut self, _key: &K, _value: &V) {}
+
+    /// Called when all the keys are removed at once, by `clear` or `drain`.
+    fn on_clear(&mut self) {}
+
+    /// Called when the table is resized.
+    fn on_resize(&mut self, _old_capacity: usize, _new_capacity: usize) {}
+}
+
+/// The observer that ignores every change. Its callbacks compile to
+/// nothing, so an `ObservedHashMap<K, V, S, ()>` costs the same as a
+/// `HashMap<K, V, S>`.
+impl<K, V> Observer<K, V> for () {}
+
+/// A `HashMap` that calls an `Observer` for every change, including those
+/// made through `entry`, `iter_mut`, `retain` and `drain`.
+///
+/// It wraps a `HashMap` rather than being one, and its mutable access
+/// differs: `get_mut`, `iter_mut` and entries give a `ValueMut` guard
+/// instead of `&mut V`, which calls `on_modify` when it's dropped.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::ObservedHashMap;
+/// use hashmap2::observed::Observer;
+///
+/// #[derive(Default)]
+/// struct Size(isize);
+///
+/// impl<K, V> Observer<K, V> for Size {
+///     fn on_insert(&mut self, _: &K, _: &V) { self.0 += 1; }
+///     fn on_remove(&mut self, _: &K, _: &V) { self.0 -= 1; }
+///     fn on_clear(&mut self) { self.0 = 0; }
+/// }
+///
+/// let mut map = ObservedHashMap::new(Size::default());
+/// map.insert("a", 1);
+/// *map.entry("b").or_insert(0) += 1;
+/// map.remove("a");
+/// assert_eq!(map.observer().0, 1);
+/// ```
+pub struct ObservedHashMap<K, V, S = RandomState, O = ()> {
+    map: HashMap<K, V, S>,
+    observer: O,
+}
+
+#[cfg(feature = "std")]
+impl<K, V, O> ObservedHashMap<K, V, RandomState, O>
+    where K: Eq + Hash, O: Observer<K, V>
+{
+    /// Creates an empty `ObservedHashMap` that reports to `observer`.
+    #[inline]
+    pub fn new(observer: O) -> ObservedHashMap<K, V, RandomState, O> {
+        ObservedHashMap::from_map(HashMap::new(), observer)
+    }
+}
+
+impl<K, V, S, O> ObservedHashMap<K, V, S, O>
+    where K: Eq + Hash, S: HashState, O: Observer<K, V>
+{
+    /// Creates an empty `ObservedHashMap` which will use the given hash
+    /// state to hash keys.
+    #[inline]
+    pub fn with_hash_state(hash_state: S, observer: O) -> ObservedHashMap<K, V, S, O> {
+        ObservedHashMap::from_map(HashMap::with_hash_state(hash_state), observer)
+    }
+
+    /// Observes the changes made to an existing map from now on.
+    pub fn from_map(map: HashMap<K, V, S>, observer: O) -> ObservedHashMap<K, V, S, O> {
+        ObservedHashMap {
+            map: map,
+            observer: observer,
+        }
+    }
+
+    /// Returns the map and the observer.
+    pub fn into_inner(self) -> (HashMap<K, V, S>, O) {
+        (self.map, self.observer)
+    }
+
+    /// Returns the observed map, for reading.
+    pub fn map(&self) -> &HashMap<K, V, S> { &self.map }
+
+    /// Returns the observer.
+    pub fn observer(&self) -> &O { &self.observer }
+
+    /// Returns the observer, mutably.
+    pub fn observer_mut(&mut self) -> &mut O { &mut self.observer }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize { self.map.len() }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool { self.map.is_empty() }
+
+    /// Returns the number of elements the map can hold without
+    /// reallocating.
+    pub fn capacity(&self) -> usize { self.map.capacity() }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.get(k)
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.contains_key(k)
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    pub fn iter(&self) -> Iter<K, V> {
+        self.map.iter()
+    }
+
+    /// Reports a resize since the capacity was `old_capacity`.
+    fn check_resize(&mut self, old_capacity: usize) {
+        let new_capacity = self.map.table.capacity();
+        if new_capacity != old_capacity {
+            self.observer.on_resize(old_capacity, new_capacity);
+        }
+    }
+
+    /// Reserves capacity for at least `additional` more elements.
+    pub fn reserve(&mut self, additional: usize) {
+        let old_capacity = self.map.table.capacity();
+        self.map.reserve(additional);
+        self.check_resize(old_capacity);
+    }
+
+    /// Shrinks the capacity of the map as much as possible.
+    pub fn shrink_to_fit(&mut self) {
+        let old_capacity = self.map.table.capacity();
+        self.map.shrink_to_fit();
+        self.check_resize(old_capacity);
+    }
+
+    /// Inserts a key-value pair into the map, and returns the old value if
+    /// the key was present. The key is not updated.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        match self.entry(k) {
+            Entry::Occupied(mut entry) => Some(entry.insert(v)),
+            Entry::Vacant(entry) => {
+                // Skip the guard, which would report a modification too.
+                let (key, value) = entry.inner.insert_refs(v);
+                entry.observer.on_insert(key, value);
+                None
+            }
+        }
+    }
+
+    /// Gets the given key's corresponding entry in the map for in-place
+    /// manipulation.
+    pub fn entry(&mut self, key: K) -> Entry<K, V, O> {
+        self.reserve(1);
+        let observer = &mut self.observer;
+        match self.map.entry(key) {
+            ::Occupied(inner) => Entry::Occupied(OccupiedEntry {
+                inner: inner,
+                observer: observer,
+            }),
+            ::Vacant(inner) => Entry::Vacant(VacantEntry {
+                inner: inner,
+                observer: observer,
+            }),
+        }
+    }
+
+    /// Returns a guard for the value corresponding to the key, which
+    /// reports a change when it's dropped.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<ValueMut<K, V, O>>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let observer = &mut self.observer;
+        self.map.search_mut(k).map(move |bucket| {
+            let (key, value) = bucket.into_mut_refs();
+            ValueMut::new(key, value, observer)
+        })
+    }
+
+    /// Removes a key from the map, returning the value at the key if the
+    /// key was previously in the map.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.take(k).map(|(_, v)| v)
+    }
+
+    /// Removes a key from the map, returning the stored key and value if
+    /// the key was previously in the map.
+    pub fn take<Q: ?Sized>(&mut self, k: &Q) -> Option<(K, V)>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let removed = self.map.take(k);
+        if let Some((ref key, ref value)) = removed {
+            self.observer.on_remove(key, value);
+        }
+        removed
+    }
+
+    /// Retains only the elements for which the predicate returns true, and
+    /// returns the number of elements removed. The predicate may change the
+    /// values, so `on_modify` is called for every element kept.
+    pub fn retain<F>(&mut self, mut keep: F) -> usize
+        where F: FnMut(&K, &mut V) -> bool
+    {
+        let observer = &mut self.observer;
+        self.map.retain(|k, v| {
+            if keep(k, v) {
+                observer.on_modify(k, v);
+                true
+            } else {
+                observer.on_remove(k, v);
+                false
+            }
+        })
+    }
+
+    /// Clears the map, removing all key-value pairs. Keeps the allocated
+    /// memory for reuse.
+    pub fn clear(&mut self) {
+        self.observer.on_clear();
+        self.map.clear();
+    }
+
+    /// Clears the map, returning all key-value pairs as an iterator. The
+    /// observer sees a single `on_clear`.
+    pub fn drain(&mut self) -> Drain<K, V> {
+        self.observer.on_clear();
+        self.map.drain()
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order, with
+    /// guards for the values.
+    pub fn iter_mut(&mut self) -> IterMut<K, V, O> {
+        IterMut {
+            inner: self.map.iter_mut(),
+            observer: &mut self.observer,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, S, O> Debug for ObservedHashMap<K, V, S, O>
+    where K: Eq + Hash + Debug, V: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+impl<'a, K, V, S, O> IntoIterator for &'a ObservedHashMap<K, V, S, O>
+    where K: Eq + Hash, S: HashState, O: Observer<K, V>
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// A mutable reference to a value in an `ObservedHashMap`, which calls
+/// `Observer::on_modify` when dropped.
+pub struct ValueMut<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    key: &'a K,
+    value: &'a mut V,
+
+    // `IterMut` hands out many guards at once, which take turns using the
+    // observer when dropped.
+    observer: *mut O,
+    marker: PhantomData<&'a mut O>,
+}
+
+impl<'a, K, V, O: Observer<K, V>> ValueMut<'a, K, V, O> {
+    fn new(key: &'a K, value: &'a mut V, observer: &'a mut O) -> ValueMut<'a, K, V, O> {
+        ValueMut {
+            key: key,
+            value: value,
+            observer: observer,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the key of the value.
+    pub fn key(&self) -> &K { self.key }
+}
+
+impl<'a, K, V, O: Observer<K, V>> Deref for ValueMut<'a, K, V, O> {
+    type Target = V;
+
+    fn deref(&self) -> &V { self.value }
+}
+
+impl<'a, K, V, O: Observer<K, V>> DerefMut for ValueMut<'a, K, V, O> {
+    fn deref_mut(&mut self) -> &mut V { self.value }
+}
+
+impl<'a, K, V, O: Observer<K, V>> Drop for ValueMut<'a, K, V, O> {
+    fn drop(&mut self) {
+        unsafe { (*self.observer).on_modify(self.key, self.value); }
+    }
+}
+
+/// ObservedHashMap mutable values iterator.
+pub struct IterMut<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    inner: ::IterMut<'a, K, V>,
+    observer: *mut O,
+    marker: PhantomData<&'a mut O>,
+}
+
+impl<'a, K, V, O: Observer<K, V>> Iterator for IterMut<'a, K, V, O> {
+    type Item = (&'a K, ValueMut<'a, K, V, O>);
+
+    fn next(&mut self) -> Option<(&'a K, ValueMut<'a, K, V, O>)> {
+        let observer = self.observer;
+        self.inner.next().map(|(k, v)| {
+            (k, ValueMut { key: k, value: v, observer: observer, marker: PhantomData })
+        })
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+impl<'a, K, V, O: Observer<K, V>> ExactSizeIterator for IterMut<'a, K, V, O> {
+    #[inline] fn len(&self) -> usize { self.inner.len() }
+}
+
+/// A view into a single location in an ObservedHashMap.
+pub enum Entry<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    /// An occupied Entry.
+    Occupied(OccupiedEntry<'a, K, V, O>),
+
+    /// A vacant Entry.
+    Vacant(VacantEntry<'a, K, V, O>),
+}
+
+/// A view into a single occupied location in an ObservedHashMap.
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    inner: ::OccupiedEntry<'a, K, V>,
+    observer: &'a mut O,
+}
+
+/// A view into a single empty location in an ObservedHashMap.
+pub struct VacantEntry<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    inner: ::VacantEntry<'a, K, V>,
+    observer: &'a mut O,
+}
+
+impl<'a, K, V, O: Observer<K, V>> Entry<'a, K, V, O> {
+    /// Returns the entry key.
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the default if empty,
+    /// and returns a guard for the value in the entry.
+    pub fn or_insert(self, default: V) -> ValueMut<'a, K, V, O> {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the
+    /// default function if empty, and returns a guard for the value in the
+    /// entry.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> ValueMut<'a, K, V, O> {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+}
+
+impl<'a, K, V, O: Observer<K, V>> OccupiedEntry<'a, K, V, O> {
+    /// Gets a reference to the entry key.
+    pub fn key(&self) -> &K {
+        self.inner.key()
+    }
+
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &V {
+        self.inner.get()
+    }
+
+    /// Gets a guard for the value in the entry.
+    pub fn get_mut(&mut self) -> ValueMut<K, V, O> {
+        let (key, value) = self.inner.elem.read_mut();
+        ValueMut::new(key, value, self.observer)
+    }
+
+    /// Converts the entry into a guard for the value, with a lifetime bound
+    /// to the map itself.
+    pub fn into_mut(self) -> ValueMut<'a, K, V, O> {
+        let (key, value) = self.inner.elem.into_mut_refs();
+        ValueMut::new(key, value, self.observer)
+    }
+
+    /// Sets the value of the entry, and returns the entry's old value.
+    pub fn insert(&mut self, value: V) -> V {
+        let old = self.inner.insert(value);
+        self.observer.on_replace(self.inner.key(), &old, self.inner.get());
+        old
+    }
+
+    /// Takes the value out of the entry, and returns it.
+    pub fn remove(self) -> V {
+        let (key, value) = pop_internal(self.inner.elem);
+        self.observer.on_remove(&key, &value);
+        value
+    }
+}
+
+impl<'a, K, V, O: Observer<K, V>> VacantEntry<'a, K, V, O> {
+    /// Gets a reference to the entry key.
+    pub fn key(&self) -> &K {
+        self.inner.key()
+    }
+
+    /// Sets the value of the entry with the VacantEntry's key, and returns
+    /// a guard for it.
+    pub fn insert(self, value: V) -> ValueMut<'a, K, V, O> {
+        let (key, value) = self.inner.insert_refs(value);
+        self.observer.on_insert(key, value);
+        ValueMut::new(key, value, self.observer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Entry, ObservedHashMap, Observer};
+    use HashMap;
+
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Observer<u32, u32> for Log {
+        fn on_insert(&mut self, k: &u32, v: &u32) { self.0.push(format!("insert {} {}", k, v)); }
+        fn on_replace(&mut self, k: &u32, old: &u32, new: &u32) {
+            self.0.push(format!("replace {} {} {}", k, old, new));
+        }
+        fn on_modify(&mut self, k: &u32, v: &u32) { self.0.push(format!("modify {} {}", k, v)); }
+        fn on_remove(&mut self, k: &u32, v: &u32) { self.0.push(format!("remove {} {}", k, v)); }
+        fn on_clear(&mut self) { self.0.push("clear".to_string()); }
+        fn on_resize(&mut self, old: usize, new: usize) {
+            self.0.push(format!("resize {} {}", old, new));
+        }
+    }
+
+    fn events(map: &mut ObservedHashMap<u32, u32, ::RandomState, Log>) -> Vec<String> {
+        ::std::mem::replace(&mut map.observer_mut().0, Vec::new())
+    }
+
+    #[test]
+    fn test_basic_events() {
+        let mut map = ObservedHashMap::new(Log::default());
+        map.insert(1, 10);
+        assert_eq!(events(&mut map), ["resize 0 32", "insert 1 10"]);
+
+        map.insert(1, 11);
+        *map.get_mut(&1).unwrap() += 1;
+        assert_eq!(map.remove(&1), Some(12));
+        assert_eq!(map.remove(&1), None);
+        assert_eq!(events(&mut map), ["replace 1 10 11", "modify 1 12", "remove 1 12"]);
+    }
+
+    #[test]
+    fn test_entry_and_iter_mut() {
+        let mut map = ObservedHashMap::new(Log::default());
+        *map.entry(1).or_insert(0) += 5;
+        if let Entry::Occupied(mut entry) = map.entry(1) {
+            assert_eq!(entry.insert(6), 5);
+            assert_eq!(entry.remove(), 6);
+        }
+        map.insert(2, 2);
+        map.insert(3, 3);
+        events(&mut map);
+
+        let guards: Vec<_> = map.iter_mut().map(|(_, v)| v).collect();
+        for mut v in guards {
+            *v *= 10;
+        }
+        let mut modified = events(&mut map);
+        modified.sort();
+        assert_eq!(modified, ["modify 2 20", "modify 3 30"]);
+
+        map.insert(4, 40);
+        events(&mut map);
+        assert_eq!(map.retain(|&k, v| { *v += 1; k != 3 }), 1);
+        let mut retained = events(&mut map);
+        retained.sort();
+        assert_eq!(retained, ["modify 2 21", "modify 4 41", "remove 3 31"]);
+        assert_eq!(map.drain().count(), 2);
+        assert_eq!(events(&mut map), ["clear"]);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_unobserved() {
+        let base: HashMap<u32, u32> = (0..10).map(|i| (i, i)).collect();
+        let mut map = ObservedHashMap::from_map(base, ());
+        *map.entry(10).or_insert(0) += 10;
+        map.remove(&0);
+        let (map, ()) = map.into_inner();
+        assert_eq!(map.len(), 10);
+        assert_eq!(map[&10], 10);
+    }
+}
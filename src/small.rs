@@ -0,0 +1,1041 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A hash map that keeps its first few entries inline.
+
+use self::Entry::*;
+
+use std::borrow::Borrow;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::iter::{FromIterator, IntoIterator};
+use std::mem::{self, replace};
+use std::ops::Index;
+use std::ptr;
+use std::slice;
+use std::collections::hash_state::HashState;
+
+use hashed::HashedTable;
+use table::{self, make_hash, SafeHash};
+use RandomState;
+
+/// Fixed-size arrays that can hold the inline entries of a `SmallHashMap`.
+///
+/// The array type picks the number of inline entries, e.g. `[(K, V); 8]`
+/// for eight. Rust can't be generic over the length of an array, so this
+/// trait stands in for a length parameter, and is only implemented for
+/// arrays of 0 to 32 elements.
+pub unsafe trait Array {
+    /// The element type.
+    type Item;
+
+    /// An array of hashes with the same length.
+    type Hashes: AsRef<[SafeHash]> + AsMut<[SafeHash]>;
+
+    /// Returns the number of elements.
+    fn size() -> usize;
+
+    /// Returns a pointer to the first element.
+    fn as_ptr(&self) -> *const Self::Item;
+
+    /// Returns a mutable pointer to the first element.
+    fn as_mut_ptr(&mut self) -> *mut Self::Item;
+}
+
+macro_rules! impl_array {
+    ($($size:expr),+) => {
+        $(
+            unsafe impl<T> Array for [T; $size] {
+                type Item = T;
+                type Hashes = [SafeHash; $size];
+                #[inline] fn size() -> usize { $size }
+                #[inline] fn as_ptr(&self) -> *const T { self[..].as_ptr() }
+                #[inline] fn as_mut_ptr(&mut self) -> *mut T { self[..].as_mut_ptr() }
+            }
+        )+
+    }
+}
+
+impl_array!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+            17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32);
+
+/// A hash map that stores up to `A::size()` entries inline, and moves them
+/// into a hash table once it grows past that.
+///
+/// While inline, entries are found by comparing the stored hashes, then the
+/// keys, one after the other. This is faster than probing a table for a
+/// handful of entries, and the map doesn't allocate at all.
+///
+/// The map stays in its table once it has spilled, even after removals;
+/// only `clear` brings it back inline.
+///
+/// The number of inline entries is given by an array type rather than a
+/// number, and is at most 32; see `Array`.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::SmallHashMap;
+///
+/// let mut map: SmallHashMap<&str, u32, [(&str, u32); 2]> = SmallHashMap::new();
+/// map.insert("a", 1);
+/// map.insert("b", 2);
+/// assert!(map.is_inline());
+///
+/// map.insert("c", 3);
+/// assert!(!map.is_inline());
+/// assert_eq!(map.get("a"), Some(&1));
+/// ```
+pub struct SmallHashMap<K, V, A: Array<Item=(K, V)>, S = RandomState> {
+    hash_state: S,
+    data: Data<K, V, A>,
+}
+
+enum Data<K, V, A: Array<Item=(K, V)>> {
+    Inline(Inline<A>),
+    Spilled(HashedTable<K, V>),
+}
+
+/// The inline entries, with their hashes in a parallel array. Only the
+/// first `len` elements of each array are initialized.
+struct Inline<A: Array> {
+    len: usize,
+    hashes: A::Hashes,
+    entries: NoDrop<A>,
+}
+
+/// Holds a value whose destructor never runs. `Inline` drops the entries
+/// it has initialized itself.
+///
+/// The value starts out uninitialized, so the variant must not be encoded
+/// in it, as it could be for an array of references. With an explicit tag
+/// the compiler can't do that.
+#[repr(u8)]
+enum NoDrop<A> {
+    Value(A),
+    Dropped,
+}
+
+impl<A> NoDrop<A> {
+    fn get(&self) -> &A {
+        match *self {
+            NoDrop::Value(ref a) => a,
+            NoDrop::Dropped => unreachable!(),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut A {
+        match *self {
+            NoDrop::Value(ref mut a) => a,
+            NoDrop::Dropped => unreachable!(),
+        }
+    }
+}
+
+impl<A> Drop for NoDrop<A> {
+    fn drop(&mut self) {
+        // Switch to the variant without fields, so that the array's
+        // destructor isn't run on uninitialized elements.
+        unsafe { ptr::write(self, NoDrop::Dropped) }
+    }
+}
+
+impl<A: Array> Inline<A> {
+    fn new() -> Inline<A> {
+        unsafe {
+            Inline {
+                len: 0,
+                hashes: mem::zeroed(),
+                entries: NoDrop::Value(mem::uninitialized()),
+            }
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == A::size()
+    }
+
+    fn entries(&self) -> &[A::Item] {
+        unsafe { slice::from_raw_parts(self.entries.get().as_ptr(), self.len) }
+    }
+
+    fn entries_mut(&mut self) -> &mut [A::Item] {
+        unsafe { slice::from_raw_parts_mut(self.entries.get_mut().as_mut_ptr(), self.len) }
+    }
+
+    /// Finds the first entry stored with `hash` that satisfies `is_match`.
+    fn position<F>(&self, hash: SafeHash, mut is_match: F) -> Option<usize>
+        where F: FnMut(&A::Item) -> bool
+    {
+        let hashes = &self.hashes.as_ref()[..self.len];
+        let entries = self.entries();
+        (0..self.len).position(|i| hashes[i] == hash && is_match(&entries[i]))
+    }
+
+    fn push(&mut self, hash: SafeHash, item: A::Item) -> &mut A::Item {
+        assert!(!self.is_full());
+        let i = self.len;
+        self.hashes.as_mut()[i] = hash;
+        unsafe {
+            let slot = self.entries.get_mut().as_mut_ptr().offset(i as isize);
+            ptr::write(slot, item);
+            self.len += 1;
+            &mut *slot
+        }
+    }
+
+    /// Removes the entry at `i`, moving the last entry into its place.
+    fn swap_remove(&mut self, i: usize) -> A::Item {
+        assert!(i < self.len);
+        self.len -= 1;
+        let last = self.len;
+        unsafe {
+            let base = self.entries.get_mut().as_mut_ptr();
+            let item = ptr::read(base.offset(i as isize));
+            if i != last {
+                ptr::copy_nonoverlapping(base.offset(last as isize), base.offset(i as isize), 1);
+                let hashes = self.hashes.as_mut();
+                hashes[i] = hashes[last];
+            }
+            item
+        }
+    }
+
+    fn retain<F>(&mut self, mut keep: F) -> usize
+        where F: FnMut(&mut A::Item) -> bool
+    {
+        let mut removed = 0;
+        let mut i = 0;
+        while i < self.len {
+            if keep(&mut self.entries_mut()[i]) {
+                i += 1;
+            } else {
+                // The last entry moves into this slot; look at it next.
+                self.swap_remove(i);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn pop(&mut self) -> Option<A::Item> {
+        self.pop_hashed().map(|(_, item)| item)
+    }
+
+    /// Removes the last entry, and returns it with its hash.
+    fn pop_hashed(&mut self) -> Option<(SafeHash, A::Item)> {
+        if self.len == 0 {
+            None
+        } else {
+            let last = self.len - 1;
+            let hash = self.hashes.as_ref()[last];
+            Some((hash, self.swap_remove(last)))
+        }
+    }
+}
+
+impl<A: Array> Drop for Inline<A> {
+    fn drop(&mut self) {
+        while let Some(_) = self.pop() {}
+    }
+}
+
+impl<K, V, A> SmallHashMap<K, V, A, RandomState>
+    where K: Eq + Hash, A: Array<Item=(K, V)>
+{
+    /// Creates an empty `SmallHashMap`. It doesn't allocate until it holds
+    /// more than `A::size()` entries.
+    #[inline]
+    pub fn new() -> SmallHashMap<K, V, A, RandomState> {
+        Default::default()
+    }
+}
+
+impl<K, V, A, S> SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    /// Creates an empty `SmallHashMap` which will use the given hash state
+    /// to hash keys.
+    #[inline]
+    pub fn with_hash_state(hash_state: S) -> SmallHashMap<K, V, A, S> {
+        SmallHashMap {
+            hash_state: hash_state,
+            data: Data::Inline(Inline::new()),
+        }
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        match self.data {
+            Data::Inline(ref inline) => inline.len,
+            Data::Spilled(ref table) => table.len(),
+        }
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Returns true if the entries are still stored inline.
+    pub fn is_inline(&self) -> bool {
+        match self.data {
+            Data::Inline(..) => true,
+            Data::Spilled(..) => false,
+        }
+    }
+
+    /// Reserves capacity for at least `additional` more elements. This
+    /// moves the entries into a table if they no longer fit inline.
+    pub fn reserve(&mut self, additional: usize) {
+        let new_size = self.len().checked_add(additional).expect("capacity overflow");
+        if new_size > A::size() {
+            self.spill();
+        }
+        if let Data::Spilled(ref mut table) = self.data {
+            table.reserve(additional);
+        }
+    }
+
+    /// Moves the inline entries into a table, along with their hashes.
+    fn spill(&mut self) {
+        let mut inline = match replace(&mut self.data, Data::Spilled(HashedTable::new())) {
+            Data::Inline(inline) => inline,
+            spilled => {
+                self.data = spilled;
+                return;
+            }
+        };
+        let mut table = HashedTable::new();
+        table.reserve(A::size() + 1);
+        while let Some((hash, (k, v))) = inline.pop_hashed() {
+            table.insert(hash, k, v);
+        }
+        self.data = Data::Spilled(table);
+    }
+
+    fn find<Q: ?Sized>(&self, hash: SafeHash, k: &Q) -> Option<(&K, &V)>
+        where K: Borrow<Q>, Q: Eq
+    {
+        match self.data {
+            Data::Inline(ref inline) => {
+                inline.position(hash, |&(ref key, _)| key.borrow() == k).map(|i| {
+                    let (ref key, ref value) = inline.entries()[i];
+                    (key, value)
+                })
+            }
+            Data::Spilled(ref table) => table.find(hash, |key| key.borrow() == k),
+        }
+    }
+
+    fn find_mut<Q: ?Sized>(&mut self, hash: SafeHash, k: &Q) -> Option<(&mut K, &mut V)>
+        where K: Borrow<Q>, Q: Eq
+    {
+        match self.data {
+            Data::Inline(ref mut inline) => {
+                match inline.position(hash, |&(ref key, _)| key.borrow() == k) {
+                    Some(i) => {
+                        let (ref mut key, ref mut value) = inline.entries_mut()[i];
+                        Some((key, value))
+                    }
+                    None => None,
+                }
+            }
+            Data::Spilled(ref mut table) => table.find_mut(hash, |key| key.borrow() == k),
+        }
+    }
+
+    fn remove_hashed<Q: ?Sized>(&mut self, hash: SafeHash, k: &Q) -> Option<(K, V)>
+        where K: Borrow<Q>, Q: Eq
+    {
+        match self.data {
+            Data::Inline(ref mut inline) => {
+                inline.position(hash, |&(ref key, _)| key.borrow() == k)
+                      .map(|i| inline.swap_remove(i))
+            }
+            Data::Spilled(ref mut table) => table.remove(hash, |key| key.borrow() == k),
+        }
+    }
+
+    /// Inserts an entry without checking for an existing one, spilling the
+    /// inline entries if they are full.
+    fn insert_hashed_nocheck(&mut self, hash: SafeHash, k: K, v: V) -> &mut V {
+        let full = match self.data {
+            Data::Inline(ref inline) => inline.is_full(),
+            Data::Spilled(..) => false,
+        };
+        if full {
+            self.spill();
+        }
+        match self.data {
+            Data::Inline(ref mut inline) => &mut inline.push(hash, (k, v)).1,
+            Data::Spilled(ref mut table) => table.insert(hash, k, v),
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        self.find(hash, k).map(|(_, v)| v)
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get(k).is_some()
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        self.find_mut(hash, k).map(|(_, v)| v)
+    }
+
+    /// Inserts a key-value pair into the map, and returns the old value if
+    /// the key was present. The key is not updated.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let hash = make_hash(&self.hash_state, &k);
+        if let Some((_, old)) = self.find_mut(hash, &k) {
+            return Some(replace(old, v));
+        }
+        self.insert_hashed_nocheck(hash, k, v);
+        None
+    }
+
+    /// Removes a key from the map, returning the value at the key if the
+    /// key was previously in the map.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.take(k).map(|(_, v)| v)
+    }
+
+    /// Removes a key from the map, returning the stored key and value if
+    /// the key was previously in the map.
+    pub fn take<Q: ?Sized>(&mut self, k: &Q) -> Option<(K, V)>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        self.remove_hashed(hash, k)
+    }
+
+    /// Gets the given key's corresponding entry in the map for in-place
+    /// manipulation.
+    pub fn entry(&mut self, key: K) -> Entry<K, V, A, S> {
+        let hash = make_hash(&self.hash_state, &key);
+        if self.find(hash, &key).is_some() {
+            Occupied(OccupiedEntry { map: self, hash: hash, key: key })
+        } else {
+            Vacant(VacantEntry { map: self, hash: hash, key: key })
+        }
+    }
+
+    /// Retains only the elements specified by the predicate, and returns
+    /// the number of removed elements.
+    pub fn retain<F>(&mut self, mut keep: F) -> usize
+        where F: FnMut(&K, &mut V) -> bool
+    {
+        match self.data {
+            Data::Inline(ref mut inline) => inline.retain(|&mut (ref k, ref mut v)| keep(k, v)),
+            Data::Spilled(ref mut table) => table.retain(keep),
+        }
+    }
+
+    /// Clears the map, removing all key-value pairs. This frees the table,
+    /// if the map had spilled, and stores entries inline again.
+    pub fn clear(&mut self) {
+        self.data = Data::Inline(Inline::new());
+    }
+
+    /// Clears the map, returning all key-value pairs as an iterator. A map
+    /// that had spilled keeps its table for reuse.
+    pub fn drain(&mut self) -> Drain<K, V, A> {
+        Drain {
+            inner: match self.data {
+                Data::Inline(ref mut inline) => DrainInner::Inline(replace(inline, Inline::new())),
+                Data::Spilled(ref mut table) => DrainInner::Spilled(table.drain()),
+            }
+        }
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter {
+            inner: match self.data {
+                Data::Inline(ref inline) => IterInner::Inline(inline.entries().iter()),
+                Data::Spilled(ref table) => IterInner::Spilled(table.iter()),
+            }
+        }
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order, with
+    /// mutable references to the values.
+    pub fn iter_mut(&mut self) -> IterMut<K, V> {
+        IterMut {
+            inner: match self.data {
+                Data::Inline(ref mut inline) => IterMutInner::Inline(inline.entries_mut().iter_mut()),
+                Data::Spilled(ref mut table) => IterMutInner::Spilled(table.iter_mut()),
+            }
+        }
+    }
+
+    /// An iterator visiting all keys in arbitrary order.
+    pub fn keys(&self) -> Keys<K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// An iterator visiting all values in arbitrary order.
+    pub fn values(&self) -> Values<K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// An iterator visiting all values mutably in arbitrary order.
+    pub fn values_mut(&mut self) -> ValuesMut<K, V> {
+        ValuesMut { inner: self.iter_mut() }
+    }
+}
+
+impl<K, V, A, S> Clone for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash + Clone, V: Clone, A: Array<Item=(K, V)>, S: HashState + Clone
+{
+    fn clone(&self) -> SmallHashMap<K, V, A, S> {
+        let mut map = SmallHashMap::with_hash_state(self.hash_state.clone());
+        map.reserve(self.len());
+        for (k, v) in self.iter() {
+            let hash = make_hash(&map.hash_state, k);
+            map.insert_hashed_nocheck(hash, k.clone(), v.clone());
+        }
+        map
+    }
+}
+
+impl<K, V, A, S> PartialEq for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, V: PartialEq, A: Array<Item=(K, V)>, S: HashState
+{
+    fn eq(&self, other: &SmallHashMap<K, V, A, S>) -> bool {
+        if self.len() != other.len() { return false; }
+
+        self.iter().all(|(key, value)|
+            other.get(key).map_or(false, |v| *value == *v)
+        )
+    }
+}
+
+impl<K, V, A, S> Eq for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, V: Eq, A: Array<Item=(K, V)>, S: HashState
+{}
+
+impl<K, V, A, S> Debug for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash + Debug, V: Debug, A: Array<Item=(K, V)>, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K, V, A, S> Default for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState + Default
+{
+    fn default() -> SmallHashMap<K, V, A, S> {
+        SmallHashMap::with_hash_state(Default::default())
+    }
+}
+
+impl<'a, K, Q: ?Sized, V, A, S> Index<&'a Q> for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash + Borrow<Q>, Q: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    type Output = V;
+
+    #[inline]
+    fn index(&self, index: &Q) -> &V {
+        self.get(index).expect("no entry found for key")
+    }
+}
+
+impl<K, V, A, S> FromIterator<(K, V)> for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState + Default
+{
+    fn from_iter<T: IntoIterator<Item=(K, V)>>(iterable: T) -> SmallHashMap<K, V, A, S> {
+        let mut map = SmallHashMap::with_hash_state(Default::default());
+        map.extend(iterable);
+        map
+    }
+}
+
+impl<K, V, A, S> Extend<(K, V)> for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K, V, A, S> IntoIterator for SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, A>;
+
+    /// Creates a consuming iterator, that is, one that moves each key-value
+    /// pair out of the map in arbitrary order.
+    fn into_iter(self) -> IntoIter<K, V, A> {
+        IntoIter {
+            inner: match self.data {
+                Data::Inline(inline) => IntoIterInner::Inline(inline),
+                Data::Spilled(table) => IntoIterInner::Spilled(table.into_iter()),
+            }
+        }
+    }
+}
+
+impl<'a, K, V, A, S> IntoIterator for &'a SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K, V, A, S> IntoIterator for &'a mut SmallHashMap<K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+/// SmallHashMap iterator.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: IterInner<'a, K, V>,
+}
+
+enum IterInner<'a, K: 'a, V: 'a> {
+    Inline(slice::Iter<'a, (K, V)>),
+    Spilled(table::Iter<'a, K, V>),
+}
+
+// FIXME(#19839) Remove in favor of `#[derive(Clone)]`
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            inner: match self.inner {
+                IterInner::Inline(ref iter) => IterInner::Inline(iter.clone()),
+                IterInner::Spilled(ref iter) => IterInner::Spilled(iter.clone()),
+            }
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        match self.inner {
+            IterInner::Inline(ref mut iter) => iter.next().map(|&(ref k, ref v)| (k, v)),
+            IterInner::Spilled(ref mut iter) => iter.next(),
+        }
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.inner {
+            IterInner::Inline(ref iter) => iter.size_hint(),
+            IterInner::Spilled(ref iter) => iter.size_hint(),
+        }
+    }
+}
+
+/// SmallHashMap mutable values iterator.
+pub struct IterMut<'a, K: 'a, V: 'a> {
+    inner: IterMutInner<'a, K, V>,
+}
+
+enum IterMutInner<'a, K: 'a, V: 'a> {
+    Inline(slice::IterMut<'a, (K, V)>),
+    Spilled(table::IterMut<'a, K, V>),
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        match self.inner {
+            IterMutInner::Inline(ref mut iter) => {
+                iter.next().map(|&mut (ref k, ref mut v)| (k, v))
+            }
+            IterMutInner::Spilled(ref mut iter) => iter.next(),
+        }
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.inner {
+            IterMutInner::Inline(ref iter) => iter.size_hint(),
+            IterMutInner::Spilled(ref iter) => iter.size_hint(),
+        }
+    }
+}
+
+/// SmallHashMap keys iterator.
+pub struct Keys<'a, K: 'a, V: 'a> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    #[inline] fn next(&mut self) -> Option<&'a K> { self.inner.next().map(|(k, _)| k) }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+/// SmallHashMap values iterator.
+pub struct Values<'a, K: 'a, V: 'a> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    #[inline] fn next(&mut self) -> Option<&'a V> { self.inner.next().map(|(_, v)| v) }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+/// SmallHashMap mutable values iterator.
+pub struct ValuesMut<'a, K: 'a, V: 'a> {
+    inner: IterMut<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+
+    #[inline] fn next(&mut self) -> Option<&'a mut V> { self.inner.next().map(|(_, v)| v) }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+/// SmallHashMap move iterator.
+pub struct IntoIter<K, V, A: Array<Item=(K, V)>> {
+    inner: IntoIterInner<K, V, A>,
+}
+
+enum IntoIterInner<K, V, A: Array<Item=(K, V)>> {
+    Inline(Inline<A>),
+    Spilled(table::IntoIter<K, V>),
+}
+
+impl<K, V, A: Array<Item=(K, V)>> Iterator for IntoIter<K, V, A> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        match self.inner {
+            IntoIterInner::Inline(ref mut inline) => inline.pop(),
+            IntoIterInner::Spilled(ref mut iter) => iter.next().map(|(_, k, v)| (k, v)),
+        }
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.inner {
+            IntoIterInner::Inline(ref inline) => (inline.len, Some(inline.len)),
+            IntoIterInner::Spilled(ref iter) => iter.size_hint(),
+        }
+    }
+}
+
+/// SmallHashMap drain iterator.
+pub struct Drain<'a, K: 'a, V: 'a, A: Array<Item=(K, V)>> {
+    inner: DrainInner<'a, K, V, A>,
+}
+
+enum DrainInner<'a, K: 'a, V: 'a, A: Array<Item=(K, V)>> {
+    // The entries were already moved out of the map.
+    Inline(Inline<A>),
+    Spilled(table::Drain<'a, K, V>),
+}
+
+impl<'a, K, V, A: Array<Item=(K, V)>> Iterator for Drain<'a, K, V, A> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        match self.inner {
+            DrainInner::Inline(ref mut inline) => inline.pop(),
+            DrainInner::Spilled(ref mut iter) => iter.next().map(|(_, k, v)| (k, v)),
+        }
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.inner {
+            DrainInner::Inline(ref inline) => (inline.len, Some(inline.len)),
+            DrainInner::Spilled(ref iter) => iter.size_hint(),
+        }
+    }
+}
+
+/// A view into a single location in a map, which may be vacant or occupied.
+pub enum Entry<'a, K: 'a, V: 'a, A: 'a + Array<Item=(K, V)>, S: 'a> {
+    /// An occupied Entry.
+    Occupied(OccupiedEntry<'a, K, V, A, S>),
+
+    /// A vacant Entry.
+    Vacant(VacantEntry<'a, K, V, A, S>),
+}
+
+/// A view into a single occupied location in a SmallHashMap.
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, A: 'a + Array<Item=(K, V)>, S: 'a> {
+    map: &'a mut SmallHashMap<K, V, A, S>,
+    hash: SafeHash,
+
+    // Finds the entry again, since spilled entries have no fixed position.
+    key: K,
+}
+
+/// A view into a single empty location in a SmallHashMap.
+pub struct VacantEntry<'a, K: 'a, V: 'a, A: 'a + Array<Item=(K, V)>, S: 'a> {
+    map: &'a mut SmallHashMap<K, V, A, S>,
+    hash: SafeHash,
+    key: K,
+}
+
+impl<'a, K, V, A, S> Entry<'a, K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    /// Returns the entry key
+    pub fn key(&self) -> &K {
+        match *self {
+            Occupied(ref entry) => entry.key(),
+            Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the default if empty, and returns
+    /// a mutable reference to the value in the entry.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// and returns a mutable reference to the value in the entry.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.insert(default()),
+        }
+    }
+}
+
+impl<'a, K, V, A, S> OccupiedEntry<'a, K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    /// Gets a reference to the entry key
+    pub fn key(&self) -> &K {
+        self.map.find(self.hash, &self.key).unwrap().0
+    }
+
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &V {
+        self.map.find(self.hash, &self.key).unwrap().1
+    }
+
+    /// Gets a mutable reference to the value in the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        self.map.find_mut(self.hash, &self.key).unwrap().1
+    }
+
+    /// Converts the OccupiedEntry into a mutable reference to the value in the entry
+    /// with a lifetime bound to the map itself
+    pub fn into_mut(self) -> &'a mut V {
+        let OccupiedEntry { map, hash, key } = self;
+        map.find_mut(hash, &key).unwrap().1
+    }
+
+    /// Sets the value of the entry, and returns the entry's old value
+    pub fn insert(&mut self, value: V) -> V {
+        replace(self.get_mut(), value)
+    }
+
+    /// Takes the value out of the entry, and returns it
+    pub fn remove(self) -> V {
+        self.map.remove_hashed(self.hash, &self.key).unwrap().1
+    }
+}
+
+impl<'a, K, V, A, S> VacantEntry<'a, K, V, A, S>
+    where K: Eq + Hash, A: Array<Item=(K, V)>, S: HashState
+{
+    /// Gets a reference to the entry key
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Sets the value of the entry with the VacantEntry's key,
+    /// and returns a mutable reference to it
+    pub fn insert(self, value: V) -> &'a mut V {
+        self.map.insert_hashed_nocheck(self.hash, self.key, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::SmallHashMap;
+    use super::Entry::{Occupied, Vacant};
+
+    type Small<K, V> = SmallHashMap<K, V, [(K, V); 4]>;
+
+    #[test]
+    fn test_spill() {
+        let mut map: Small<u32, u32> = SmallHashMap::new();
+        for i in 0..4 {
+            assert_eq!(map.insert(i, i * 10), None);
+        }
+        assert!(map.is_inline());
+        assert_eq!(map.insert(2, 21), Some(20));
+        assert_eq!(map.remove(&3), Some(30));
+        assert_eq!(map.insert(3, 31), None);
+        assert!(map.is_inline());
+
+        assert_eq!(map.insert(4, 40), None);
+        assert!(!map.is_inline());
+        assert_eq!(map.len(), 5);
+        for i in 0..5 {
+            assert!(map.contains_key(&i));
+        }
+        assert_eq!(map[&2], 21);
+        assert_eq!(map.take(&2), Some((2, 21)));
+        assert_eq!(map.len(), 4);
+
+        map.clear();
+        assert!(map.is_inline());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_spill_keeps_hashes() {
+        use test_map::CountingState;
+
+        let hashes = Rc::new(Cell::new(0));
+        let mut map: SmallHashMap<u32, u32, [(u32, u32); 4], _> =
+            SmallHashMap::with_hash_state(CountingState(hashes.clone()));
+        for i in 0..5 {
+            map.insert(i, i);
+        }
+        assert!(!map.is_inline());
+        assert_eq!(hashes.get(), 5);
+        for i in 0..5 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut map: Small<&str, u32> = SmallHashMap::new();
+        for (i, word) in "a b c a b a d e a".split(' ').enumerate() {
+            *map.entry(word).or_insert(0) += 1;
+            assert_eq!(map.is_inline(), i < 7);
+        }
+        assert_eq!(map["a"], 4);
+
+        match map.entry("b") {
+            Occupied(mut e) => {
+                assert_eq!(e.insert(7), 2);
+                assert_eq!(e.remove(), 7);
+            }
+            Vacant(..) => panic!(),
+        }
+        match map.entry("z") {
+            Occupied(..) => panic!(),
+            Vacant(e) => assert_eq!(*e.insert(26), 26),
+        }
+        let mut keys: Vec<_> = map.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "c", "d", "e", "z"]);
+    }
+
+    #[test]
+    fn test_boxed_keys() {
+        // Boxes and references can't be null, which the compiler could use
+        // to store the state of the inline array.
+        let mut map: Small<Box<u32>, &str> = SmallHashMap::new();
+        for i in 0..4 {
+            map.insert(Box::new(i), "inline");
+        }
+        assert_eq!(map.get(&2), Some(&"inline"));
+        map.insert(Box::new(4), "spilled");
+        assert_eq!(map.get(&4), Some(&"spilled"));
+        assert_eq!(map.remove(&0), Some("inline"));
+        assert_eq!(map.len(), 4);
+    }
+
+    #[test]
+    fn test_retain_drain_into_iter() {
+        for &n in &[4, 10] {
+            let mut map: Small<u32, u32> = (0..n).map(|i| (i, i)).collect();
+            assert_eq!(map.retain(|&k, v| { *v += 1; k % 2 == 0 }), n as usize / 2);
+            for v in map.values_mut() {
+                *v *= 10;
+            }
+            let mut entries: Vec<_> = map.drain().collect();
+            entries.sort();
+            assert_eq!(entries, (0..n).filter(|i| i % 2 == 0).map(|i| (i, (i + 1) * 10))
+                                      .collect::<Vec<_>>());
+            assert!(map.is_empty());
+
+            map.extend((0..n).map(|i| (i, i)));
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort();
+            assert_eq!(entries, (0..n).map(|i| (i, i)).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_drops() {
+        struct Counted(Rc<Cell<usize>>);
+
+        impl Drop for Counted {
+            fn drop(&mut self) { self.0.set(self.0.get() + 1); }
+        }
+
+        let drops = Rc::new(Cell::new(0));
+        {
+            let mut map: Small<u32, Counted> = SmallHashMap::new();
+            for i in 0..3 {
+                map.insert(i, Counted(drops.clone()));
+            }
+            map.remove(&0);
+            assert_eq!(drops.get(), 1);
+        }
+        assert_eq!(drops.get(), 3);
+
+        {
+            let mut map: Small<u32, Counted> = SmallHashMap::new();
+            for i in 0..6 {
+                map.insert(i, Counted(drops.clone()));
+            }
+            for (_, v) in map.iter_mut() {
+                *v = Counted(v.0.clone());
+            }
+            assert_eq!(drops.get(), 9);
+        }
+        assert_eq!(drops.get(), 15);
+    }
+}
@@ -0,0 +1,571 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A hash map with cheap, copy-on-write snapshots.
+
+use std::borrow::Borrow;
+use std::cmp::max;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::iter::IntoIterator;
+use std::mem::{replace, swap};
+use std::slice;
+use std::sync::Arc;
+use std::collections::hash_state::HashState;
+
+use table::{make_hash, SafeHash};
+use {DefaultResizePolicy, RandomState};
+
+/// The number of buckets in a chunk, which is the unit of copying.
+const CHUNK_SIZE: usize = 64;
+
+type Slot<K, V> = Option<(SafeHash, K, V)>;
+type Chunk<K, V> = Vec<Slot<K, V>>;
+
+/// A hash map whose `snapshot` takes a read-only view in constant time.
+///
+/// The buckets are split into chunks of 64, each behind its own reference
+/// count, and the list of chunks is shared as well. A snapshot shares both
+/// with the map. The first write after a snapshot copies the list of chunk
+/// pointers, and each write copies the chunks it changes, if they are
+/// still shared. Rows of buckets that aren't written stay shared, and a
+/// chunk is freed once neither the map nor any snapshot uses it.
+///
+/// Writes need `K: Clone` and `V: Clone`, to copy shared chunks. Values
+/// that are expensive to clone can be wrapped in an `Arc`.
+///
+/// # Examples
+///
+/// ```
+/// use hashmap2::SnapshotHashMap;
+///
+/// let mut map = SnapshotHashMap::new();
+/// map.insert("a", 1);
+///
+/// let snapshot = map.snapshot();
+/// map.insert("a", 2);
+/// map.insert("b", 3);
+///
+/// assert_eq!(snapshot.get("a"), Some(&1));
+/// assert_eq!(snapshot.get("b"), None);
+/// assert_eq!(map.get("a"), Some(&2));
+/// ```
+pub struct SnapshotHashMap<K, V, S = RandomState> {
+    hash_state: Arc<S>,
+    table: Table<K, V>,
+    resize_policy: DefaultResizePolicy,
+}
+
+/// A read-only view of a `SnapshotHashMap` at the time it was taken.
+///
+/// Snapshots are cheap to clone, and can be sent to other threads when the
+/// keys, values and hash state can.
+pub struct Snapshot<K, V, S = RandomState> {
+    hash_state: Arc<S>,
+    table: Table<K, V>,
+}
+
+/// A Robin Hood table over chunked buckets, shared between a map and its
+/// snapshots.
+struct Table<K, V> {
+    chunks: Arc<Vec<Arc<Chunk<K, V>>>>,
+    size: usize,
+}
+
+impl<K, V> Clone for Table<K, V> {
+    fn clone(&self) -> Table<K, V> {
+        Table {
+            chunks: self.chunks.clone(),
+            size: self.size,
+        }
+    }
+}
+
+impl<K, V> Table<K, V> {
+    fn new(capacity: usize) -> Table<K, V> {
+        let chunks = (0..capacity / CHUNK_SIZE)
+            .map(|_| Arc::new((0..CHUNK_SIZE).map(|_| None).collect()))
+            .collect();
+        Table {
+            chunks: Arc::new(chunks),
+            size: 0,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.chunks.len() * CHUNK_SIZE
+    }
+
+    fn slot(&self, index: usize) -> &Slot<K, V> {
+        &self.chunks[index / CHUNK_SIZE][index % CHUNK_SIZE]
+    }
+
+    /// The distance from a bucket to the ideal bucket of a hash.
+    fn displacement(&self, index: usize, hash: SafeHash) -> usize {
+        let mask = self.capacity() - 1;
+        index.wrapping_sub(hash.inspect() as usize) & mask
+    }
+
+    /// Returns the index of the bucket holding the key.
+    fn search<Q: ?Sized>(&self, hash: SafeHash, k: &Q) -> Option<usize>
+        where K: Borrow<Q>, Q: Eq
+    {
+        let cap = self.capacity();
+        if cap == 0 {
+            return None;
+        }
+
+        let mut index = hash.inspect() as usize & (cap - 1);
+        for distance in 0..cap {
+            match *self.slot(index) {
+                None => return None,
+                Some((h, ref key, _)) => {
+                    if self.displacement(index, h) < distance {
+                        return None;
+                    }
+                    if h == hash && k.eq(key.borrow()) {
+                        return Some(index);
+                    }
+                }
+            }
+            index = (index + 1) & (cap - 1);
+        }
+        None
+    }
+
+    fn get<Q: ?Sized>(&self, hash: SafeHash, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Eq
+    {
+        self.search(hash, k).map(|index| match *self.slot(index) {
+            Some((_, _, ref value)) => value,
+            None => unreachable!(),
+        })
+    }
+
+    fn iter(&self) -> Iter<K, V> {
+        Iter {
+            chunks: self.chunks.iter(),
+            slots: [].iter(),
+            remaining: self.size,
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> Table<K, V> {
+    /// Returns a bucket for writing, copying its chunk first if a snapshot
+    /// shares it.
+    fn slot_mut(&mut self, index: usize) -> &mut Slot<K, V> {
+        let chunks = Arc::make_mut(&mut self.chunks);
+        &mut Arc::make_mut(&mut chunks[index / CHUNK_SIZE])[index % CHUNK_SIZE]
+    }
+
+    /// Inserts an entry with Robin Hood hashing, without comparing it to any
+    /// other entry. There must be room for it.
+    fn insert_hashed(&mut self, hash: SafeHash, k: K, v: V) {
+        let mask = self.capacity() - 1;
+        let mut index = hash.inspect() as usize & mask;
+        let mut distance = 0;
+        let mut entry = (hash, k, v);
+        self.size += 1;
+
+        loop {
+            let steal = match *self.slot(index) {
+                None => {
+                    *self.slot_mut(index) = Some(entry);
+                    return;
+                }
+                Some((h, _, _)) => self.displacement(index, h) < distance,
+            };
+            if steal {
+                // Found a luckier entry. Take its bucket, and carry on
+                // placing it instead.
+                if let Some(ref mut resident) = *self.slot_mut(index) {
+                    swap(resident, &mut entry);
+                }
+                distance = self.displacement(index, entry.0);
+            }
+            index = (index + 1) & mask;
+            distance += 1;
+        }
+    }
+
+    /// Removes the entry at `index`, shifting the entries after it back.
+    fn remove_at(&mut self, index: usize) -> (K, V) {
+        let mask = self.capacity() - 1;
+        let (_, k, v) = self.slot_mut(index).take().unwrap();
+        self.size -= 1;
+
+        let mut gap = index;
+        loop {
+            let next = (gap + 1) & mask;
+            match *self.slot(next) {
+                Some((h, _, _)) if self.displacement(next, h) > 0 => {}
+                _ => break,
+            }
+            let entry = self.slot_mut(next).take();
+            *self.slot_mut(gap) = entry;
+            gap = next;
+        }
+        (k, v)
+    }
+
+    /// Takes the entries out of the table, moving them out of the chunks
+    /// that no snapshot shares and cloning the rest.
+    fn into_entries(self) -> Vec<(SafeHash, K, V)> {
+        let mut entries = Vec::with_capacity(self.size);
+        let chunks = match Arc::try_unwrap(self.chunks) {
+            Ok(chunks) => chunks,
+            Err(shared) => (*shared).clone(),
+        };
+        for chunk in chunks {
+            match Arc::try_unwrap(chunk) {
+                Ok(slots) => entries.extend(slots.into_iter().filter_map(|slot| slot)),
+                Err(shared) => entries.extend(shared.iter().filter_map(|slot| slot.clone())),
+            }
+        }
+        entries
+    }
+}
+
+impl<K: Hash + Eq, V> SnapshotHashMap<K, V, RandomState> {
+    /// Creates an empty `SnapshotHashMap`.
+    #[inline]
+    pub fn new() -> SnapshotHashMap<K, V, RandomState> {
+        Default::default()
+    }
+}
+
+impl<K, V, S> SnapshotHashMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Creates an empty `SnapshotHashMap` which will use the given hash
+    /// state to hash keys.
+    #[inline]
+    pub fn with_hash_state(hash_state: S) -> SnapshotHashMap<K, V, S> {
+        SnapshotHashMap {
+            hash_state: Arc::new(hash_state),
+            table: Table::new(0),
+            resize_policy: DefaultResizePolicy::new(),
+        }
+    }
+
+    /// Returns a read-only view of the map as it is now. This takes
+    /// constant time.
+    pub fn snapshot(&self) -> Snapshot<K, V, S> {
+        Snapshot {
+            hash_state: self.hash_state.clone(),
+            table: self.table.clone(),
+        }
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize { self.table.size }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Returns the number of elements the map can hold without growing.
+    pub fn capacity(&self) -> usize {
+        self.resize_policy.usable_capacity(self.table.capacity())
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.table.get(make_hash(&*self.hash_state, k), k)
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get(k).is_some()
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    pub fn iter(&self) -> Iter<K, V> {
+        self.table.iter()
+    }
+
+    /// Clears the map, removing all key-value pairs. Snapshots keep their
+    /// contents.
+    pub fn clear(&mut self) {
+        self.table = Table::new(0);
+    }
+}
+
+impl<K, V, S> SnapshotHashMap<K, V, S>
+    where K: Eq + Hash + Clone, V: Clone, S: HashState
+{
+    /// Returns a mutable reference to the value corresponding to the key.
+    /// This copies the bucket's chunk if a snapshot shares it.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&*self.hash_state, k);
+        match self.table.search(hash, k) {
+            Some(index) => match *self.table.slot_mut(index) {
+                Some((_, _, ref mut value)) => Some(value),
+                None => unreachable!(),
+            },
+            None => None,
+        }
+    }
+
+    /// Inserts a key-value pair into the map, and returns the old value if
+    /// the key was present. The key is not updated.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let hash = make_hash(&*self.hash_state, &k);
+        if let Some(index) = self.table.search(hash, &k) {
+            if let Some((_, _, ref mut value)) = *self.table.slot_mut(index) {
+                return Some(replace(value, v));
+            }
+        }
+
+        self.reserve(1);
+        self.table.insert_hashed(hash, k, v);
+        None
+    }
+
+    /// Removes a key from the map, returning the value at the key if the
+    /// key was previously in the map.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&*self.hash_state, k);
+        self.table.search(hash, k).map(|index| self.table.remove_at(index).1)
+    }
+
+    /// Reserves capacity for at least `additional` more elements. Growing
+    /// the map copies the entries that snapshots still share, and moves the
+    /// others.
+    pub fn reserve(&mut self, additional: usize) {
+        let new_size = self.len().checked_add(additional).expect("capacity overflow");
+        let min_cap = self.resize_policy.min_capacity(new_size);
+        if self.table.capacity() >= min_cap {
+            return;
+        }
+
+        let new_capacity = max(min_cap.next_power_of_two(), CHUNK_SIZE);
+        let old_table = replace(&mut self.table, Table::new(new_capacity));
+        for (hash, k, v) in old_table.into_entries() {
+            self.table.insert_hashed(hash, k, v);
+        }
+    }
+}
+
+impl<K, V, S> Debug for SnapshotHashMap<K, V, S>
+    where K: Eq + Hash + Debug, V: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K, V, S> Default for SnapshotHashMap<K, V, S>
+    where K: Eq + Hash, S: HashState + Default
+{
+    fn default() -> SnapshotHashMap<K, V, S> {
+        SnapshotHashMap::with_hash_state(Default::default())
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a SnapshotHashMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<K, V, S> Snapshot<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Returns the number of elements in the snapshot.
+    pub fn len(&self) -> usize { self.table.size }
+
+    /// Returns true if the snapshot contains no elements.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.table.get(make_hash(&*self.hash_state, k), k)
+    }
+
+    /// Returns true if the snapshot contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get(k).is_some()
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    pub fn iter(&self) -> Iter<K, V> {
+        self.table.iter()
+    }
+}
+
+impl<K, V, S> Clone for Snapshot<K, V, S> {
+    fn clone(&self) -> Snapshot<K, V, S> {
+        Snapshot {
+            hash_state: self.hash_state.clone(),
+            table: self.table.clone(),
+        }
+    }
+}
+
+impl<K, V, S> Debug for Snapshot<K, V, S>
+    where K: Eq + Hash + Debug, V: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a Snapshot<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// Iterator over a SnapshotHashMap or a Snapshot.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    chunks: slice::Iter<'a, Arc<Chunk<K, V>>>,
+    slots: slice::Iter<'a, Slot<K, V>>,
+    remaining: usize,
+}
+
+// FIXME(#19839) Remove in favor of `#[derive(Clone)]`
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            chunks: self.chunks.clone(),
+            slots: self.slots.clone(),
+            remaining: self.remaining,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            match self.slots.next() {
+                Some(&Some((_, ref k, ref v))) => {
+                    self.remaining -= 1;
+                    return Some((k, v));
+                }
+                Some(&None) => {}
+                None => self.slots = self.chunks.next().unwrap().iter(),
+            }
+        }
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
+    #[inline] fn len(&self) -> usize { self.remaining }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::thread;
+
+    use super::SnapshotHashMap;
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let mut map = SnapshotHashMap::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        let before = map.snapshot();
+
+        for i in 0..1000 {
+            if i % 2 == 0 {
+                map.remove(&i);
+            } else {
+                *map.get_mut(&i).unwrap() += 1;
+            }
+        }
+        for i in 1000..3000 {
+            map.insert(i, i);
+        }
+        let after = map.snapshot();
+        map.clear();
+
+        assert_eq!(before.len(), 1000);
+        for i in 0..1000 {
+            assert_eq!(before.get(&i), Some(&i));
+        }
+        assert_eq!(before.iter().count(), 1000);
+
+        assert_eq!(after.len(), 2500);
+        for i in 0..3000 {
+            let expected = if i >= 1000 { Some(i) } else if i % 2 == 1 { Some(i + 1) } else { None };
+            assert_eq!(after.get(&i).cloned(), expected);
+        }
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_reclaims_snapshots() {
+        let value = Rc::new(1);
+        let mut map = SnapshotHashMap::new();
+        map.insert("a", value.clone());
+        for i in 0..100 {
+            map.insert(if i % 2 == 0 { "b" } else { "c" }, Rc::new(i));
+        }
+
+        let snapshot = map.snapshot();
+        assert_eq!(Rc::strong_count(&value), 2);
+        map.insert("a", Rc::new(2));
+        assert_eq!(Rc::strong_count(&value), 2);
+        assert_eq!(**snapshot.get("a").unwrap(), 1);
+
+        drop(snapshot);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn test_snapshot_across_threads() {
+        let mut map = SnapshotHashMap::new();
+        for i in 0..100u32 {
+            map.insert(i, i);
+        }
+        let snapshot = map.snapshot();
+        let reader = thread::spawn(move || {
+            (0..100u32).all(|i| snapshot.get(&i) == Some(&i))
+        });
+        for i in 0..100u32 {
+            map.insert(i, 0);
+        }
+        assert!(reader.join().unwrap());
+    }
+}
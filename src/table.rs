@@ -12,6 +12,7 @@ use alloc::heap::{allocate, deallocate, EMPTY};
 
 use std::cmp;
 use std::hash::{Hash, Hasher};
+use std::intrinsics::prefetch_read_data;
 use std::marker;
 use std::mem::{align_of, size_of};
 use std::mem;
@@ -59,19 +60,31 @@ const EMPTY_BUCKET: u64 = 0;
 /// around just the "table" part of the hashtable. It enforces some
 /// invariants at the type level and employs some performance trickery,
 /// but in general is just a tricked out `Vec<Option<u64, K, V>>`.
+///
+/// The arrangement of the arrays is a `Layout`. The description above is
+/// the default `SplitLayout`.
 #[unsafe_no_drop_flag]
-pub struct RawTable<K, V> {
+pub struct RawTable<K, V, L: Layout = SplitLayout> {
     capacity: usize,
     size:     usize,
     hashes:   Unique<u64>,
 
+    // No entry is further than this from its ideal bucket. Raised by every
+    // `put` and `replace`, but only lowered once the table is empty, since
+    // finding the new maximum after a removal would take a full scan.
+    max_displacement: usize,
+
+    // Set for tables in a caller's buffer, which must never be freed, even
+    // while unwinding from a panicking destructor.
+    borrowed: bool,
+
     // Because K/V do not appear directly in any of the types in the struct,
     // inform rustc that in fact instances of K and V are reachable from here.
-    marker:   marker::PhantomData<(K,V)>,
+    marker:   marker::PhantomData<(K,V,L)>,
 }
 
-unsafe impl<K: Send, V: Send> Send for RawTable<K, V> {}
-unsafe impl<K: Sync, V: Sync> Sync for RawTable<K, V> {}
+unsafe impl<K: Send, V: Send, L: Layout> Send for RawTable<K, V, L> {}
+unsafe impl<K: Sync, V: Sync, L: Layout> Sync for RawTable<K, V, L> {}
 
 struct RawBucket<K, V> {
     hash: *mut u64,
@@ -108,11 +121,15 @@ pub struct FullBucket<K, V, M> {
     table: M
 }
 
-pub type EmptyBucketImm<'table, K, V> = EmptyBucket<K, V, &'table RawTable<K, V>>;
-pub type  FullBucketImm<'table, K, V> =  FullBucket<K, V, &'table RawTable<K, V>>;
+pub type EmptyBucketImm<'table, K, V, L = SplitLayout> =
+    EmptyBucket<K, V, &'table RawTable<K, V, L>>;
+pub type  FullBucketImm<'table, K, V, L = SplitLayout> =
+     FullBucket<K, V, &'table RawTable<K, V, L>>;
 
-pub type EmptyBucketMut<'table, K, V> = EmptyBucket<K, V, &'table mut RawTable<K, V>>;
-pub type  FullBucketMut<'table, K, V> =  FullBucket<K, V, &'table mut RawTable<K, V>>;
+pub type EmptyBucketMut<'table, K, V, L = SplitLayout> =
+    EmptyBucket<K, V, &'table mut RawTable<K, V, L>>;
+pub type  FullBucketMut<'table, K, V, L = SplitLayout> =
+     FullBucket<K, V, &'table mut RawTable<K, V, L>>;
 
 pub enum BucketState<K, V, M> {
     Empty(EmptyBucket<K, V, M>),
@@ -170,11 +187,13 @@ fn can_alias_safehash_as_u64() {
 }
 
 impl<K, V> RawBucket<K, V> {
-    unsafe fn offset(self, count: isize) -> RawBucket<K, V> {
+    /// Moves `count` buckets along, with the strides of the layout `L`.
+    unsafe fn offset<L: Layout>(self, count: isize) -> RawBucket<K, V> {
+        let (hash_stride, key_stride, val_stride) = L::strides::<K, V>();
         RawBucket {
-            hash: self.hash.offset(count),
-            key:  self.key.offset(count),
-            val:  self.val.offset(count),
+            hash: (self.hash as *mut u8).offset(count * hash_stride as isize) as *mut u64,
+            key:  (self.key as *mut u8).offset(count * key_stride as isize) as *mut K,
+            val:  (self.val as *mut u8).offset(count * val_stride as isize) as *mut V,
             _marker: marker::PhantomData,
         }
     }
@@ -218,7 +237,7 @@ impl<K, V, M> Bucket<K, V, M> {
     }
 }
 
-impl<K, V, M: Deref<Target=RawTable<K, V>>> Bucket<K, V, M> {
+impl<K, V, L: Layout, M: Deref<Target=RawTable<K, V, L>>> Bucket<K, V, M> {
     pub fn new(table: M, hash: SafeHash) -> Bucket<K, V, M> {
         Bucket::at_index(table, hash.inspect() as usize)
     }
@@ -230,7 +249,7 @@ impl<K, V, M: Deref<Target=RawTable<K, V>>> Bucket<K, V, M> {
         let ib_index = ib_index & (table.capacity() - 1);
         Bucket {
             raw: unsafe {
-               table.first_bucket_raw().offset(ib_index as isize)
+               table.first_bucket_raw().offset::<L>(ib_index as isize)
             },
             idx: ib_index,
             table: table
@@ -285,12 +304,12 @@ impl<K, V, M: Deref<Target=RawTable<K, V>>> Bucket<K, V, M> {
         self.idx += 1;
 
         unsafe {
-            self.raw = self.raw.offset(dist);
+            self.raw = self.raw.offset::<L>(dist);
         }
     }
 }
 
-impl<K, V, M: Deref<Target=RawTable<K, V>>> EmptyBucket<K, V, M> {
+impl<K, V, L: Layout, M: Deref<Target=RawTable<K, V, L>>> EmptyBucket<K, V, M> {
     #[inline]
     pub fn next(self) -> Bucket<K, V, M> {
         let mut bucket = self.into_bucket();
@@ -326,7 +345,7 @@ impl<K, V, M: Deref<Target=RawTable<K, V>>> EmptyBucket<K, V, M> {
     }
 }
 
-impl<K, V, M: Deref<Target=RawTable<K, V>> + DerefMut> EmptyBucket<K, V, M> {
+impl<K, V, L: Layout, M: Deref<Target=RawTable<K, V, L>> + DerefMut> EmptyBucket<K, V, M> {
     /// Puts given key and value pair, along with the key's hash,
     /// into this bucket in the hashtable. Note how `self` is 'moved' into
     /// this function, because this slot will no longer be empty when
@@ -343,12 +362,13 @@ impl<K, V, M: Deref<Target=RawTable<K, V>> + DerefMut> EmptyBucket<K, V, M> {
         }
 
         self.table.size += 1;
+        self.table.note_displacement(self.idx, hash);
 
         FullBucket { raw: self.raw, idx: self.idx, table: self.table }
     }
 }
 
-impl<K, V, M: Deref<Target=RawTable<K, V>>> FullBucket<K, V, M> {
+impl<K, V, L: Layout, M: Deref<Target=RawTable<K, V, L>>> FullBucket<K, V, M> {
     #[inline]
     pub fn next(self) -> Bucket<K, V, M> {
         let mut bucket = self.into_bucket();
@@ -395,13 +415,14 @@ impl<K, V, M: Deref<Target=RawTable<K, V>>> FullBucket<K, V, M> {
     }
 }
 
-impl<K, V, M: Deref<Target=RawTable<K, V>> + DerefMut> FullBucket<K, V, M> {
+impl<K, V, L: Layout, M: Deref<Target=RawTable<K, V, L>> + DerefMut> FullBucket<K, V, M> {
     /// Removes this bucket's key and value from the hashtable.
     ///
     /// This works similarly to `put`, building an `EmptyBucket` out of the
     /// taken bucket.
     pub fn take(mut self) -> (EmptyBucket<K, V, M>, K, V) {
         self.table.size -= 1;
+        self.table.note_removal();
 
         unsafe {
             *self.raw.hash = EMPTY_BUCKET;
@@ -422,6 +443,7 @@ impl<K, V, M: Deref<Target=RawTable<K, V>> + DerefMut> FullBucket<K, V, M> {
             let old_hash = ptr::replace(self.raw.hash as *mut SafeHash, h);
             let old_key  = ptr::replace(self.raw.key,  k);
             let old_val  = ptr::replace(self.raw.val,  v);
+            self.table.note_displacement(self.idx, h);
 
             (old_hash, old_key, old_val)
         }
@@ -436,7 +458,7 @@ impl<K, V, M: Deref<Target=RawTable<K, V>> + DerefMut> FullBucket<K, V, M> {
     }
 }
 
-impl<'t, K, V, M: Deref<Target=RawTable<K, V>> + 't> FullBucket<K, V, M> {
+impl<'t, K, V, L: Layout, M: Deref<Target=RawTable<K, V, L>> + 't> FullBucket<K, V, M> {
     /// Exchange a bucket state for immutable references into the table.
     /// Because the underlying reference to the table is also consumed,
     /// no further changes to the structure of the table are possible;
@@ -450,7 +472,7 @@ impl<'t, K, V, M: Deref<Target=RawTable<K, V>> + 't> FullBucket<K, V, M> {
     }
 }
 
-impl<'t, K, V, M: Deref<Target=RawTable<K, V>> + DerefMut + 't> FullBucket<K, V, M> {
+impl<'t, K, V, L: Layout, M: Deref<Target=RawTable<K, V, L>> + DerefMut + 't> FullBucket<K, V, M> {
     /// This works similarly to `into_refs`, exchanging a bucket state
     /// for mutable references into the table.
     pub fn into_mut_refs(self) -> (&'t mut K, &'t mut V) {
@@ -471,7 +493,7 @@ impl<K, V, M> BucketState<K, V, M> {
     }
 }
 
-impl<K, V, M: Deref<Target=RawTable<K, V>>> GapThenFull<K, V, M> {
+impl<K, V, L: Layout, M: Deref<Target=RawTable<K, V, L>>> GapThenFull<K, V, M> {
     #[inline]
     pub fn full(&self) -> &FullBucket<K, V, M> {
         &self.full
@@ -501,6 +523,186 @@ impl<K, V, M: Deref<Target=RawTable<K, V>>> GapThenFull<K, V, M> {
 }
 
 
+/// The arrangement of a table's hashes, keys and values in its single
+/// allocation.
+///
+/// The hash of the first bucket is at the start of the allocation. Each of
+/// the hashes, keys and values is found from the one of the previous bucket
+/// by adding a fixed stride.
+///
+/// # Safety
+///
+/// Implementations must place every hash, key and value of the first
+/// `capacity` buckets inside the allocation, aligned for its type, and
+/// without overlapping any other.
+pub unsafe trait Layout {
+    /// Returns the allocation for a table of `capacity` buckets, or `None`
+    /// if its size overflows.
+    fn allocation<K, V>(capacity: usize) -> Option<Allocation>;
+
+    /// Returns the offsets of the first key and the first value in the
+    /// allocation of a table of `capacity` buckets, which has already been
+    /// allocated. This is called on every lookup, so implementations should
+    /// override it with a cheaper computation. It must agree with
+    /// `allocation`.
+    #[inline]
+    fn offsets<K, V>(capacity: usize) -> (usize, usize) {
+        let allocation = Self::allocation::<K, V>(capacity).expect("capacity overflow");
+        (allocation.key_offset, allocation.val_offset)
+    }
+
+    /// Returns the distances in bytes between the hashes, the keys and the
+    /// values of consecutive buckets.
+    fn strides<K, V>() -> (usize, usize, usize);
+}
+
+/// The size and alignment of a table's allocation, and the offsets of the
+/// first key and the first value in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    pub size: usize,
+    pub align: usize,
+    pub key_offset: usize,
+    pub val_offset: usize,
+}
+
+/// Separate arrays of hashes, keys and values. Probing scans the hashes
+/// densely, but a successful lookup touches three cache lines. This is the
+/// default.
+pub enum SplitLayout {}
+
+/// One array of buckets, each holding a hash, a key and a value. A
+/// successful lookup touches a single bucket, but probing skips over the
+/// keys and values, and padding is paid in every bucket.
+pub enum InterleavedLayout {}
+
+/// An array of hashes paired with keys, followed by an array of values.
+/// Probing compares keys next to their hashes, and only the matching value
+/// is touched.
+pub enum HybridLayout {}
+
+unsafe impl Layout for SplitLayout {
+    fn allocation<K, V>(capacity: usize) -> Option<Allocation> {
+        let hashes_size = match capacity.checked_mul(size_of::<u64>()) {
+            Some(size) => size,
+            None => return None,
+        };
+        let keys_size = match capacity.checked_mul(size_of::<K>()) {
+            Some(size) => size,
+            None => return None,
+        };
+        let vals_size = match capacity.checked_mul(size_of::<V>()) {
+            Some(size) => size,
+            None => return None,
+        };
+
+        let (key_offset, val_offset, _) =
+            calculate_offsets(hashes_size, keys_size, align_of::<K>(), align_of::<V>());
+        let (align, _, size, oflo) =
+            calculate_allocation(hashes_size, align_of::<u64>(),
+                                 keys_size, align_of::<K>(),
+                                 vals_size, align_of::<V>());
+        if oflo {
+            return None;
+        }
+        Some(Allocation {
+            size: size,
+            align: align,
+            key_offset: key_offset,
+            val_offset: val_offset,
+        })
+    }
+
+    #[inline]
+    fn offsets<K, V>(capacity: usize) -> (usize, usize) {
+        let (key_offset, val_offset, _) =
+            calculate_offsets(capacity * size_of::<u64>(), capacity * size_of::<K>(),
+                              align_of::<K>(), align_of::<V>());
+        (key_offset, val_offset)
+    }
+
+    fn strides<K, V>() -> (usize, usize, usize) {
+        (size_of::<u64>(), size_of::<K>(), size_of::<V>())
+    }
+}
+
+/// Returns the offsets of the key and value in an interleaved bucket, and
+/// the size of the bucket.
+fn interleaved_bucket<K, V>() -> (usize, usize, usize) {
+    let align = cmp::max(align_of::<u64>(), cmp::max(align_of::<K>(), align_of::<V>()));
+    let key_offset = round_up_to_next(size_of::<u64>(), align_of::<K>());
+    let val_offset = round_up_to_next(key_offset + size_of::<K>(), align_of::<V>());
+    (key_offset, val_offset, round_up_to_next(val_offset + size_of::<V>(), align))
+}
+
+unsafe impl Layout for InterleavedLayout {
+    fn allocation<K, V>(capacity: usize) -> Option<Allocation> {
+        let (key_offset, val_offset, bucket_size) = interleaved_bucket::<K, V>();
+        capacity.checked_mul(bucket_size).map(|size| {
+            Allocation {
+                size: size,
+                align: cmp::max(align_of::<u64>(), cmp::max(align_of::<K>(), align_of::<V>())),
+                key_offset: key_offset,
+                val_offset: val_offset,
+            }
+        })
+    }
+
+    #[inline]
+    fn offsets<K, V>(_capacity: usize) -> (usize, usize) {
+        let (key_offset, val_offset, _) = interleaved_bucket::<K, V>();
+        (key_offset, val_offset)
+    }
+
+    fn strides<K, V>() -> (usize, usize, usize) {
+        let (_, _, bucket_size) = interleaved_bucket::<K, V>();
+        (bucket_size, bucket_size, bucket_size)
+    }
+}
+
+/// Returns the offset of the key in a hash and key pair, and the size of
+/// the pair.
+fn hybrid_pair<K>() -> (usize, usize) {
+    let align = cmp::max(align_of::<u64>(), align_of::<K>());
+    let key_offset = round_up_to_next(size_of::<u64>(), align_of::<K>());
+    (key_offset, round_up_to_next(key_offset + size_of::<K>(), align))
+}
+
+unsafe impl Layout for HybridLayout {
+    fn allocation<K, V>(capacity: usize) -> Option<Allocation> {
+        let (key_offset, pair_size) = hybrid_pair::<K>();
+        let pairs_size = match capacity.checked_mul(pair_size) {
+            Some(size) => size,
+            None => return None,
+        };
+        let vals_size = match capacity.checked_mul(size_of::<V>()) {
+            Some(size) => size,
+            None => return None,
+        };
+
+        let val_offset = round_up_to_next(pairs_size, align_of::<V>());
+        val_offset.checked_add(vals_size).map(|size| {
+            Allocation {
+                size: size,
+                align: cmp::max(align_of::<u64>(), cmp::max(align_of::<K>(), align_of::<V>())),
+                key_offset: key_offset,
+                val_offset: val_offset,
+            }
+        })
+    }
+
+    #[inline]
+    fn offsets<K, V>(capacity: usize) -> (usize, usize) {
+        let (key_offset, pair_size) = hybrid_pair::<K>();
+        (key_offset, round_up_to_next(capacity * pair_size, align_of::<V>()))
+    }
+
+    fn strides<K, V>() -> (usize, usize, usize) {
+        let (_, pair_size) = hybrid_pair::<K>();
+        (pair_size, pair_size, size_of::<V>())
+    }
+}
+
 /// Rounds up to a multiple of a power of two. Returns the closest multiple
 /// of `target_alignment` that is higher or equal to `unrounded`.
 ///
@@ -555,6 +757,27 @@ fn calculate_allocation(hash_size: usize, hash_align: usize,
     (align, hash_offset, end_of_vals, oflo || oflo2)
 }
 
+#[test]
+fn test_size_of_allocation() {
+    assert_eq!(RawTable::<u8, u32>::size_of_allocation(0), 0);
+    assert_eq!(RawTable::<u8, u32>::size_of_allocation(32), 32 * 8 + 32 + 32 * 4);
+    assert_eq!(RawTable::<u8, u64>::size_of_allocation(4), 4 * 8 + 4 + 4 + 4 * 8);
+}
+
+#[test]
+fn test_layout_allocation() {
+    assert_eq!(InterleavedLayout::allocation::<u8, u32>(32),
+               Some(Allocation { size: 32 * 16, align: 8, key_offset: 8, val_offset: 12 }));
+    assert_eq!(InterleavedLayout::strides::<u8, u32>(), (16, 16, 16));
+    assert_eq!(HybridLayout::allocation::<u8, u32>(32),
+               Some(Allocation { size: 32 * 16 + 32 * 4, align: 8, key_offset: 8,
+                                 val_offset: 32 * 16 }));
+    assert_eq!(HybridLayout::strides::<u8, u32>(), (16, 16, 4));
+    assert_eq!(SplitLayout::allocation::<u8, u32>(32).unwrap().size,
+               RawTable::<u8, u32>::size_of_allocation(32));
+    assert_eq!(InterleavedLayout::allocation::<u64, u64>(usize::max_value() / 8), None);
+}
+
 #[test]
 fn test_offset_calculation() {
     assert_eq!(calculate_allocation(128, 8, 15, 1, 4,  4), (8, 0, 148, false));
@@ -565,91 +788,143 @@ fn test_offset_calculation() {
     assert_eq!(calculate_offsets(6,   12, 4, 8), (8,   24, false));
 }
 
-impl<K, V> RawTable<K, V> {
+impl<K, V, L: Layout> RawTable<K, V, L> {
     /// Does not initialize the buckets. The caller should ensure they,
     /// at the very least, set every hash to EMPTY_BUCKET.
-    unsafe fn new_uninitialized(capacity: usize) -> RawTable<K, V> {
+    unsafe fn new_uninitialized(capacity: usize) -> RawTable<K, V, L> {
         if capacity == 0 {
             return RawTable {
                 size: 0,
                 capacity: 0,
                 hashes: Unique::new(EMPTY as *mut u64),
+                max_displacement: 0,
+                borrowed: false,
                 marker: marker::PhantomData,
             };
         }
 
-        // No need for `checked_mul` before a more restrictive check performed
-        // later in this method.
-        let hashes_size = capacity * size_of::<u64>();
-        let keys_size   = capacity * size_of::< K >();
-        let vals_size   = capacity * size_of::< V >();
-
         // Allocating hashmaps is a little tricky. We need to allocate three
         // arrays, but since we know their sizes and alignments up front,
         // we just allocate a single array, and then have the subarrays
         // point into it.
         //
         // This is great in theory, but in practice getting the alignment
-        // right is a little subtle. Therefore, calculating offsets has been
-        // factored out into a different function.
-        let (malloc_alignment, hash_offset, size, oflo) =
-            calculate_allocation(
-                hashes_size, align_of::<u64>(),
-                keys_size,   align_of::< K >(),
-                vals_size,   align_of::< V >());
-
-        assert!(!oflo, "capacity overflow");
+        // right is a little subtle. Therefore, the arrangement of the
+        // arrays has been factored out into the layout.
+        let allocation = L::allocation::<K, V>(capacity).expect("capacity overflow");
 
         // One check for overflow that covers calculation and rounding of size.
         let size_of_bucket = size_of::<u64>().checked_add(size_of::<K>()).unwrap()
                                              .checked_add(size_of::<V>()).unwrap();
-        assert!(size >= capacity.checked_mul(size_of_bucket)
-                                .expect("capacity overflow"),
+        assert!(allocation.size >= capacity.checked_mul(size_of_bucket)
+                                           .expect("capacity overflow"),
                 "capacity overflow");
 
-        let buffer = allocate(size, malloc_alignment);
+        let buffer = allocate(allocation.size, allocation.align);
         if buffer.is_null() { ::alloc::oom() }
 
-        let hashes = buffer.offset(hash_offset as isize) as *mut u64;
-
         RawTable {
             capacity: capacity,
             size:     0,
-            hashes:   Unique::new(hashes),
+            hashes:   Unique::new(buffer as *mut u64),
+            max_displacement: 0,
+            borrowed: false,
             marker:   marker::PhantomData,
         }
     }
 
     fn first_bucket_raw(&self) -> RawBucket<K, V> {
-        let hashes_size = self.capacity * size_of::<u64>();
-        let keys_size = self.capacity * size_of::<K>();
-
         let buffer = *self.hashes as *mut u8;
-        let (keys_offset, vals_offset, oflo) =
-            calculate_offsets(hashes_size,
-                              keys_size, align_of::<K>(),
-                              align_of::<V>());
-        debug_assert!(!oflo, "capacity overflow");
+        let (key_offset, val_offset) = L::offsets::<K, V>(self.capacity);
         unsafe {
             RawBucket {
                 hash: *self.hashes,
-                key:  buffer.offset(keys_offset as isize) as *mut K,
-                val:  buffer.offset(vals_offset as isize) as *mut V,
+                key:  buffer.offset(key_offset as isize) as *mut K,
+                val:  buffer.offset(val_offset as isize) as *mut V,
                 _marker: marker::PhantomData,
             }
         }
     }
 
+    /// Sets every hash to EMPTY_BUCKET.
+    unsafe fn clear_hashes(&mut self) {
+        let (hash_stride, _, _) = L::strides::<K, V>();
+        if hash_stride == size_of::<u64>() {
+            ptr::write_bytes(*self.hashes, 0, self.capacity);
+        } else {
+            let mut raw = self.first_bucket_raw();
+            for _ in 0..self.capacity {
+                *raw.hash = EMPTY_BUCKET;
+                raw = raw.offset::<L>(1);
+            }
+        }
+    }
+
     /// Creates a new raw table from a given capacity. All buckets are
     /// initially empty.
-    pub fn new(capacity: usize) -> RawTable<K, V> {
+    pub fn new(capacity: usize) -> RawTable<K, V, L> {
         unsafe {
-            let ret = RawTable::new_uninitialized(capacity);
-            ptr::write_bytes(*ret.hashes, 0, capacity);
+            let mut ret = RawTable::new_uninitialized(capacity);
+            ret.clear_hashes();
             ret
         }
     }
 
+    /// Creates an empty raw table inside `buffer`, with the largest power of
+    /// two capacity that fits once the start of the buffer is aligned.
+    ///
+    /// The table doesn't own its memory, and must not outlive the buffer.
+    /// Dropping it drops the entries but leaves the buffer alone.
+    pub unsafe fn in_buffer(buffer: &mut [u8]) -> RawTable<K, V, L> {
+        let align = L::allocation::<K, V>(1).expect("capacity overflow").align;
+        let start = buffer.as_mut_ptr() as usize;
+        let offset = round_up_to_next(start, align) - start;
+        let len = buffer.len().saturating_sub(offset);
+
+        let mut capacity = 0;
+        while RawTable::<K, V, L>::size_of_allocation(cmp::max(capacity * 2, 1)) <= len {
+            capacity = cmp::max(capacity * 2, 1);
+        }
+        if capacity == 0 {
+            return RawTable::new(0);
+        }
+
+        let hashes = buffer.as_mut_ptr().offset(offset as isize) as *mut u64;
+        let mut table = RawTable {
+            capacity: capacity,
+            size:     0,
+            hashes:   Unique::new(hashes),
+            max_displacement: 0,
+            borrowed: true,
+            marker:   marker::PhantomData,
+        };
+        table.clear_hashes();
+        table
+    }
+
+    /// The number of bytes allocated for a table with `capacity` buckets,
+    /// including the padding needed to align keys and values.
+    pub fn size_of_allocation(capacity: usize) -> usize {
+        if capacity == 0 {
+            return 0;
+        }
+        L::allocation::<K, V>(capacity).expect("capacity overflow").size
+    }
+
+    /// Asks the CPU to start loading the hash and then the key of the ideal
+    /// bucket for `hash`, so that a later search for it doesn't stall.
+    pub fn prefetch(&self, hash: SafeHash) {
+        if self.capacity == 0 {
+            return;
+        }
+        let index = hash.inspect() as usize & (self.capacity - 1);
+        unsafe {
+            let raw = self.first_bucket_raw().offset::<L>(index as isize);
+            prefetch_read_data(raw.hash as *const u64, 3);
+            prefetch_read_data(raw.key as *const K, 3);
+        }
+    }
+
     /// The hashtable's capacity, similar to a vector's.
     pub fn capacity(&self) -> usize {
         self.capacity
@@ -661,31 +936,59 @@ impl<K, V> RawTable<K, V> {
         self.size
     }
 
-    fn raw_buckets(&self) -> RawBuckets<K, V> {
+    /// An upper bound on the distance of any entry from its ideal bucket.
+    /// Searches need to probe no further than this.
+    pub fn max_displacement(&self) -> usize {
+        self.max_displacement
+    }
+
+    /// Raises the maximum displacement to cover an entry with `hash` just
+    /// stored at `index`.
+    fn note_displacement(&mut self, index: usize, hash: SafeHash) {
+        let distance = index.wrapping_sub(hash.inspect() as usize) & (self.capacity - 1);
+        if distance > self.max_displacement {
+            self.max_displacement = distance;
+        }
+    }
+
+    /// Forgets the maximum displacement, before every entry is placed again
+    /// with `put` or `replace`, which raise it back.
+    pub fn reset_max_displacement(&mut self) {
+        self.max_displacement = 0;
+    }
+
+    /// Resets the maximum displacement once the last entry is removed.
+    fn note_removal(&mut self) {
+        if self.size == 0 {
+            self.max_displacement = 0;
+        }
+    }
+
+    fn raw_buckets(&self) -> RawBuckets<K, V, L> {
         RawBuckets {
             raw: self.first_bucket_raw(),
             hashes_end: unsafe {
-                self.hashes.offset(self.capacity as isize)
+                self.first_bucket_raw().offset::<L>(self.capacity as isize).hash
             },
             marker: marker::PhantomData,
         }
     }
 
-    pub fn iter(&self) -> Iter<K, V> {
+    pub fn iter(&self) -> Iter<K, V, L> {
         Iter {
             iter: self.raw_buckets(),
             elems_left: self.size(),
         }
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<K, V> {
+    pub fn iter_mut(&mut self) -> IterMut<K, V, L> {
         IterMut {
             iter: self.raw_buckets(),
             elems_left: self.size(),
         }
     }
 
-    pub fn into_iter(self) -> IntoIter<K, V> {
+    pub fn into_iter(self) -> IntoIter<K, V, L> {
         let RawBuckets { raw, hashes_end, .. } = self.raw_buckets();
         // Replace the marker regardless of lifetime bounds on parameters.
         IntoIter {
@@ -698,7 +1001,7 @@ impl<K, V> RawTable<K, V> {
         }
     }
 
-    pub fn drain(&mut self) -> Drain<K, V> {
+    pub fn drain(&mut self) -> Drain<K, V, L> {
         let RawBuckets { raw, hashes_end, .. } = self.raw_buckets();
         // Replace the marker regardless of lifetime bounds on parameters.
         Drain {
@@ -713,10 +1016,10 @@ impl<K, V> RawTable<K, V> {
 
     /// Returns an iterator that copies out each entry. Used while the table
     /// is being dropped.
-    unsafe fn rev_move_buckets(&mut self) -> RevMoveBuckets<K, V> {
+    unsafe fn rev_move_buckets(&mut self) -> RevMoveBuckets<K, V, L> {
         let raw_bucket = self.first_bucket_raw();
         RevMoveBuckets {
-            raw: raw_bucket.offset(self.capacity as isize),
+            raw: raw_bucket.offset::<L>(self.capacity as isize),
             hashes_end: raw_bucket.hash,
             elems_left: self.size,
             marker:     marker::PhantomData,
@@ -726,7 +1029,7 @@ impl<K, V> RawTable<K, V> {
 
 /// A raw iterator. The basis for some other iterators in this module. Although
 /// this interface is safe, it's not used outside this module.
-struct RawBuckets<'a, K, V> {
+struct RawBuckets<'a, K, V, L> {
     raw: RawBucket<K, V>,
     hashes_end: *mut u64,
 
@@ -735,12 +1038,12 @@ struct RawBuckets<'a, K, V> {
     // move iterations, so that messes up a lot of other things. So
     // just use `&'a (K,V)` as this is not a publicly exposed type
     // anyway.
-    marker: marker::PhantomData<&'a ()>,
+    marker: marker::PhantomData<(&'a (), L)>,
 }
 
 // FIXME(#19839) Remove in favor of `#[derive(Clone)]`
-impl<'a, K, V> Clone for RawBuckets<'a, K, V> {
-    fn clone(&self) -> RawBuckets<'a, K, V> {
+impl<'a, K, V, L> Clone for RawBuckets<'a, K, V, L> {
+    fn clone(&self) -> RawBuckets<'a, K, V, L> {
         RawBuckets {
             raw: self.raw,
             hashes_end: self.hashes_end,
@@ -750,7 +1053,7 @@ impl<'a, K, V> Clone for RawBuckets<'a, K, V> {
 }
 
 
-impl<'a, K, V> Iterator for RawBuckets<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for RawBuckets<'a, K, V, L> {
     type Item = RawBucket<K, V>;
 
     fn next(&mut self) -> Option<RawBucket<K, V>> {
@@ -758,7 +1061,7 @@ impl<'a, K, V> Iterator for RawBuckets<'a, K, V> {
             unsafe {
                 // We are swapping out the pointer to a bucket and replacing
                 // it with the pointer to the next one.
-                let prev = ptr::replace(&mut self.raw, self.raw.offset(1));
+                let prev = ptr::replace(&mut self.raw, self.raw.offset::<L>(1));
                 if *prev.hash != EMPTY_BUCKET {
                     return Some(prev);
                 }
@@ -772,7 +1075,7 @@ impl<'a, K, V> Iterator for RawBuckets<'a, K, V> {
 /// An iterator that moves out buckets in reverse order. It leaves the table
 /// in an inconsistent state and should only be used for dropping
 /// the table's remaining entries. It's used in the implementation of Drop.
-struct RevMoveBuckets<'a, K, V> {
+struct RevMoveBuckets<'a, K, V, L> {
     raw: RawBucket<K, V>,
     hashes_end: *mut u64,
     elems_left: usize,
@@ -780,10 +1083,10 @@ struct RevMoveBuckets<'a, K, V> {
     // As above, `&'a (K,V)` would seem better, but we often use
     // 'static for the lifetime, and this is not a publicly exposed
     // type.
-    marker: marker::PhantomData<&'a ()>,
+    marker: marker::PhantomData<(&'a (), L)>,
 }
 
-impl<'a, K, V> Iterator for RevMoveBuckets<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for RevMoveBuckets<'a, K, V, L> {
     type Item = (K, V);
 
     fn next(&mut self) -> Option<(K, V)> {
@@ -795,7 +1098,7 @@ impl<'a, K, V> Iterator for RevMoveBuckets<'a, K, V> {
             debug_assert!(self.raw.hash != self.hashes_end);
 
             unsafe {
-                self.raw = self.raw.offset(-1);
+                self.raw = self.raw.offset::<L>(-1);
 
                 if *self.raw.hash != EMPTY_BUCKET {
                     self.elems_left -= 1;
@@ -810,17 +1113,17 @@ impl<'a, K, V> Iterator for RevMoveBuckets<'a, K, V> {
 }
 
 /// Iterator over shared references to entries in a table.
-pub struct Iter<'a, K: 'a, V: 'a> {
-    iter: RawBuckets<'a, K, V>,
+pub struct Iter<'a, K: 'a, V: 'a, L: Layout = SplitLayout> {
+    iter: RawBuckets<'a, K, V, L>,
     elems_left: usize,
 }
 
-unsafe impl<'a, K: Sync, V: Sync> Sync for Iter<'a, K, V> {}
-unsafe impl<'a, K: Sync, V: Sync> Send for Iter<'a, K, V> {}
+unsafe impl<'a, K: Sync, V: Sync, L: Layout> Sync for Iter<'a, K, V, L> {}
+unsafe impl<'a, K: Sync, V: Sync, L: Layout> Send for Iter<'a, K, V, L> {}
 
 // FIXME(#19839) Remove in favor of `#[derive(Clone)]`
-impl<'a, K, V> Clone for Iter<'a, K, V> {
-    fn clone(&self) -> Iter<'a, K, V> {
+impl<'a, K, V, L: Layout> Clone for Iter<'a, K, V, L> {
+    fn clone(&self) -> Iter<'a, K, V, L> {
         Iter {
             iter: self.iter.clone(),
             elems_left: self.elems_left
@@ -830,35 +1133,35 @@ impl<'a, K, V> Clone for Iter<'a, K, V> {
 
 
 /// Iterator over mutable references to entries in a table.
-pub struct IterMut<'a, K: 'a, V: 'a> {
-    iter: RawBuckets<'a, K, V>,
+pub struct IterMut<'a, K: 'a, V: 'a, L: Layout = SplitLayout> {
+    iter: RawBuckets<'a, K, V, L>,
     elems_left: usize,
 }
 
-unsafe impl<'a, K: Sync, V: Sync> Sync for IterMut<'a, K, V> {}
+unsafe impl<'a, K: Sync, V: Sync, L: Layout> Sync for IterMut<'a, K, V, L> {}
 // Both K: Sync and K: Send are correct for IterMut's Send impl,
 // but Send is the more useful bound
-unsafe impl<'a, K: Send, V: Send> Send for IterMut<'a, K, V> {}
+unsafe impl<'a, K: Send, V: Send, L: Layout> Send for IterMut<'a, K, V, L> {}
 
 /// Iterator over the entries in a table, consuming the table.
-pub struct IntoIter<K, V> {
-    table: RawTable<K, V>,
-    iter: RawBuckets<'static, K, V>
+pub struct IntoIter<K, V, L: Layout = SplitLayout> {
+    table: RawTable<K, V, L>,
+    iter: RawBuckets<'static, K, V, L>
 }
 
-unsafe impl<K: Sync, V: Sync> Sync for IntoIter<K, V> {}
-unsafe impl<K: Send, V: Send> Send for IntoIter<K, V> {}
+unsafe impl<K: Sync, V: Sync, L: Layout> Sync for IntoIter<K, V, L> {}
+unsafe impl<K: Send, V: Send, L: Layout> Send for IntoIter<K, V, L> {}
 
 /// Iterator over the entries in a table, clearing the table.
-pub struct Drain<'a, K: 'a, V: 'a> {
-    table: &'a mut RawTable<K, V>,
-    iter: RawBuckets<'static, K, V>,
+pub struct Drain<'a, K: 'a, V: 'a, L: 'a + Layout = SplitLayout> {
+    table: &'a mut RawTable<K, V, L>,
+    iter: RawBuckets<'static, K, V, L>,
 }
 
-unsafe impl<'a, K: Sync, V: Sync> Sync for Drain<'a, K, V> {}
-unsafe impl<'a, K: Send, V: Send> Send for Drain<'a, K, V> {}
+unsafe impl<'a, K: Sync, V: Sync, L: Layout> Sync for Drain<'a, K, V, L> {}
+unsafe impl<'a, K: Send, V: Send, L: Layout> Send for Drain<'a, K, V, L> {}
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for Iter<'a, K, V, L> {
     type Item = (&'a K, &'a V);
 
     fn next(&mut self) -> Option<(&'a K, &'a V)> {
@@ -875,11 +1178,11 @@ impl<'a, K, V> Iterator for Iter<'a, K, V> {
         (self.elems_left, Some(self.elems_left))
     }
 }
-impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
+impl<'a, K, V, L: Layout> ExactSizeIterator for Iter<'a, K, V, L> {
     fn len(&self) -> usize { self.elems_left }
 }
 
-impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for IterMut<'a, K, V, L> {
     type Item = (&'a K, &'a mut V);
 
     fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
@@ -896,16 +1199,17 @@ impl<'a, K, V> Iterator for IterMut<'a, K, V> {
         (self.elems_left, Some(self.elems_left))
     }
 }
-impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {
+impl<'a, K, V, L: Layout> ExactSizeIterator for IterMut<'a, K, V, L> {
     fn len(&self) -> usize { self.elems_left }
 }
 
-impl<K, V> Iterator for IntoIter<K, V> {
+impl<K, V, L: Layout> Iterator for IntoIter<K, V, L> {
     type Item = (SafeHash, K, V);
 
     fn next(&mut self) -> Option<(SafeHash, K, V)> {
         self.iter.next().map(|bucket| {
             self.table.size -= 1;
+            self.table.note_removal();
             unsafe {
                 (
                     SafeHash {
@@ -923,17 +1227,18 @@ impl<K, V> Iterator for IntoIter<K, V> {
         (size, Some(size))
     }
 }
-impl<K, V> ExactSizeIterator for IntoIter<K, V> {
+impl<K, V, L: Layout> ExactSizeIterator for IntoIter<K, V, L> {
     fn len(&self) -> usize { self.table.size() }
 }
 
-impl<'a, K, V> Iterator for Drain<'a, K, V> {
+impl<'a, K, V, L: Layout> Iterator for Drain<'a, K, V, L> {
     type Item = (SafeHash, K, V);
 
     #[inline]
     fn next(&mut self) -> Option<(SafeHash, K, V)> {
         self.iter.next().map(|bucket| {
             self.table.size -= 1;
+            self.table.note_removal();
             unsafe {
                 (
                     SafeHash {
@@ -951,18 +1256,18 @@ impl<'a, K, V> Iterator for Drain<'a, K, V> {
         (size, Some(size))
     }
 }
-impl<'a, K, V> ExactSizeIterator for Drain<'a, K, V> {
+impl<'a, K, V, L: Layout> ExactSizeIterator for Drain<'a, K, V, L> {
     fn len(&self) -> usize { self.table.size() }
 }
 
-impl<'a, K: 'a, V: 'a> Drop for Drain<'a, K, V> {
+impl<'a, K: 'a, V: 'a, L: Layout> Drop for Drain<'a, K, V, L> {
     fn drop(&mut self) {
         for _ in self {}
     }
 }
 
-impl<K: Clone, V: Clone> Clone for RawTable<K, V> {
-    fn clone(&self) -> RawTable<K, V> {
+impl<K: Clone, V: Clone, L: Layout> Clone for RawTable<K, V, L> {
+    fn clone(&self) -> RawTable<K, V, L> {
         unsafe {
             let mut new_ht = RawTable::new_uninitialized(self.capacity());
 
@@ -991,13 +1296,14 @@ impl<K: Clone, V: Clone> Clone for RawTable<K, V> {
             };
 
             new_ht.size = self.size();
+            new_ht.max_displacement = self.max_displacement;
 
             new_ht
         }
     }
 }
 
-impl<K, V> Drop for RawTable<K, V> {
+impl<K, V, L: Layout> Drop for RawTable<K, V, L> {
     #[unsafe_destructor_blind_to_params]
     fn drop(&mut self) {
         if self.capacity == 0 || self.capacity == mem::POST_DROP_USIZE {
@@ -1013,18 +1319,14 @@ impl<K, V> Drop for RawTable<K, V> {
             for _ in self.rev_move_buckets() {}
         }
 
-        let hashes_size = self.capacity * size_of::<u64>();
-        let keys_size = self.capacity * size_of::<K>();
-        let vals_size = self.capacity * size_of::<V>();
-        let (align, _, size, oflo) =
-            calculate_allocation(hashes_size, align_of::<u64>(),
-                                 keys_size, align_of::<K>(),
-                                 vals_size, align_of::<V>());
+        if self.borrowed {
+            return;
+        }
 
-        debug_assert!(!oflo, "should be impossible");
+        let allocation = L::allocation::<K, V>(self.capacity).expect("should be impossible");
 
         unsafe {
-            deallocate(*self.hashes as *mut u8, size, align);
+            deallocate(*self.hashes as *mut u8, allocation.size, allocation.align);
             // Remember how everything was allocated out of one buffer
             // during initialization? We only need one call to free here.
         }
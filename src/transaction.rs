@@ -0,0 +1,315 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! All-or-nothing updates to a map, undone from a log.
+
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::collections::hash_state::HashState;
+
+use {Entry, HashMap};
+
+impl<K, V, S> HashMap<K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Runs a closure that updates the map through a `Transaction`. If the
+    /// closure returns `Ok`, its changes are kept. If it returns `Err` or
+    /// panics, the map is rolled back to exactly the entries it had before.
+    ///
+    /// # Panics
+    ///
+    /// Rolling back re-inserts keys, which runs their `Hash` and `Eq`
+    /// implementations. If the closure panicked and one of those panics
+    /// too, while unwinding, the process aborts.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::HashMap;
+    ///
+    /// let mut config = HashMap::new();
+    /// config.insert("port", 80);
+    ///
+    /// let result: Result<(), &str> = config.transaction(|tx| {
+    ///     tx.insert("port", 8080);
+    ///     tx.insert("workers", 0);
+    ///     Err("workers must be positive")
+    /// });
+    ///
+    /// assert!(result.is_err());
+    /// assert_eq!(config.len(), 1);
+    /// assert_eq!(config["port"], 80);
+    /// ```
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+        where F: FnOnce(&mut Transaction<K, V, S>) -> Result<T, E>
+    {
+        let mut tx = Transaction {
+            map: self,
+            log: Vec::new(),
+            savepoints: Vec::new(),
+            next_generation: 0,
+            committed: false,
+        };
+        let result = f(&mut tx);
+        tx.committed = result.is_ok();
+        result
+    }
+}
+
+/// A set of changes to a map, made by `HashMap::transaction`.
+///
+/// Every change made through `insert`, `remove` and `entry` records the key
+/// and its previous value in an undo log. Rolling back replays the log
+/// backwards. The log keeps the replaced values until the transaction ends,
+/// so they are dropped only once it commits.
+///
+/// Savepoints mark a place in the log to roll back to, without ending the
+/// transaction. They can be nested, e.g. to implement scoped symbol tables.
+pub struct Transaction<'a, K: 'a, V: 'a, S: 'a>
+    where K: Eq + Hash, S: HashState
+{
+    map: &'a mut HashMap<K, V, S>,
+    log: Vec<(K, Option<V>)>,
+
+    // The generations of the savepoints that can still be rolled back to,
+    // in the order they were taken.
+    savepoints: Vec<usize>,
+    next_generation: usize,
+
+    committed: bool,
+}
+
+/// A place in a transaction to roll back to, from `Transaction::savepoint`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Savepoint {
+    // The length of the log when the savepoint was taken.
+    position: usize,
+    generation: usize,
+}
+
+impl<'a, K, V, S> Transaction<'a, K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize { self.map.len() }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool { self.map.is_empty() }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.get(k)
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.contains_key(k)
+    }
+
+    /// Inserts a key-value pair into the map. Returns the value it replaced,
+    /// which the undo log keeps until the transaction ends.
+    pub fn insert(&mut self, k: K, v: V) -> Option<&V>
+        where K: Clone
+    {
+        let old = self.map.insert(k.clone(), v);
+        self.log.push((k, old));
+        self.log.last().unwrap().1.as_ref()
+    }
+
+    /// Removes a key from the map. Returns the value it removed, which the
+    /// undo log keeps until the transaction ends.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        match self.map.take(k) {
+            Some((key, value)) => {
+                self.log.push((key, Some(value)));
+                self.log.last().unwrap().1.as_ref()
+            }
+            None => None,
+        }
+    }
+
+    /// Gets the given key's entry in the map, for in-place manipulation.
+    /// The entry's current value is cloned into the undo log first.
+    pub fn entry(&mut self, key: K) -> Entry<K, V>
+        where K: Clone, V: Clone
+    {
+        let old = self.map.get(&key).cloned();
+        self.log.push((key.clone(), old));
+        self.map.entry(key)
+    }
+
+    /// Marks the current state of the map, to roll back to later.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        self.savepoints.push(generation);
+        Savepoint {
+            position: self.log.len(),
+            generation: generation,
+        }
+    }
+
+    /// Undoes every change made since the savepoint. The savepoint can be
+    /// rolled back to again, but savepoints taken after it become stale.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the savepoint is stale, because the transaction was rolled
+    /// back to an earlier savepoint since it was taken.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        let live = self.savepoints.binary_search(&savepoint.generation)
+            .expect("savepoint is stale: an earlier savepoint was rolled back to");
+        self.savepoints.truncate(live + 1);
+        self.rollback(savepoint.position);
+    }
+
+    /// Undoes the changes logged from `position` on.
+    fn rollback(&mut self, position: usize) {
+        while self.log.len() > position {
+            match self.log.pop().unwrap() {
+                (k, Some(v)) => { self.map.insert(k, v); }
+                (k, None) => { self.map.remove(&k); }
+            }
+        }
+    }
+
+    /// Runs a closure as a nested transaction. If it returns `Err`, only the
+    /// changes it made are rolled back. If it panics, the whole transaction
+    /// is.
+    pub fn nested<T, E, F>(&mut self, f: F) -> Result<T, E>
+        where F: FnOnce(&mut Transaction<'a, K, V, S>) -> Result<T, E>
+    {
+        let savepoint = self.savepoint();
+        let result = f(self);
+        if result.is_err() {
+            self.rollback_to(savepoint);
+        }
+        result
+    }
+}
+
+impl<'a, K, V, S> Drop for Transaction<'a, K, V, S>
+    where K: Eq + Hash, S: HashState
+{
+    fn drop(&mut self) {
+        if !self.committed {
+            self.rollback(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use HashMap;
+
+    #[test]
+    fn test_commit_and_rollback() {
+        let mut map: HashMap<String, u32> = (0..10).map(|i| (i.to_string(), i)).collect();
+        let before = map.clone();
+
+        let result: Result<(), ()> = map.transaction(|tx| {
+            assert_eq!(tx.insert("1".to_string(), 100), Some(&1));
+            assert_eq!(tx.insert("1".to_string(), 200), Some(&100));
+            assert_eq!(tx.remove("2"), Some(&2));
+            assert_eq!(tx.remove("2"), None);
+            *tx.entry("3".to_string()).or_insert(0) += 1;
+            *tx.entry("new".to_string()).or_insert(0) += 1;
+            assert_eq!(tx.len(), 10);
+            Err(())
+        });
+        assert!(result.is_err());
+        assert_eq!(map, before);
+
+        let result: Result<u32, ()> = map.transaction(|tx| {
+            tx.remove("0");
+            tx.insert("10".to_string(), 10);
+            Ok(tx.len() as u32)
+        });
+        assert_eq!(result, Ok(10));
+        assert!(!map.contains_key("0"));
+        assert_eq!(map["10"], 10);
+    }
+
+    #[test]
+    fn test_rollback_on_panic() {
+        let map: HashMap<u32, u32> = (0..100).map(|i| (i, i)).collect();
+        let before = map.clone();
+        let shared = Arc::new(Mutex::new(map));
+
+        let shared2 = shared.clone();
+        let result = thread::spawn(move || {
+            let mut map = shared2.lock().unwrap();
+            let _: Result<(), ()> = map.transaction(|tx| {
+                for i in 0..200 {
+                    tx.insert(i, 0);
+                }
+                panic!("validation failed");
+            });
+        }).join();
+        assert!(result.is_err());
+
+        let map = match shared.lock() {
+            Ok(map) => map,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        assert_eq!(*map, before);
+    }
+
+    #[test]
+    fn test_scoped_symbols() {
+        let mut symbols = HashMap::new();
+        let _: Result<(), ()> = symbols.transaction(|tx| {
+            tx.insert("x", "global");
+            let outer = tx.savepoint();
+            tx.insert("x", "local");
+            tx.insert("y", "local");
+
+            let inner: Result<(), ()> = tx.nested(|tx| {
+                tx.remove("x");
+                Err(())
+            });
+            assert!(inner.is_err());
+            assert_eq!(tx.get("x"), Some(&"local"));
+
+            tx.rollback_to(outer);
+            assert_eq!(tx.get("x"), Some(&"global"));
+            assert!(!tx.contains_key("y"));
+            Ok(())
+        });
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols["x"], "global");
+    }
+
+    #[test]
+    #[should_panic(expected = "stale")]
+    fn test_stale_savepoint() {
+        let mut map = HashMap::new();
+        let _: Result<(), ()> = map.transaction(|tx| {
+            let outer = tx.savepoint();
+            tx.insert(1, 1);
+            let inner = tx.savepoint();
+            tx.rollback_to(outer);
+
+            // As many changes as before, so the log is as long again.
+            tx.insert(2, 2);
+            tx.rollback_to(inner);
+            Ok(())
+        });
+    }
+}
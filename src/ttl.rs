@@ -0,0 +1,432 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A hash map whose entries expire.
+
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::collections::hash_state::HashState;
+
+use table::{self, Bucket};
+use {HashMap, RandomState, pop_internal, retain_in_place};
+
+/// A source of the current time for a `TtlHashMap`.
+pub trait Clock {
+    /// Returns the time elapsed since an arbitrary, fixed starting point.
+    /// It must never go backwards.
+    fn now(&self) -> Duration;
+}
+
+/// A clock that reads the system's monotonic clock.
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    /// Creates a clock that counts from the current instant.
+    pub fn new() -> SystemClock {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a
+/// test can keep one clone and hand another to the map.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use hashmap2::ttl::{ManualClock, TtlHashMap};
+///
+/// let clock = ManualClock::new();
+/// let mut sessions = TtlHashMap::with_clock(clock.clone());
+/// sessions.insert_with_ttl("alice", 1, Duration::from_secs(60));
+///
+/// clock.advance(Duration::from_secs(59));
+/// assert_eq!(sessions.get("alice"), Some(&1));
+/// clock.advance(Duration::from_secs(1));
+/// assert_eq!(sessions.get("alice"), None);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<Duration>>,
+}
+
+impl ManualClock {
+    /// Creates a clock starting at zero.
+    pub fn new() -> ManualClock {
+        ManualClock { now: Rc::new(Cell::new(Duration::new(0, 0))) }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+/// A value with its expiry time. `None` never expires.
+struct Timed<V> {
+    value: V,
+    expires: Option<Duration>,
+}
+
+impl<V> Timed<V> {
+    fn is_expired(&self, now: Duration) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+}
+
+/// A hash map whose entries can be given a time to live.
+///
+/// Expired entries are treated as absent. `get` removes an expired entry it
+/// comes across, and `purge_expired` removes all of them. Until then, expired
+/// entries still occupy the table and count towards `len`.
+///
+/// The current time comes from a `Clock`, which defaults to the system's
+/// monotonic clock.
+pub struct TtlHashMap<K, V, S = RandomState, C = SystemClock> {
+    map: HashMap<K, Timed<V>, S>,
+    clock: C,
+}
+
+impl<K: Hash + Eq, V> TtlHashMap<K, V, RandomState, SystemClock> {
+    /// Creates an empty map that uses the system clock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hashmap2::ttl::TtlHashMap;
+    /// let mut map: TtlHashMap<&str, isize> = TtlHashMap::new();
+    /// ```
+    pub fn new() -> TtlHashMap<K, V, RandomState, SystemClock> {
+        TtlHashMap::with_clock(SystemClock::new())
+    }
+}
+
+impl<K: Hash + Eq, V, C: Clock> TtlHashMap<K, V, RandomState, C> {
+    /// Creates an empty map that reads the time from `clock`.
+    pub fn with_clock(clock: C) -> TtlHashMap<K, V, RandomState, C> {
+        TtlHashMap::with_clock_and_hash_state(clock, Default::default())
+    }
+}
+
+impl<K, V, S, C> TtlHashMap<K, V, S, C>
+    where K: Eq + Hash, S: HashState, C: Clock
+{
+    /// Creates an empty map that reads the time from `clock` and uses
+    /// `hash_state` to hash the keys.
+    pub fn with_clock_and_hash_state(clock: C, hash_state: S) -> TtlHashMap<K, V, S, C> {
+        TtlHashMap {
+            map: HashMap::with_hash_state(hash_state),
+            clock: clock,
+        }
+    }
+
+    /// Returns a reference to the clock.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Returns the number of entries in the map, including expired entries
+    /// that weren't removed yet.
+    pub fn len(&self) -> usize { self.map.len() }
+
+    /// Returns true if the map contains no entries, including expired ones.
+    pub fn is_empty(&self) -> bool { self.map.is_empty() }
+
+    /// Inserts a key-value pair that never expires.
+    ///
+    /// If the map had a live value for this key, it's replaced and returned.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.insert_timed(k, v, None)
+    }
+
+    /// Inserts a key-value pair that expires once `ttl` has passed.
+    ///
+    /// If the map had a live value for this key, it's replaced and returned.
+    pub fn insert_with_ttl(&mut self, k: K, v: V, ttl: Duration) -> Option<V> {
+        let expires = self.clock.now() + ttl;
+        self.insert_timed(k, v, Some(expires))
+    }
+
+    fn insert_timed(&mut self, k: K, v: V, expires: Option<Duration>) -> Option<V> {
+        let now = self.clock.now();
+        let old = self.map.insert(k, Timed { value: v, expires: expires });
+        match old {
+            Some(ref old) if old.is_expired(now) => None,
+            old => old.map(|old| old.value),
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key, if it
+    /// hasn't expired. An expired entry is removed.
+    ///
+    /// The key may be any borrowed form of the map's key type, but
+    /// `Hash` and `Eq` on the borrowed form *must* match those for
+    /// the key type.
+    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get_mut(k).map(|v| &*v)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key, if
+    /// it hasn't expired. An expired entry is removed.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let now = self.clock.now();
+        let (index, expired) = match self.map.search(k) {
+            Some(bucket) => (bucket.index(), bucket.read().1.is_expired(now)),
+            None => return None,
+        };
+
+        // Go back to the bucket found, without hashing or probing again.
+        if expired {
+            pop_internal(Bucket::at_index(&mut self.map.table, index).peek().expect_full());
+            self.map.debug_check_invariants();
+            return None;
+        }
+        let bucket = Bucket::at_index(&mut self.map.table, index).peek().expect_full();
+        Some(&mut bucket.into_mut_refs().1.value)
+    }
+
+    /// Returns a reference to the value corresponding to the key, if it
+    /// hasn't expired. Doesn't remove an expired entry.
+    pub fn peek<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let now = self.clock.now();
+        self.map.get(k).and_then(|timed| {
+            if timed.is_expired(now) { None } else { Some(&timed.value) }
+        })
+    }
+
+    /// Returns true if the map contains a live value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.peek(k).is_some()
+    }
+
+    /// Returns the time left until the key's entry expires. Returns `None`
+    /// if the key is absent, has expired or never expires.
+    pub fn ttl<Q: ?Sized>(&self, k: &Q) -> Option<Duration>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let now = self.clock.now();
+        self.map.get(k)
+                .and_then(|timed| timed.expires)
+                .and_then(|expires| if expires > now { Some(expires - now) } else { None })
+    }
+
+    /// Removes a key from the map, returning its value if the key was
+    /// present and hadn't expired.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        let now = self.clock.now();
+        let result = self.map.search_mut(k).map(pop_internal);
+        self.map.debug_check_invariants();
+        match result {
+            Some((_, ref timed)) if timed.is_expired(now) => None,
+            result => result.map(|(_, timed)| timed.value),
+        }
+    }
+
+    /// Removes all expired entries in a single sweep over the table, and
+    /// returns how many were removed.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = self.clock.now();
+        let removed = retain_in_place(&mut self.map.table, |_, timed| !timed.is_expired(now));
+        self.map.debug_check_invariants();
+        removed
+    }
+
+    /// Returns the time left until the next entry expires, or `None` if no
+    /// entry expires. Returns zero if an entry has already expired.
+    ///
+    /// This looks at every entry.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use hashmap2::ttl::{ManualClock, TtlHashMap};
+    ///
+    /// let clock = ManualClock::new();
+    /// let mut tokens = TtlHashMap::with_clock(clock.clone());
+    /// tokens.insert("a", 1);
+    /// assert_eq!(tokens.next_expiry(), None);
+    ///
+    /// tokens.insert_with_ttl("b", 2, Duration::from_secs(30));
+    /// tokens.insert_with_ttl("c", 3, Duration::from_secs(10));
+    /// clock.advance(Duration::from_secs(4));
+    /// assert_eq!(tokens.next_expiry(), Some(Duration::from_secs(6)));
+    /// ```
+    pub fn next_expiry(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.map.values()
+                .filter_map(|timed| timed.expires)
+                .min()
+                .map(|expires| if expires > now { expires - now } else { Duration::new(0, 0) })
+    }
+
+    /// Clears the map, removing all entries.
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// An iterator visiting all live key-value pairs in arbitrary order.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter {
+            inner: self.map.table.iter(),
+            now: self.clock.now(),
+        }
+    }
+}
+
+impl<K, V, S, C> Debug for TtlHashMap<K, V, S, C>
+    where K: Eq + Hash + Debug, V: Debug, S: HashState, C: Clock
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K, V, S, C> IntoIterator for &'a TtlHashMap<K, V, S, C>
+    where K: Eq + Hash, S: HashState, C: Clock
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// TtlHashMap iterator over live entries.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: table::Iter<'a, K, Timed<V>>,
+    now: Duration,
+}
+
+// FIXME(#19839) Remove in favor of `#[derive(Clone)]`
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            inner: self.inner.clone(),
+            now: self.now,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        let now = self.now;
+        self.inner.by_ref()
+                  .find(|&(_, timed)| !timed.is_expired(now))
+                  .map(|(k, timed)| (k, &timed.value))
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ManualClock, TtlHashMap};
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn test_expiry() {
+        let clock = ManualClock::new();
+        let mut map = TtlHashMap::with_clock(clock.clone());
+        map.insert(0, 0);
+        map.insert_with_ttl(1, 1, secs(10));
+        map.insert_with_ttl(2, 2, secs(20));
+
+        clock.advance(secs(10));
+        assert!(!map.contains_key(&1));
+        assert_eq!(map.peek(&1), None);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.len(), 2);
+
+        assert_eq!(map.ttl(&0), None);
+        assert_eq!(map.ttl(&2), Some(secs(10)));
+        assert_eq!(map.iter().count(), 2);
+
+        // Replacing an expired value doesn't return it.
+        clock.advance(secs(10));
+        assert_eq!(map.insert(2, 3), None);
+        assert_eq!(map.get(&2), Some(&3));
+        assert_eq!(map.insert_with_ttl(2, 4, secs(1)), Some(3));
+        clock.advance(secs(1));
+        assert_eq!(map.remove(&2), None);
+        assert_eq!(map.remove(&0), Some(0));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let clock = ManualClock::new();
+        let mut map = TtlHashMap::with_clock(clock.clone());
+        for i in 0..1000 {
+            map.insert_with_ttl(i, i, secs(i % 3));
+        }
+        assert_eq!(map.next_expiry(), Some(secs(0)));
+
+        clock.advance(secs(1));
+        assert_eq!(map.purge_expired(), 667);
+        assert_eq!(map.len(), 333);
+        assert_eq!(map.next_expiry(), Some(secs(1)));
+        for i in 0..1000 {
+            assert_eq!(map.peek(&i).is_some(), i % 3 == 2);
+        }
+
+        clock.advance(secs(1));
+        assert_eq!(map.purge_expired(), 333);
+        assert!(map.is_empty());
+        assert_eq!(map.next_expiry(), None);
+    }
+}
@@ -0,0 +1,437 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Hash maps holding weak references, whose entries vanish once the
+//! referent is dropped.
+
+use std::borrow::Borrow;
+use std::default::Default;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::mem::replace;
+use std::ops::Deref;
+use std::rc::{self, Rc};
+use std::sync::{self, Arc};
+use std::collections::hash_state::HashState;
+
+use hashed::HashedTable;
+use table::{self, make_hash};
+use {HashMap, RandomState, retain_in_place};
+
+/// A weak reference that can be stored in a `WeakValueHashMap` or a
+/// `WeakKeyHashMap`.
+pub trait WeakElement {
+    /// The strong reference this weak reference comes from.
+    type Strong: Deref;
+
+    /// Creates a weak reference to the referent of `strong`.
+    fn new(strong: &Self::Strong) -> Self;
+
+    /// Returns a strong reference, if the referent is still alive.
+    fn view(&self) -> Option<Self::Strong>;
+
+    /// Returns true if the referent was dropped.
+    fn is_expired(&self) -> bool {
+        self.view().is_none()
+    }
+}
+
+impl<T: ?Sized> WeakElement for rc::Weak<T> {
+    type Strong = Rc<T>;
+
+    fn new(strong: &Rc<T>) -> rc::Weak<T> {
+        Rc::downgrade(strong)
+    }
+
+    fn view(&self) -> Option<Rc<T>> {
+        self.upgrade()
+    }
+}
+
+impl<T: ?Sized> WeakElement for sync::Weak<T> {
+    type Strong = Arc<T>;
+
+    fn new(strong: &Arc<T>) -> sync::Weak<T> {
+        Arc::downgrade(strong)
+    }
+
+    fn view(&self) -> Option<Arc<T>> {
+        self.upgrade()
+    }
+}
+
+/// A hash map whose values are weak references. An entry whose value was
+/// dropped is treated as absent.
+///
+/// Dead entries are removed by `purge`, and also by any insertion that
+/// would otherwise grow the table, so cleanup is paid for along with
+/// growth. Until then, they count towards `len`.
+///
+/// # Examples
+///
+/// ```
+/// use std::rc::{Rc, Weak};
+/// use hashmap2::weak::WeakValueHashMap;
+///
+/// let mut registry: WeakValueHashMap<u32, Weak<String>> = WeakValueHashMap::new();
+/// let window = Rc::new("window".to_string());
+/// registry.insert(1, window.clone());
+/// assert_eq!(registry.get(&1), Some(window.clone()));
+///
+/// drop(window);
+/// assert_eq!(registry.get(&1), None);
+/// assert_eq!(registry.purge(), 1);
+/// assert!(registry.is_empty());
+/// ```
+pub struct WeakValueHashMap<K, W, S = RandomState> {
+    map: HashMap<K, W, S>,
+}
+
+impl<K: Hash + Eq, W: WeakElement> WeakValueHashMap<K, W, RandomState> {
+    /// Creates an empty map.
+    pub fn new() -> WeakValueHashMap<K, W, RandomState> {
+        WeakValueHashMap::with_hash_state(Default::default())
+    }
+}
+
+impl<K, W, S> WeakValueHashMap<K, W, S>
+    where K: Eq + Hash, W: WeakElement, S: HashState
+{
+    /// Creates an empty map which will use the given hash state to hash keys.
+    pub fn with_hash_state(hash_state: S) -> WeakValueHashMap<K, W, S> {
+        WeakValueHashMap { map: HashMap::with_hash_state(hash_state) }
+    }
+
+    /// Returns the number of entries in the map, including dead entries
+    /// that weren't removed yet.
+    pub fn len(&self) -> usize { self.map.len() }
+
+    /// Returns true if the map contains no entries, including dead ones.
+    pub fn is_empty(&self) -> bool { self.map.is_empty() }
+
+    /// Inserts a weak reference to `value` under the key. Removes all dead
+    /// entries first if the table would grow otherwise.
+    ///
+    /// If the map had a live value for this key, it's replaced and returned.
+    pub fn insert(&mut self, k: K, value: W::Strong) -> Option<W::Strong> {
+        if self.map.would_grow(1) {
+            self.purge();
+        }
+        self.map.insert(k, W::new(&value)).and_then(|old| old.view())
+    }
+
+    /// Returns the value corresponding to the key, if it's still alive.
+    ///
+    /// The key may be any borrowed form of the map's key type, but
+    /// `Hash` and `Eq` on the borrowed form *must* match those for
+    /// the key type.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<W::Strong>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.get(k).and_then(|weak| weak.view())
+    }
+
+    /// Returns true if the map contains a live value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.get(k).map_or(false, |weak| !weak.is_expired())
+    }
+
+    /// Removes a key from the map, returning its value if it was still
+    /// alive.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<W::Strong>
+        where K: Borrow<Q>, Q: Hash + Eq
+    {
+        self.map.remove(k).and_then(|weak| weak.view())
+    }
+
+    /// Removes all dead entries, and returns how many were removed.
+    pub fn purge(&mut self) -> usize {
+        let removed = retain_in_place(&mut self.map.table, |_, weak| !weak.is_expired());
+        self.map.debug_check_invariants();
+        removed
+    }
+
+    /// Clears the map, removing all entries.
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// An iterator visiting the live entries in arbitrary order.
+    pub fn iter(&self) -> ValueIter<K, W> {
+        ValueIter { inner: self.map.table.iter() }
+    }
+}
+
+impl<K, W, S> Debug for WeakValueHashMap<K, W, S>
+    where K: Eq + Hash + Debug, W: WeakElement, W::Strong: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// WeakValueHashMap iterator over live entries.
+pub struct ValueIter<'a, K: 'a, W: 'a> {
+    inner: table::Iter<'a, K, W>,
+}
+
+impl<'a, K, W: WeakElement> Iterator for ValueIter<'a, K, W> {
+    type Item = (&'a K, W::Strong);
+
+    fn next(&mut self) -> Option<(&'a K, W::Strong)> {
+        while let Some((k, weak)) = self.inner.next() {
+            if let Some(strong) = weak.view() {
+                return Some((k, strong));
+            }
+        }
+        None
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+/// A hash map whose keys are weak references. An entry whose key was
+/// dropped is treated as absent.
+///
+/// Each entry keeps the hash its key had when it was inserted, since a dead
+/// key can't be hashed again. Dead entries are removed by `purge`, and also
+/// by any insertion that would otherwise grow the table. Until then, they
+/// count towards `len`.
+///
+/// # Examples
+///
+/// ```
+/// use std::rc::{Rc, Weak};
+/// use hashmap2::weak::WeakKeyHashMap;
+///
+/// let mut sizes: WeakKeyHashMap<Weak<String>, usize> = WeakKeyHashMap::new();
+/// let name = Rc::new("config.toml".to_string());
+/// sizes.insert(name.clone(), 120);
+/// assert_eq!(sizes.get("config.toml"), Some(&120));
+///
+/// drop(name);
+/// assert_eq!(sizes.get("config.toml"), None);
+/// assert_eq!(sizes.purge(), 1);
+/// ```
+pub struct WeakKeyHashMap<K, V, S = RandomState> {
+    hash_state: S,
+    table: HashedTable<K, V>,
+}
+
+impl<K, V> WeakKeyHashMap<K, V, RandomState>
+    where K: WeakElement, <K::Strong as Deref>::Target: Hash + Eq
+{
+    /// Creates an empty map.
+    pub fn new() -> WeakKeyHashMap<K, V, RandomState> {
+        WeakKeyHashMap::with_hash_state(Default::default())
+    }
+}
+
+impl<K, V, S> WeakKeyHashMap<K, V, S>
+    where K: WeakElement, <K::Strong as Deref>::Target: Hash + Eq, S: HashState
+{
+    /// Creates an empty map which will use the given hash state to hash keys.
+    pub fn with_hash_state(hash_state: S) -> WeakKeyHashMap<K, V, S> {
+        WeakKeyHashMap {
+            hash_state: hash_state,
+            table: HashedTable::new(),
+        }
+    }
+
+    /// Returns the number of entries in the map, including dead entries
+    /// that weren't removed yet.
+    pub fn len(&self) -> usize { self.table.len() }
+
+    /// Returns true if the map contains no entries, including dead ones.
+    pub fn is_empty(&self) -> bool { self.table.len() == 0 }
+
+    /// Inserts a value under a weak reference to `key`. Removes all dead
+    /// entries first if the table would grow otherwise.
+    ///
+    /// If the map had a live entry for this key, its value is replaced and
+    /// returned.
+    pub fn insert(&mut self, key: K::Strong, v: V) -> Option<V> {
+        let hash = make_hash(&self.hash_state, &*key);
+        if let Some((_, old)) = self.table.find_mut(hash, |weak| is_key(weak, &*key)) {
+            return Some(replace(old, v));
+        }
+
+        if self.table.would_grow(1) {
+            self.purge();
+        }
+        self.table.insert(hash, K::new(&key), v);
+        None
+    }
+
+    /// Returns a reference to the value corresponding to the key, if the key
+    /// is still alive.
+    ///
+    /// The key may be any borrowed form of the referent type, but
+    /// `Hash` and `Eq` on the borrowed form *must* match those for
+    /// the referent type.
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+        where <K::Strong as Deref>::Target: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        self.table.find(hash, |weak| is_key(weak, k)).map(|(_, v)| v)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key, if
+    /// the key is still alive.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+        where <K::Strong as Deref>::Target: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        self.table.find_mut(hash, |weak| is_key(weak, k)).map(|(_, v)| v)
+    }
+
+    /// Returns true if the map contains a live entry for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
+        where <K::Strong as Deref>::Target: Borrow<Q>, Q: Hash + Eq
+    {
+        self.get(k).is_some()
+    }
+
+    /// Removes a key from the map, returning its value if the key was
+    /// present and alive.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where <K::Strong as Deref>::Target: Borrow<Q>, Q: Hash + Eq
+    {
+        let hash = make_hash(&self.hash_state, k);
+        self.table.remove(hash, |weak| is_key(weak, k)).map(|(_, v)| v)
+    }
+
+    /// Removes all dead entries, and returns how many were removed.
+    pub fn purge(&mut self) -> usize {
+        self.table.retain(|weak, _| !weak.is_expired())
+    }
+
+    /// Clears the map, removing all entries.
+    pub fn clear(&mut self) {
+        self.table.clear();
+    }
+
+    /// An iterator visiting the live entries in arbitrary order.
+    pub fn iter(&self) -> KeyIter<K, V> {
+        KeyIter { inner: self.table.iter() }
+    }
+}
+
+/// Returns true if `weak` is alive and refers to a key equal to `k`.
+fn is_key<W, Q: ?Sized>(weak: &W, k: &Q) -> bool
+    where W: WeakElement, <W::Strong as Deref>::Target: Borrow<Q>, Q: Eq
+{
+    weak.view().map_or(false, |strong| k.eq((*strong).borrow()))
+}
+
+impl<K, V, S> Debug for WeakKeyHashMap<K, V, S>
+    where K: WeakElement, K::Strong: Debug, <K::Strong as Deref>::Target: Hash + Eq,
+          V: Debug, S: HashState
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// WeakKeyHashMap iterator over live entries.
+pub struct KeyIter<'a, K: 'a, V: 'a> {
+    inner: table::Iter<'a, K, V>,
+}
+
+impl<'a, K: WeakElement, V> Iterator for KeyIter<'a, K, V> {
+    type Item = (K::Strong, &'a V);
+
+    fn next(&mut self) -> Option<(K::Strong, &'a V)> {
+        while let Some((weak, v)) = self.inner.next() {
+            if let Some(strong) = weak.view() {
+                return Some((strong, v));
+            }
+        }
+        None
+    }
+
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::{Rc, Weak};
+    use std::sync::{self, Arc};
+
+    use super::{WeakKeyHashMap, WeakValueHashMap};
+
+    #[test]
+    fn test_weak_value_purge_on_growth() {
+        let mut map: WeakValueHashMap<i32, Weak<i32>> = WeakValueHashMap::new();
+        let mut alive = vec![];
+        for i in 0..1000 {
+            let value = Rc::new(i);
+            map.insert(i, value.clone());
+            if i % 10 == 0 {
+                alive.push(value);
+            }
+        }
+        // Dead entries were purged instead of growing the table.
+        assert!(map.len() < 200);
+        assert_eq!(map.iter().count(), 100);
+
+        assert_eq!(map.get(&10), Some(Rc::new(10)));
+        assert_eq!(map.get(&11), None);
+        assert!(!map.contains_key(&11));
+        assert_eq!(map.remove(&20), Some(Rc::new(20)));
+
+        let len = map.len();
+        alive.clear();
+        assert_eq!(map.purge(), len);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_weak_value_arc() {
+        let mut map: WeakValueHashMap<&str, sync::Weak<String>> = WeakValueHashMap::new();
+        let a = Arc::new("a".to_string());
+        assert_eq!(map.insert("a", a.clone()), None);
+        assert_eq!(map.insert("a", a.clone()), Some(a.clone()));
+        drop(a);
+        assert_eq!(map.insert("a", Arc::new("b".to_string())), None);
+    }
+
+    #[test]
+    fn test_weak_key() {
+        let mut map: WeakKeyHashMap<Weak<String>, i32> = WeakKeyHashMap::new();
+        let keys: Vec<_> = (0..100).map(|i| Rc::new(i.to_string())).collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(map.insert(key.clone(), i as i32), None);
+        }
+        assert_eq!(map.insert(keys[5].clone(), -5), Some(5));
+        assert_eq!(map.get("5"), Some(&-5));
+        *map.get_mut("6").unwrap() += 1;
+        assert_eq!(map.get("6"), Some(&7));
+
+        let kept: Vec<_> = keys.into_iter().filter(|k| k.len() == 1).collect();
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get("50"), None);
+        assert_eq!(map.remove("50"), None);
+        assert_eq!(map.iter().count(), 10);
+
+        // The dead keys are still found by their stored hash.
+        assert_eq!(map.purge(), 90);
+        assert_eq!(map.len(), 10);
+        assert_eq!(map.remove("3"), Some(3));
+        drop(kept);
+        assert_eq!(map.purge(), 9);
+    }
+}